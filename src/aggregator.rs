@@ -1,43 +1,201 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
-use chrono::{Duration, Timelike, Utc};
-use tokio::{
-    sync::Mutex,
-    time::{sleep, Duration as TokioDuration},
-};
+use chrono::{DateTime, Duration, Utc};
+use tracing::Instrument;
 
 use crate::{
-    common::{models::TimeFrame, utils::make_kline_from_trades},
-    State,
+    bus::{Delivery, Event, RecvError, Subscription, Topic},
+    common::{
+        clock::{SharedClock, SystemClock},
+        models::{Kline, KlineSource, TimeFrame, Trade, Vbs},
+        utils::make_kline_from_trades,
+    },
+    database::StorageError,
+    exchange::Exchange,
+    metrics::metrics,
+    SharedState,
 };
 
+/// Kline of the current window of one symbol, updated from live trades.
+///
+/// Only the running OHLC and volumes are kept, so every update takes the same time
+/// however many trades the window already has
+pub struct FormingKline {
+    timeframe: TimeFrame,
+    kline: Option<Kline>,
+}
+
+impl FormingKline {
+    pub fn new(timeframe: TimeFrame) -> Self {
+        Self {
+            timeframe,
+            kline: None,
+        }
+    }
+
+    /// Adds the trade and returns the updated kline, a trade of a later window starts a new one.
+    /// Trades of earlier windows are ignored, their kline is left to the aggregator
+    pub fn update(&mut self, trade: Trade) -> Option<Kline> {
+        let ts = DateTime::from_timestamp_millis(trade.ts as i64)?;
+        let utc_begin = Aggregator::next_boundary(ts, &self.timeframe) - self.timeframe.duration();
+        let price = trade.price.parse::<f64>().ok()?;
+        let quantity = trade.quantity.parse::<f64>().unwrap_or(0.0);
+
+        match &self.kline {
+            Some(kline) if utc_begin.timestamp() < kline.utc_begin => return None,
+            Some(kline) if utc_begin.timestamp() == kline.utc_begin => {}
+            _ => {
+                self.kline = Some(Kline {
+                    pair: trade.symbol.clone(),
+                    timeframe: self.timeframe.clone(),
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    utc_begin: utc_begin.timestamp(),
+                    utc_end: (utc_begin + self.timeframe.duration()).timestamp(),
+                    volume_bs: Vbs {
+                        buy_base: 0.0,
+                        sell_base: 0.0,
+                        buy_quote: 0.0,
+                        sell_quote: 0.0,
+                    },
+                    source: KlineSource::Aggregated,
+                })
+            }
+        }
+
+        let kline = self.kline.as_mut()?;
+        kline.high = kline.high.max(price);
+        kline.low = kline.low.min(price);
+        kline.close = price;
+        match trade.taker_side.as_str() {
+            "buy" => {
+                kline.volume_bs.buy_base += quantity;
+                kline.volume_bs.buy_quote += quantity * price;
+            }
+            "sell" => {
+                kline.volume_bs.sell_base += quantity;
+                kline.volume_bs.sell_quote += quantity * price;
+            }
+            _ => {}
+        }
+
+        Some(kline.clone())
+    }
+}
+
 /// As stated per tech task requirement, the system should convert recent trades into klines
 /// My approach to that would be to save all RTs, and then convert them into klines after specified time has passed
-
+///
 /// The `Aggregator` schedules tasks to run at regular intervals based on the specified timeframes.
 /// Each task generates uniform Klines by aligning to fixed intervals of the timeframe.
 ///
 /// For example, if the timeframe is 15 minutes, the aggregator will create a Kline at
 /// 00, 15, 30, and 45 minutes of every hour.
+///
+/// Each window is built `settle_delay` after it closes, so trades which arrive slightly late
+/// are still included. Windows missed while the process was paused are built on wake up,
+/// windows missed while it was stopped are built by `catch_up` before the schedule starts.
 pub struct Aggregator {
     timeframes: Vec<TimeFrame>,
-    state: Arc<Mutex<State>>,
+    state: SharedState,
+    clock: SharedClock,
+    settle_delay: Duration,
+    symbols: Vec<String>,
+    exchange: Option<Arc<dyn Exchange>>,
+    max_catch_up: Duration,
 }
 
+pub const SETTLE_DELAY_MS: i64 = 2000;
+pub const MAX_CATCH_UP_HOURS: i64 = 24;
+
 impl Aggregator {
-    pub fn new(timeframes: Vec<TimeFrame>, state: Arc<Mutex<State>>) -> Self {
-        Self { timeframes, state }
+    pub fn new(timeframes: Vec<TimeFrame>, state: SharedState) -> Self {
+        Self {
+            timeframes,
+            state,
+            clock: Arc::new(SystemClock),
+            settle_delay: Duration::milliseconds(SETTLE_DELAY_MS),
+            symbols: Vec::new(),
+            exchange: None,
+            max_catch_up: Duration::hours(MAX_CATCH_UP_HOURS),
+        }
+    }
+
+    /// Symbols caught up on startup in addition to those which already have stored trades
+    pub fn with_symbols(mut self, symbols: Vec<String>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Fills windows without stored trades from exchange candles during `catch_up`
+    pub fn with_exchange(mut self, exchange: Arc<dyn Exchange>) -> Self {
+        self.exchange = Some(exchange);
+        self
+    }
+
+    /// How far back `catch_up` goes for symbols without any stored kline
+    pub fn with_max_catch_up(mut self, max_catch_up: Duration) -> Self {
+        self.max_catch_up = max_catch_up;
+        self
+    }
+
+    pub fn with_settle_delay(mut self, settle_delay: Duration) -> Self {
+        self.settle_delay = settle_delay;
+        self
+    }
+
+    /// Schedules runs with `clock` instead of the wall clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
     }
 
     pub async fn run(&self) {
         let mut handles = vec![];
 
+        let trades = self
+            .state
+            .bus
+            .subscribe("aggregator", &[Topic::Trades], Delivery::Lossy);
+        handles.push(tokio::spawn(Self::form_klines(
+            self.state.clone(),
+            self.timeframes.clone(),
+            trades,
+        )));
+
         for timeframe in &self.timeframes {
+            // the live schedule starts with the window open right now, everything before it is caught up
+            let window_end = Self::next_boundary(self.clock.now(), timeframe);
+            match self
+                .catch_up(timeframe, window_end - timeframe.duration())
+                .await
+            {
+                Ok(klines) => {
+                    tracing::info!("Caught up {} {} klines", klines.len(), timeframe.as_ref())
+                }
+                Err(err) => {
+                    tracing::error!("Failed to catch up {} klines: {}", timeframe.as_ref(), err)
+                }
+            }
+
             let timeframe_clone = timeframe.clone();
             let state_clone = self.state.clone();
-            let handle = tokio::spawn(async move {
-                Self::calc(timeframe_clone, state_clone).await;
-            });
+            let clock_clone = self.clock.clone();
+            let settle_delay = self.settle_delay;
+            let span = tracing::info_span!("aggregator", timeframe = timeframe.as_ref());
+            let handle = tokio::spawn(
+                Self::calc(
+                    timeframe_clone,
+                    state_clone,
+                    clock_clone,
+                    settle_delay,
+                    window_end,
+                )
+                .instrument(span),
+            );
             handles.push(handle);
         }
 
@@ -46,41 +204,611 @@ impl Aggregator {
         }
     }
 
-    async fn calc(timeframe: TimeFrame, state: Arc<Mutex<State>>) {
+    /// Publishes the forming kline of every symbol and timeframe after each trade,
+    /// finalized klines are published by `aggregate`
+    async fn form_klines(state: SharedState, timeframes: Vec<TimeFrame>, mut trades: Subscription) {
+        let mut forming: BTreeMap<(String, TimeFrame), FormingKline> = BTreeMap::new();
+
+        loop {
+            let trade = match trades.recv().await {
+                Ok(Event::Trade(trade)) => trade,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Forming klines missed {} trades", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            for timeframe in &timeframes {
+                let kline = forming
+                    .entry((trade.symbol.clone(), timeframe.clone()))
+                    .or_insert_with(|| FormingKline::new(timeframe.clone()))
+                    .update(trade.clone());
+                if let Some(kline) = kline {
+                    state.bus.publish_kline(kline, false).await;
+                }
+            }
+        }
+    }
+
+    async fn calc(
+        timeframe: TimeFrame,
+        state: SharedState,
+        clock: SharedClock,
+        settle_delay: Duration,
+        mut window_end: DateTime<Utc>,
+    ) {
+        let length = timeframe.duration();
+
         loop {
-            let duration = Self::calc_next_run_time(timeframe.clone());
             // Wait for next time it should run
-            sleep(TokioDuration::from_secs(duration.num_seconds() as u64)).await;
+            clock.sleep_until(window_end + settle_delay).await;
 
-            let now = Utc::now();
-            let start_time = match timeframe {
-                TimeFrame::Minutes15 => now - Duration::minutes(15),
-                TimeFrame::Hour => now - Duration::hours(1),
-            };
-            let end_time = now;
-            let state = state.lock().await;
-            let trades = state.db.retrieve_trades_in_interval(&start_time, &end_time);
-            let kline = make_kline_from_trades(trades, timeframe.clone());
+            // more than one window is due if the process was paused or the storage was slow
+            while window_end + settle_delay <= clock.now() {
+                let start_time = window_end - length;
+                let span = tracing::info_span!("window", start = start_time.timestamp());
+
+                match Self::aggregate(&state, timeframe.clone(), start_time, window_end)
+                    .instrument(span)
+                    .await
+                {
+                    Ok(klines) => tracing::debug!(
+                        start = start_time.timestamp(),
+                        klines = klines.len(),
+                        "Built klines"
+                    ),
+                    Err(err) => tracing::error!(
+                        start = start_time.timestamp(),
+                        error = %err,
+                        "Failed to aggregate klines"
+                    ),
+                }
+                window_end += length;
+            }
+        }
+    }
+
+    /// Builds and stores one kline per symbol from the trades between `start_time` and `end_time`
+    pub async fn aggregate(
+        state: &SharedState,
+        timeframe: TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let trades = state
+            .db
+            .retrieve_trades_in_interval(None, start_time, end_time, None)
+            .await?;
+
+        let mut by_symbol: BTreeMap<String, Vec<Trade>> = BTreeMap::new();
+        for trade in trades {
+            by_symbol
+                .entry(trade.symbol.clone())
+                .or_default()
+                .push(trade);
+        }
+
+        let mut klines = Vec::new();
+        for trades in by_symbol.into_values() {
+            if let Some(kline) = make_kline_from_trades(trades, timeframe.clone(), start_time) {
+                match state.db.insert_kline(kline.clone()).await {
+                    Ok(()) => {}
+                    // e.g. the gap filler stored the exchange candle first, the other symbols go on
+                    Err(StorageError::Conflict(msg)) => {
+                        tracing::warn!(symbol = kline.pair.as_str(), "Kline not stored: {}", msg);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                }
+                metrics()
+                    .klines_produced
+                    .with_label_values(&[timeframe.as_ref()])
+                    .inc();
+                state.bus.publish_kline(kline.clone(), true).await;
+                klines.push(kline);
+            }
+        }
+        state.health.aggregated(&timeframe);
+
+        Ok(klines)
+    }
+
+    /// Builds and stores the klines missing between the latest stored kline of each symbol and `until`.
+    ///
+    /// Symbols without a stored kline start `max_catch_up` before `until`. Windows without
+    /// stored trades are taken from exchange candles if a REST client is set.
+    /// Returns the klines stored, conflicting ones are skipped
+    pub async fn catch_up(
+        &self,
+        timeframe: &TimeFrame,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let length = timeframe.duration();
+        let earliest = Self::next_boundary(until - self.max_catch_up, timeframe) - length;
+
+        let mut symbols = self.symbols.clone();
+        for symbol in self
+            .state
+            .db
+            .call(|storage| storage.trade_symbols())
+            .await?
+        {
+            if !symbols.contains(&symbol) {
+                symbols.push(symbol);
+            }
+        }
+
+        let mut klines = Vec::new();
+        for symbol in symbols {
+            let latest = self
+                .state
+                .db
+                .latest_kline(symbol.clone(), timeframe.clone())
+                .await?;
+            let from = match latest.and_then(|kline| DateTime::from_timestamp(kline.utc_end, 0)) {
+                // first boundary at or after the end of the latest kline
+                Some(end) => Self::next_boundary(end - Duration::milliseconds(1), timeframe),
+                None => earliest,
+            }
+            .max(earliest);
+            if from >= until {
+                continue;
+            }
+
+            let trades = self
+                .state
+                .db
+                .retrieve_trades_in_interval(Some(symbol.clone()), from, until, None)
+                .await?;
+            let length_millis = length.num_milliseconds();
+            let mut by_window: BTreeMap<i64, Vec<Trade>> = BTreeMap::new();
+            for trade in trades {
+                let ts = trade.ts as i64;
+                by_window
+                    .entry(ts - ts.rem_euclid(length_millis))
+                    .or_default()
+                    .push(trade);
+            }
 
-            state.db.insert_kline(&kline.unwrap()).unwrap();
+            let mut built = Vec::new();
+            let mut without_trades = Vec::new();
+            let mut window = from;
+            while window < until {
+                match by_window.remove(&window.timestamp_millis()) {
+                    Some(trades) => {
+                        built.extend(make_kline_from_trades(trades, timeframe.clone(), window))
+                    }
+                    None => without_trades.push(window.timestamp()),
+                }
+                window += length;
+            }
+
+            built.extend(
+                self.klines_from_candles(&symbol, timeframe, &without_trades)
+                    .await,
+            );
+            built.sort_by_key(|kline| kline.utc_begin);
+            klines.extend(built);
         }
+
+        let mut stored = Vec::with_capacity(klines.len());
+        for kline in klines {
+            match self.state.db.insert_kline(kline.clone()).await {
+                Ok(()) => {}
+                // like `aggregate`, the window was stored meanwhile and the other symbols go on
+                Err(StorageError::Conflict(msg)) => {
+                    tracing::warn!(symbol = kline.pair.as_str(), "Kline not stored: {}", msg);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+            if kline.source == KlineSource::Aggregated {
+                metrics()
+                    .klines_produced
+                    .with_label_values(&[timeframe.as_ref()])
+                    .inc();
+            }
+            stored.push(kline);
+        }
+
+        Ok(stored)
     }
 
-    fn calc_next_run_time(timeframe: TimeFrame) -> Duration {
-        let now = Utc::now();
-        let next_time = match timeframe {
-            TimeFrame::Minutes15 => {
-                let minutes = now.minute();
-                let next_minute = (minutes / 15 + 1) * 15;
-                now.with_minute(next_minute)
-                    .unwrap()
-                    .with_second(0)
-                    .unwrap()
+    /// Exchange candles of the windows starting at `windows` (unix seconds, ascending)
+    async fn klines_from_candles(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        windows: &[i64],
+    ) -> Vec<Kline> {
+        let (Some(exchange), Some(first), Some(last)) =
+            (&self.exchange, windows.first(), windows.last())
+        else {
+            return Vec::new();
+        };
+
+        let start_time = DateTime::from_timestamp(*first, 0).unwrap_or_default();
+        let end_time =
+            DateTime::from_timestamp(*last, 0).unwrap_or_default() + timeframe.duration();
+
+        match exchange
+            .candles(symbol, timeframe, start_time, end_time)
+            .await
+        {
+            Ok(klines) => klines
+                .into_iter()
+                .filter(|kline| windows.contains(&kline.utc_begin))
+                .collect(),
+            Err(err) => {
+                tracing::warn!("Failed to fetch {} candles for catch-up: {}", symbol, err);
+                Vec::new()
             }
-            TimeFrame::Hour => {
-                now.with_minute(0).unwrap().with_second(0).unwrap() + Duration::hours(1)
+        }
+    }
+
+    /// Aggregates every window of `timeframe` between `start_time` and `end_time`,
+    /// windows are aligned to the timeframe, e.g. replayed sessions are built at :00, :15 and so on
+    pub async fn aggregate_windows(
+        state: &SharedState,
+        timeframe: TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let length = timeframe.duration().num_seconds();
+        let first = start_time.timestamp() - start_time.timestamp().rem_euclid(length);
+
+        let mut klines = Vec::new();
+        let mut window = first;
+        while window <= end_time.timestamp() {
+            let begin = DateTime::from_timestamp(window, 0).unwrap_or_default();
+            let end = DateTime::from_timestamp(window + length, 0).unwrap_or_default();
+            klines.extend(Self::aggregate(state, timeframe.clone(), begin, end).await?);
+            window += length;
+        }
+
+        Ok(klines)
+    }
+
+    /// First window boundary strictly after `now`, boundaries are multiples of the timeframe
+    /// since the unix epoch
+    pub fn next_boundary(now: DateTime<Utc>, timeframe: &TimeFrame) -> DateTime<Utc> {
+        let length = timeframe.duration().num_milliseconds();
+        let millis = now.timestamp_millis();
+
+        DateTime::from_timestamp_millis(millis - millis.rem_euclid(length) + length).unwrap_or(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::clock::ManualClock,
+        test_support::{self, memory_state, MockPoloniex},
+    };
+
+    fn trade(id: &str, price: &str, ts: DateTime<Utc>) -> Trade {
+        test_support::trade(id, "BTC_USDT", "buy", price, ts.timestamp_millis() as u64)
+    }
+
+    #[tokio::test]
+    async fn forming_klines_are_published_from_bus_trades() {
+        let state = memory_state().await;
+        let mut klines = state
+            .bus
+            .subscribe("test", &[Topic::Klines], Delivery::Lossy);
+        let trades = state
+            .bus
+            .subscribe("aggregator", &[Topic::Trades], Delivery::Lossy);
+        tokio::spawn(Aggregator::form_klines(
+            state.clone(),
+            vec![TimeFrame::Minutes15],
+            trades,
+        ));
+
+        let at = |secs: i64| DateTime::from_timestamp(secs, 0).unwrap();
+        state
+            .bus
+            .publish_trades(&[
+                trade("1", "10", at(1)),
+                trade("2", "12", at(2)),
+                trade("3", "20", at(900)),
+                // late trade of the previous window, left to the aggregator
+                trade("4", "11", at(3)),
+                trade("5", "21", at(901)),
+            ])
+            .await;
+
+        let mut updates = Vec::new();
+        for _ in 0..4 {
+            match klines.recv().await {
+                Ok(Event::Kline(update)) => {
+                    assert!(!update.is_final);
+                    updates.push((
+                        update.kline.utc_begin,
+                        update.kline.open,
+                        update.kline.close,
+                    ));
+                }
+                other => panic!("unexpected {:?}", other),
             }
+        }
+        assert_eq!(
+            updates,
+            vec![
+                (0, 10.0, 10.0),
+                (0, 10.0, 12.0),
+                (900, 20.0, 20.0),
+                (900, 20.0, 21.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn forming_kline_matches_the_aggregated_one() {
+        let at = |secs: i64| DateTime::from_timestamp(secs, 0).unwrap();
+        let trades = vec![
+            trade("1", "10", at(1)),
+            test_support::trade("2", "BTC_USDT", "sell", "8", 2_000),
+            trade("3", "14", at(3)),
+            test_support::trade("4", "BTC_USDT", "sell", "12", 4_000),
+        ];
+
+        let mut forming = FormingKline::new(TimeFrame::Minutes15);
+        let mut last = None;
+        for trade in trades.clone() {
+            last = forming.update(trade);
+        }
+
+        let aggregated = make_kline_from_trades(trades, TimeFrame::Minutes15, at(0));
+        assert_eq!(last, aggregated);
+    }
+
+    #[tokio::test]
+    async fn stored_kline_of_one_symbol_doesnt_stop_the_others() {
+        let state = memory_state().await;
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let end = DateTime::from_timestamp(900, 0).unwrap();
+        let stored =
+            make_kline_from_trades(vec![trade("1", "10", start)], TimeFrame::Minutes15, start)
+                .unwrap();
+        state.db.insert_kline(stored).await.unwrap();
+
+        let eth = test_support::trade("2", "ETH_USDT", "buy", "2", 1_000);
+        state
+            .db
+            .insert_recent_trades(vec![trade("1", "10", start), eth])
+            .await
+            .unwrap();
+        state.db.flush().await.unwrap();
+
+        let klines = Aggregator::aggregate(&state, TimeFrame::Minutes15, start, end)
+            .await
+            .unwrap();
+        let pairs: Vec<&str> = klines.iter().map(|kline| kline.pair.as_str()).collect();
+        assert_eq!(pairs, vec!["ETH_USDT"]);
+    }
+
+    #[tokio::test]
+    async fn klines_are_built_at_window_boundaries() {
+        let midnight = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        let clock = ManualClock::new(midnight + Duration::minutes(5));
+
+        let state = memory_state().await;
+        state
+            .db
+            .insert_recent_trades(vec![
+                trade("1", "1", midnight + Duration::minutes(3)),
+                trade("2", "2", midnight + Duration::minutes(10)),
+                trade("3", "3", midnight + Duration::minutes(16)),
+            ])
+            .await
+            .unwrap();
+
+        let aggregator = Aggregator::new(vec![TimeFrame::Minutes15], state.clone())
+            .with_clock(clock.clone())
+            .with_settle_delay(Duration::zero());
+        let runner = tokio::spawn(async move { aggregator.run().await });
+        let latest = || {
+            state
+                .db
+                .latest_kline("BTC_USDT".to_string(), TimeFrame::Minutes15)
         };
-        next_time - now
+
+        clock.wait_for_sleeps(1).await;
+        assert_eq!(latest().await.unwrap(), None);
+
+        clock.advance(Duration::minutes(10));
+        clock.wait_for_sleeps(2).await;
+        let first = latest().await.unwrap().unwrap();
+        assert_eq!(first.utc_begin, midnight.timestamp());
+        assert_eq!((first.open, first.close), (1.0, 2.0));
+
+        clock.advance(Duration::minutes(15));
+        clock.wait_for_sleeps(3).await;
+        let second = latest().await.unwrap().unwrap();
+        assert_eq!(
+            second.utc_begin,
+            (midnight + Duration::minutes(15)).timestamp()
+        );
+        assert_eq!((second.open, second.close), (3.0, 3.0));
+        assert_eq!(clock.sleeps(), 3);
+
+        runner.abort();
+    }
+
+    #[test]
+    fn next_boundary_is_aligned_for_every_minute() {
+        let hour = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+
+        let late = hour + Duration::minutes(47) + Duration::milliseconds(30_500);
+        assert_eq!(
+            Aggregator::next_boundary(late, &TimeFrame::Minutes15),
+            hour + Duration::hours(1)
+        );
+        assert_eq!(
+            Aggregator::next_boundary(late, &TimeFrame::Hour),
+            hour + Duration::hours(1)
+        );
+        assert_eq!(
+            Aggregator::next_boundary(hour, &TimeFrame::Minutes15),
+            hour + Duration::minutes(15)
+        );
+    }
+
+    #[tokio::test]
+    async fn missed_windows_are_caught_up_after_settle_delay() {
+        let midnight = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        let clock = ManualClock::new(midnight + Duration::minutes(50));
+
+        let state = memory_state().await;
+        state
+            .db
+            .insert_recent_trades(vec![
+                trade("1", "1", midnight + Duration::minutes(59)),
+                trade("2", "2", midnight + Duration::minutes(80)),
+                trade("3", "3", midnight + Duration::minutes(100)),
+            ])
+            .await
+            .unwrap();
+
+        let aggregator = Aggregator::new(vec![TimeFrame::Minutes15], state.clone())
+            .with_clock(clock.clone())
+            .with_settle_delay(Duration::seconds(5));
+        let runner = tokio::spawn(async move { aggregator.run().await });
+        let klines = || {
+            state.db.retrieve_klines_in_interval(
+                "BTC_USDT".to_string(),
+                TimeFrame::Minutes15,
+                midnight,
+                midnight + Duration::days(1),
+                None,
+            )
+        };
+
+        // the 00:45 - 01:00 window is built once the settle delay has passed
+        clock.wait_for_sleeps(1).await;
+        clock.advance(Duration::minutes(10));
+        tokio::task::yield_now().await;
+        assert_eq!(clock.sleeps(), 1);
+
+        clock.advance(Duration::seconds(5));
+        clock.wait_for_sleeps(2).await;
+        assert_eq!(klines().await.unwrap().len(), 1);
+
+        // a pause over three boundaries builds all of them at once
+        clock.advance(Duration::minutes(45));
+        clock.wait_for_sleeps(3).await;
+        let begins: Vec<i64> = klines()
+            .await
+            .unwrap()
+            .iter()
+            .map(|kline| kline.utc_begin - midnight.timestamp())
+            .collect();
+        assert_eq!(begins, vec![45 * 60, 75 * 60, 90 * 60]);
+
+        runner.abort();
+    }
+
+    #[tokio::test]
+    async fn missing_klines_are_caught_up_from_trades_and_candles() {
+        let midnight = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        let mock = MockPoloniex::start().await;
+        let candle_start = (midnight + Duration::minutes(30)).timestamp_millis();
+        let row: Vec<String> = ["1", "4", "2", "3", "30", "10", "5"]
+            .iter()
+            .map(|v| v.to_string())
+            .chain([
+                candle_start.to_string(),
+                (candle_start + 899_999).to_string(),
+            ])
+            .collect();
+        mock.set_candles("BTC_USDT", vec![row]);
+
+        let state = memory_state().await;
+        let stored = make_kline_from_trades(
+            vec![trade("1", "5", midnight + Duration::minutes(1))],
+            TimeFrame::Minutes15,
+            midnight,
+        )
+        .unwrap();
+        state.db.insert_kline(stored).await.unwrap();
+        state
+            .db
+            .insert_recent_trades(vec![
+                trade("2", "6", midnight + Duration::minutes(20)),
+                trade("3", "7", midnight + Duration::minutes(50)),
+                trade("4", "8", midnight + Duration::minutes(61)),
+            ])
+            .await
+            .unwrap();
+
+        let aggregator = Aggregator::new(vec![TimeFrame::Minutes15], state.clone())
+            .with_symbols(vec!["BTC_USDT".to_string()])
+            .with_exchange(mock.exchange());
+        let klines = aggregator
+            .catch_up(&TimeFrame::Minutes15, midnight + Duration::hours(1))
+            .await
+            .unwrap();
+
+        // 00:15 and 00:45 from trades, 00:30 from the exchange, 01:00 is still open
+        let built: Vec<(i64, f64)> = klines
+            .iter()
+            .map(|kline| (kline.utc_begin - midnight.timestamp(), kline.close))
+            .collect();
+        assert_eq!(built, vec![(15 * 60, 6.0), (30 * 60, 3.0), (45 * 60, 7.0)]);
+        assert_eq!(mock.requests().len(), 1);
+
+        let latest = state
+            .db
+            .latest_kline("BTC_USDT".to_string(), TimeFrame::Minutes15)
+            .await
+            .unwrap();
+        assert_eq!(latest.as_ref(), klines.last());
+    }
+
+    #[tokio::test]
+    async fn catch_up_skips_conflicting_klines_per_symbol() {
+        let midnight = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        let state = memory_state().await;
+        // ends where it begins, so catch up rebuilds the 00:15 window it occupies
+        let quarter = midnight.timestamp() + 15 * 60;
+        state
+            .db
+            .insert_kline(
+                test_support::kline("ETH_USDT", TimeFrame::Minutes15, quarter, quarter).build(),
+            )
+            .await
+            .unwrap();
+        state
+            .db
+            .insert_recent_trades(vec![
+                test_support::trade(
+                    "1",
+                    "ETH_USDT",
+                    "buy",
+                    "5",
+                    (midnight + Duration::minutes(20)).timestamp_millis() as u64,
+                ),
+                trade("2", "6", midnight + Duration::minutes(20)),
+            ])
+            .await
+            .unwrap();
+
+        let aggregator = Aggregator::new(vec![TimeFrame::Minutes15], state.clone())
+            .with_symbols(vec!["ETH_USDT".to_string(), "BTC_USDT".to_string()])
+            .with_max_catch_up(Duration::minutes(30));
+        let klines = aggregator
+            .catch_up(&TimeFrame::Minutes15, midnight + Duration::minutes(30))
+            .await
+            .unwrap();
+
+        let built: Vec<(&str, i64)> = klines
+            .iter()
+            .map(|kline| (kline.pair.as_str(), kline.utc_begin))
+            .collect();
+        assert_eq!(built, vec![("BTC_USDT", quarter)]);
     }
 }
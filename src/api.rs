@@ -0,0 +1,381 @@
+use std::future::Future;
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{
+    common::models::{Kline, TimeFrame, Trade},
+    database::StorageError,
+    metrics::metrics,
+    SharedState,
+};
+
+pub const DEFAULT_LIMIT: usize = 500;
+pub const MAX_LIMIT: usize = 5000;
+
+#[derive(Clone)]
+struct ApiState {
+    state: SharedState,
+    max_limit: usize,
+}
+
+/// `GET /klines` parameters, `from` and `to` are unix seconds matched against `utc_begin`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct KlinesQuery {
+    symbol: Option<String>,
+    timeframe: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<usize>,
+}
+
+/// `GET /trades` parameters, `from` and `to` are unix milliseconds matched against `ts`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TradesQuery {
+    symbol: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<usize>,
+}
+
+/// One page of results, `next` is the `from` value of the following page
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub next: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Storage(StorageError),
+}
+
+impl From<StorageError> for ApiError {
+    fn from(err: StorageError) -> Self {
+        ApiError::Storage(err)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::Storage(err) => {
+                tracing::error!("API storage query failed: {}", err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "storage error".to_string(),
+                )
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+/// Read-only JSON API over stored klines and trades
+pub fn router(state: SharedState, max_limit: usize) -> Router {
+    Router::new()
+        .route("/klines", get(klines))
+        .route("/trades", get(trades))
+        .with_state(ApiState { state, max_limit })
+}
+
+/// Prometheus metrics at `/metrics`, served even with the query API disabled
+pub fn metrics_router() -> Router {
+    Router::new().route("/metrics", get(prometheus))
+}
+
+async fn prometheus() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+/// Serves `router` until `shutdown` turns true
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    mut shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await
+}
+
+async fn klines(
+    State(api): State<ApiState>,
+    query: Result<Query<KlinesQuery>, QueryRejection>,
+) -> Result<Json<Page<Kline>>, ApiError> {
+    let Query(query) = query?;
+
+    let symbol = query
+        .symbol
+        .ok_or_else(|| ApiError::BadRequest("symbol is required".to_string()))?;
+    let timeframe = query
+        .timeframe
+        .ok_or_else(|| ApiError::BadRequest("timeframe is required".to_string()))?;
+    let timeframe = TimeFrame::try_from(timeframe.clone())
+        .map_err(|_| ApiError::BadRequest(format!("unknown timeframe {}", timeframe)))?;
+    let (start, end) = range(query.from, query.to, Utc::now().timestamp(), |secs| {
+        DateTime::from_timestamp(secs, 0)
+    })?;
+    let limit = limit(query.limit, api.max_limit)?;
+
+    let db = &api.state.db;
+    let page = paginate(
+        start,
+        end,
+        limit,
+        |kline: &Kline| kline.utc_begin,
+        |secs| DateTime::from_timestamp(secs, 0),
+        move |start, end, limit| {
+            db.retrieve_klines_in_interval(symbol.clone(), timeframe.clone(), start, end, limit)
+        },
+    )
+    .await?;
+
+    Ok(Json(page))
+}
+
+async fn trades(
+    State(api): State<ApiState>,
+    query: Result<Query<TradesQuery>, QueryRejection>,
+) -> Result<Json<Page<Trade>>, ApiError> {
+    let Query(query) = query?;
+
+    let (start, end) = range(
+        query.from,
+        query.to,
+        Utc::now().timestamp_millis(),
+        DateTime::from_timestamp_millis,
+    )?;
+    let limit = limit(query.limit, api.max_limit)?;
+
+    let db = &api.state.db;
+    let symbol = query.symbol;
+    let page = paginate(
+        start,
+        end,
+        limit,
+        |trade: &Trade| trade.ts as i64,
+        DateTime::from_timestamp_millis,
+        move |start, end, limit| db.retrieve_trades_in_interval(symbol.clone(), start, end, limit),
+    )
+    .await?;
+
+    Ok(Json(page))
+}
+
+/// `from` defaults to the epoch and `to` to `now`, both in the unit `convert` expects
+pub(crate) fn range(
+    from: Option<i64>,
+    to: Option<i64>,
+    now: i64,
+    convert: impl Fn(i64) -> Option<DateTime<Utc>>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), ApiError> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(now);
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+
+    match (convert(from), convert(to)) {
+        (Some(start), Some(end)) if from >= 0 => Ok((start, end)),
+        _ => Err(ApiError::BadRequest("from or to out of range".to_string())),
+    }
+}
+
+pub(crate) fn limit(limit: Option<usize>, max_limit: usize) -> Result<usize, ApiError> {
+    match limit.unwrap_or(DEFAULT_LIMIT.min(max_limit)) {
+        0 => Err(ApiError::BadRequest("limit must be positive".to_string())),
+        limit if limit > max_limit => Err(ApiError::BadRequest(format!(
+            "limit can't exceed {}",
+            max_limit
+        ))),
+        limit => Ok(limit),
+    }
+}
+
+/// Reads the first `limit` rows keyed in `[start, end)`, extended by rows sharing the last key
+/// so that a page never ends in the middle of a key and `next` never skips or repeats rows.
+///
+/// `fetch` returns rows of a range ordered by key, at most as many as asked for, and `to_time`
+/// converts keys back into times. Only `limit + 1` rows are read, plus the rest of the
+/// last key when the page would end inside it
+pub(crate) async fn paginate<T, F, Fut>(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    limit: usize,
+    key: impl Fn(&T) -> i64,
+    to_time: impl Fn(i64) -> Option<DateTime<Utc>>,
+    fetch: F,
+) -> Result<Page<T>, StorageError>
+where
+    F: Fn(DateTime<Utc>, DateTime<Utc>, Option<usize>) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, StorageError>>,
+{
+    let mut rows = fetch(start, end, Some(limit + 1)).await?;
+    if rows.len() <= limit {
+        return Ok(Page {
+            data: rows,
+            next: None,
+        });
+    }
+
+    let last = key(&rows[limit - 1]);
+    if key(&rows[limit]) != last {
+        let next = Some(key(&rows[limit]));
+        rows.truncate(limit);
+        return Ok(Page { data: rows, next });
+    }
+
+    let (Some(last_start), Some(last_end)) = (to_time(last), to_time(last + 1)) else {
+        return Err(StorageError::InvalidData(format!(
+            "key {} out of range",
+            last
+        )));
+    };
+    rows.retain(|row| key(row) != last);
+    rows.extend(fetch(last_start, last_end.min(end), None).await?);
+    let next = if last_end < end {
+        fetch(last_end, end, Some(1)).await?.first().map(&key)
+    } else {
+        None
+    };
+
+    Ok(Page { data: rows, next })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_support::{kline, memory_state, trade};
+
+    async fn start_api() -> String {
+        let state = memory_state().await;
+        for utc_begin in [0, 900, 1800] {
+            let kline = kline("BTC_USDT", TimeFrame::Minutes15, utc_begin, utc_begin + 900);
+            state.db.insert_kline(kline.build()).await.unwrap();
+        }
+        let trades = vec![
+            trade("1", "BTC_USDT", "buy", "100", 1000),
+            trade("2", "BTC_USDT", "buy", "100", 2000),
+            trade("3", "BTC_USDT", "buy", "100", 2000),
+            trade("4", "BTC_USDT", "buy", "100", 3000),
+        ];
+        state.db.insert_recent_trades(trades).await.unwrap();
+        state.db.flush().await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            // dropping the sender would stop the server
+            let _shutdown_tx = shutdown_tx;
+            serve(listener, router(state, 100), shutdown_rx).await
+        });
+        format!("http://{}", addr)
+    }
+
+    async fn get(url: String) -> (u16, Value) {
+        let response = reqwest::get(url).await.unwrap();
+        let status = response.status().as_u16();
+        (
+            status,
+            serde_json::from_str(&response.text().await.unwrap()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn klines_and_trades_are_paginated() {
+        let base = start_api().await;
+
+        let (status, page) = get(format!(
+            "{}/klines?symbol=BTC_USDT&timeframe=15m&from=0&to=3600&limit=2",
+            base
+        ))
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(page["data"].as_array().unwrap().len(), 2);
+        assert_eq!(page["data"][0]["timeframe"], "15m");
+        assert_eq!(page["data"][0]["source"], "aggregated");
+        assert_eq!(page["next"], 1800);
+
+        // both trades at 2000 end up on the first page
+        let (status, page) = get(format!("{}/trades?symbol=BTC_USDT&to=5000&limit=2", base)).await;
+        assert_eq!(status, 200);
+        let ids: Vec<&str> = page["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|trade| trade["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert_eq!(page["next"], 3000);
+
+        let (_, page) = get(format!("{}/trades?from=3000&to=5000", base)).await;
+        assert_eq!(page["data"].as_array().unwrap().len(), 1);
+        assert_eq!(page["next"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_without_the_query_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let _shutdown_tx = shutdown_tx;
+            serve(listener, metrics_router(), shutdown_rx).await
+        });
+
+        let response = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let response = reqwest::get(format!("http://{}/klines", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[tokio::test]
+    async fn invalid_parameters_are_rejected() {
+        let base = start_api().await;
+
+        for query in [
+            "klines?timeframe=15m",
+            "klines?symbol=BTC_USDT&timeframe=5m",
+            "klines?symbol=BTC_USDT&timeframe=15m&from=10&to=5",
+            "klines?symbol=BTC_USDT&timeframe=15m&limit=1000",
+            "trades?limit=0",
+            "trades?from=abc",
+        ] {
+            let (status, body) = get(format!("{}/{}", base, query)).await;
+            assert_eq!(status, 400, "{}", query);
+            assert!(body["error"].is_string(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use futures_util::{stream, Stream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    client::models::BookUpdate,
+    common::models::{FundingRate, Kline, OpenInterest, ReferencePrice, Trade},
+    metrics::metrics,
+};
+
+/// Events a subscriber's queue holds before it counts as lagging
+pub const BUS_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KlineUpdate {
+    pub kline: Kline,
+    /// False while the window is still open
+    pub is_final: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Trade(Trade),
+    Book(BookUpdate),
+    Kline(KlineUpdate),
+    /// State of the upstream exchange connection
+    Connection(ConnectionState),
+    /// Mark or index price of a perpetual
+    Price(ReferencePrice),
+    Funding(FundingRate),
+    OpenInterest(OpenInterest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic {
+    Trades,
+    Book,
+    Klines,
+    Connection,
+    Prices,
+    Funding,
+    OpenInterest,
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::Trade(_) => Topic::Trades,
+            Event::Book(_) => Topic::Book,
+            Event::Kline(_) => Topic::Klines,
+            Event::Connection(_) => Topic::Connection,
+            Event::Price(_) => Topic::Prices,
+            Event::Funding(_) => Topic::Funding,
+            Event::OpenInterest(_) => Topic::OpenInterest,
+        }
+    }
+}
+
+/// What happens when a subscriber's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The event is dropped for this subscriber, which receives `RecvError::Lagged` next
+    Lossy,
+    /// `publish` waits until the subscriber has room, for consumers which can't miss events
+    Reliable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Number of events dropped since the last successful receive
+    Lagged(u64),
+    Closed,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    name: String,
+    topics: Vec<Topic>,
+    delivery: Delivery,
+    queue: mpsc::Sender<Event>,
+    missed: Arc<AtomicU64>,
+}
+
+/// Typed fan-out between ingestion, aggregation, storage and the API servers.
+///
+/// Every subscriber has its own bounded queue, so a slow consumer only affects itself,
+/// unless it asked for `Delivery::Reliable`
+pub struct EventBus {
+    capacity: usize,
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Events dropped for lossy subscribers since start
+    missed: AtomicU64,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            subscribers: Mutex::new(Vec::new()),
+            missed: AtomicU64::new(0),
+        }
+    }
+
+    /// Events published after this call are delivered until the `Subscription` is dropped
+    pub fn subscribe(&self, name: &str, topics: &[Topic], delivery: Delivery) -> Subscription {
+        let (queue, events) = mpsc::channel(self.capacity);
+        let missed = Arc::new(AtomicU64::new(0));
+
+        self.subscribers.lock().unwrap().push(Subscriber {
+            name: name.to_string(),
+            topics: topics.to_vec(),
+            delivery,
+            queue,
+            missed: missed.clone(),
+        });
+
+        Subscription {
+            name: name.to_string(),
+            events,
+            missed,
+        }
+    }
+
+    pub async fn publish(&self, event: Event) {
+        let topic = event.topic();
+        let subscribers: Vec<Subscriber> = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|subscriber| !subscriber.queue.is_closed());
+            subscribers
+                .iter()
+                .filter(|subscriber| subscriber.topics.contains(&topic))
+                .cloned()
+                .collect()
+        };
+
+        for subscriber in subscribers {
+            match subscriber.delivery {
+                // an error means the subscription was dropped meanwhile
+                Delivery::Reliable => {
+                    let _ = subscriber.queue.send(event.clone()).await;
+                }
+                Delivery::Lossy => {
+                    if let Err(TrySendError::Full(_)) = subscriber.queue.try_send(event.clone()) {
+                        self.missed.fetch_add(1, Ordering::Relaxed);
+                        metrics().bus_missed_events.inc();
+                        // warn once per lag episode, not for every dropped event
+                        if subscriber.missed.fetch_add(1, Ordering::Relaxed) == 0 {
+                            tracing::warn!(
+                                "Subscriber {} is lagging, dropping events",
+                                subscriber.name
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn publish_trades(&self, trades: &[Trade]) {
+        for trade in trades {
+            self.publish(Event::Trade(trade.clone())).await;
+        }
+    }
+
+    pub async fn publish_kline(&self, kline: Kline, is_final: bool) {
+        self.publish(Event::Kline(KlineUpdate { kline, is_final }))
+            .await;
+    }
+
+    /// Total events dropped for lagging subscribers
+    pub fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribers(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.queue.is_closed());
+        subscribers.len()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(BUS_CAPACITY)
+    }
+}
+
+pub struct Subscription {
+    name: String,
+    events: mpsc::Receiver<Event>,
+    missed: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// Reports dropped events before delivering the next one
+    pub async fn recv(&mut self) -> Result<Event, RecvError> {
+        let missed = self.missed.swap(0, Ordering::Relaxed);
+        if missed > 0 {
+            return Err(RecvError::Lagged(missed));
+        }
+        self.events.recv().await.ok_or(RecvError::Closed)
+    }
+
+    /// Next queued event without waiting
+    pub fn try_recv(&mut self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+
+    /// Events as a stream which logs and skips over lag
+    pub fn into_stream(self) -> impl Stream<Item = Event> + Send + 'static {
+        stream::unfold(self, |mut subscription| async move {
+            loop {
+                match subscription.recv().await {
+                    Ok(event) => return Some((event, subscription)),
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Subscriber {} missed {} events", subscription.name, missed)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_lossy_subscriber_lags_without_blocking_others() {
+        let bus = EventBus::new(2);
+        let mut slow = bus.subscribe("slow", &[Topic::Connection], Delivery::Lossy);
+        let mut reliable = bus.subscribe("reliable", &[Topic::Connection], Delivery::Reliable);
+        let mut other = bus.subscribe("other", &[Topic::Trades], Delivery::Lossy);
+
+        let publisher = async {
+            for _ in 0..3 {
+                bus.publish(Event::Connection(ConnectionState::Connected))
+                    .await;
+            }
+        };
+        let consumer = async {
+            for _ in 0..3 {
+                assert_eq!(
+                    reliable.recv().await,
+                    Ok(Event::Connection(ConnectionState::Connected))
+                );
+            }
+        };
+        tokio::join!(publisher, consumer);
+
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged(1)));
+        assert!(slow.recv().await.is_ok());
+        assert!(slow.recv().await.is_ok());
+        assert_eq!(slow.try_recv(), None);
+        assert_eq!(other.try_recv(), None);
+        assert_eq!(bus.missed(), 1);
+
+        drop(slow);
+        assert_eq!(bus.subscribers(), 2);
+    }
+}
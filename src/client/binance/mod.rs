@@ -0,0 +1,260 @@
+//! Binance spot market data: the `<symbol>@trade` stream and `/api/v3/klines`.
+//!
+//! Binance concatenates symbols, e.g. `BTCUSDT`, they are translated to and from
+//! the collector's `BTC_USDT` form at this boundary
+
+pub mod models;
+pub mod rest;
+pub mod ws;
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use self::{
+    models::{BinanceKline, BinanceSymbol, BinanceTrade},
+    rest::BinanceRest,
+    ws::BinanceWsBuilder,
+};
+use crate::{
+    common::models::{Kline, KlineSource, TimeFrame, Trade, Vbs},
+    exchange::{Exchange, ExchangeError, Market, MarketStream},
+    stream::TradeBufferConfig,
+};
+
+/// `BTC_USDT` -> `BTCUSDT`
+pub fn to_binance_symbol(symbol: &str) -> String {
+    symbol.replace('_', "").to_uppercase()
+}
+
+/// Binance symbols of the subscribed markets mapped back to the collector's form.
+///
+/// A concatenated symbol can't be split reliably, so `BTCUSDT` is only known as
+/// `BTC_USDT` once that symbol was subscribed
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap(HashMap<String, String>);
+
+impl SymbolMap {
+    pub fn new(symbols: &[String]) -> Self {
+        let mut map = Self::default();
+        map.extend(symbols);
+        map
+    }
+
+    /// Adds `symbols`, given in the collector's form
+    pub fn extend(&mut self, symbols: &[String]) {
+        self.0.extend(
+            symbols
+                .iter()
+                .map(|symbol| (to_binance_symbol(symbol), symbol.clone())),
+        );
+    }
+
+    /// `BTCUSDT` -> `BTC_USDT`, `None` if no subscribed symbol matches
+    pub fn collector_symbol(&self, symbol: &str) -> Option<&str> {
+        self.0.get(&symbol.to_uppercase()).map(String::as_str)
+    }
+}
+
+/// Converts a trade stream event, `None` if its symbol isn't in `symbols` or its
+/// price and quantity aren't numbers
+pub fn make_trade_from_binance(trade: BinanceTrade, symbols: &SymbolMap) -> Option<Trade> {
+    Some(Trade {
+        symbol: symbols.collector_symbol(&trade.symbol)?.to_string(),
+        amount: quote_amount(&trade.price, &trade.quantity)?,
+        taker_side: if trade.buyer_is_maker { "sell" } else { "buy" }.to_string(),
+        quantity: trade.quantity,
+        create_time: trade.trade_time,
+        price: trade.price,
+        id: trade.id.to_string(),
+        ts: trade.event_time,
+    })
+}
+
+/// `price * quantity` with as many decimals as both factors together, so exact
+/// decimal inputs don't pick up float noise
+fn quote_amount(price: &str, quantity: &str) -> Option<String> {
+    let decimals = |value: &str| value.split_once('.').map_or(0, |(_, frac)| frac.len());
+    let amount = price.parse::<f64>().ok()? * quantity.parse::<f64>().ok()?;
+    let amount = format!("{:.*}", decimals(price) + decimals(quantity), amount);
+
+    if !amount.contains('.') {
+        return Some(amount);
+    }
+    Some(
+        amount
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string(),
+    )
+}
+
+/// Converts a `/api/v3/klines` row of `symbol`, given in the collector's form.
+///
+/// Binance reports the taker buy volume, the rest of the volume was sold
+pub fn make_kline_from_binance(
+    symbol: &str,
+    timeframe: TimeFrame,
+    row: &BinanceKline,
+) -> Option<Kline> {
+    let float = |value: &str| value.parse::<f64>().ok();
+    let utc_begin = DateTime::from_timestamp_millis(row.0)?;
+    let utc_end = utc_begin + timeframe.duration();
+    let (volume, quote_volume) = (float(&row.5)?, float(&row.7)?);
+    let (buy_base, buy_quote) = (float(&row.9)?, float(&row.10)?);
+
+    Some(Kline {
+        pair: symbol.to_string(),
+        timeframe,
+        open: float(&row.1)?,
+        high: float(&row.2)?,
+        low: float(&row.3)?,
+        close: float(&row.4)?,
+        utc_begin: utc_begin.timestamp(),
+        utc_end: utc_end.timestamp(),
+        volume_bs: Vbs {
+            buy_base,
+            sell_base: volume - buy_base,
+            buy_quote,
+            sell_quote: quote_volume - buy_quote,
+        },
+        source: KlineSource::Exchange,
+    })
+}
+
+impl From<BinanceSymbol> for Market {
+    fn from(symbol: BinanceSymbol) -> Self {
+        Self {
+            symbol: format!("{}_{}", symbol.base_asset, symbol.quote_asset),
+            base: symbol.base_asset,
+            quote: symbol.quote_asset,
+            trading: symbol.status == "TRADING",
+        }
+    }
+}
+
+/// Binance spot market data over `BinanceRest` and `BinanceWs`
+pub struct Binance {
+    rest: Arc<BinanceRest>,
+    ws: BinanceWsBuilder,
+    buffer_config: TradeBufferConfig,
+}
+
+impl Binance {
+    /// Every `connect` opens a new WebSocket configured by `ws`
+    pub fn new(rest: Arc<BinanceRest>, ws: BinanceWsBuilder) -> Self {
+        Self {
+            rest,
+            ws,
+            buffer_config: TradeBufferConfig::default(),
+        }
+    }
+
+    pub fn with_buffer_config(mut self, buffer_config: TradeBufferConfig) -> Self {
+        self.buffer_config = buffer_config;
+        self
+    }
+}
+
+#[async_trait]
+impl Exchange for Binance {
+    fn name(&self) -> &'static str {
+        "binance"
+    }
+
+    async fn connect(&self) -> Result<Box<dyn MarketStream>, ExchangeError> {
+        let ws = self.ws.clone().connect().await?;
+        Ok(Box::new(ws.with_buffer_config(self.buffer_config.clone())))
+    }
+
+    async fn candles(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, ExchangeError> {
+        self.rest
+            .klines(symbol, timeframe, start_time, end_time)
+            .await
+    }
+
+    async fn markets(&self) -> Result<Vec<Market>, ExchangeError> {
+        let symbols = self.rest.exchange_info().await?;
+        Ok(symbols.into_iter().map(Market::from).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::models::ExchangeInfo;
+    use super::*;
+
+    const KLINES: &str = include_str!("../../../fixtures/binance/klines_1h.json");
+    const EXCHANGE_INFO: &str = include_str!("../../../fixtures/binance/exchange_info.json");
+
+    #[test]
+    fn symbols_are_mapped_both_ways() {
+        assert_eq!(to_binance_symbol("BTC_USDT"), "BTCUSDT");
+        assert_eq!(to_binance_symbol("eth_btc"), "ETHBTC");
+
+        let symbols = SymbolMap::new(&[
+            "BTC_USDT".to_string(),
+            "ETH_BTC".to_string(),
+            "BTC_FDUSD".to_string(),
+            "1000SATS_USDT".to_string(),
+        ]);
+        assert_eq!(symbols.collector_symbol("BTCUSDT"), Some("BTC_USDT"));
+        assert_eq!(symbols.collector_symbol("ethbtc"), Some("ETH_BTC"));
+        assert_eq!(symbols.collector_symbol("BTCFDUSD"), Some("BTC_FDUSD"));
+        assert_eq!(
+            symbols.collector_symbol("1000SATSUSDT"),
+            Some("1000SATS_USDT")
+        );
+        assert_eq!(symbols.collector_symbol("USDT"), None);
+        assert_eq!(symbols.collector_symbol("BTCXYZ"), None);
+    }
+
+    #[test]
+    fn recorded_klines_and_markets_are_normalized() {
+        let rows: Vec<BinanceKline> = serde_json::from_str(KLINES).unwrap();
+        let klines: Vec<Kline> = rows
+            .iter()
+            .filter_map(|row| make_kline_from_binance("BTC_USDT", TimeFrame::Hour, row))
+            .collect();
+
+        assert_eq!(klines.len(), 2);
+        let first = &klines[0];
+        assert_eq!(first.pair, "BTC_USDT");
+        assert_eq!((first.utc_begin, first.utc_end), (1704067200, 1704070800));
+        assert_eq!(
+            (first.open, first.high, first.low, first.close),
+            (42283.58, 42554.57, 42261.02, 42475.23)
+        );
+        assert_eq!(first.volume_bs.buy_base, 682.57581);
+        assert!((first.volume_bs.sell_base - 589.10527).abs() < 1e-9);
+        assert_eq!(first.source, KlineSource::Exchange);
+        assert_eq!(klines[1].utc_begin, first.utc_end);
+
+        let info: ExchangeInfo = serde_json::from_str(EXCHANGE_INFO).unwrap();
+        let markets: Vec<Market> = info.symbols.into_iter().map(Market::from).collect();
+        assert_eq!(
+            markets,
+            vec![
+                Market {
+                    symbol: "BTC_USDT".to_string(),
+                    base: "BTC".to_string(),
+                    quote: "USDT".to_string(),
+                    trading: true,
+                },
+                Market {
+                    symbol: "LUNA_USDT".to_string(),
+                    base: "LUNA".to_string(),
+                    quote: "USDT".to_string(),
+                    trading: false,
+                },
+            ]
+        );
+    }
+}
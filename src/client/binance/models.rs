@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+// WS models
+
+/// Messages on a raw `/ws` connection
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BinanceWsEvent {
+    Trade(BinanceTrade),
+    /// Rejected request, `id` is missing when the request couldn't be parsed
+    Error {
+        error: BinanceError,
+        id: Option<u64>,
+    },
+    /// Answer to a `SUBSCRIBE` request, `result` is null on success
+    Response {
+        result: Option<serde_json::Value>,
+        id: u64,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BinanceError {
+    pub code: i64,
+    pub msg: String,
+}
+
+/// Event of the `<symbol>@trade` stream
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BinanceTrade {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    /// Binance symbol, e.g. `BTCUSDT`
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "t")]
+    pub id: u64,
+    #[serde(rename = "p")]
+    pub price: String,
+    #[serde(rename = "q")]
+    pub quantity: String,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+    /// The buyer placed the resting order, so the taker sold
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BinanceWsRequest {
+    /// `SUBSCRIBE` or `UNSUBSCRIBE`
+    pub method: String,
+    /// Stream names, e.g. `btcusdt@trade`
+    pub params: Vec<String>,
+    pub id: u64,
+}
+
+// REST models
+
+/// Row of `/api/v3/klines`
+/// `[openTime, open, high, low, close, volume, closeTime, quoteVolume, trades,
+/// takerBuyBaseVolume, takerBuyQuoteVolume, ignore]`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BinanceKline(
+    pub i64,
+    pub String,
+    pub String,
+    pub String,
+    pub String,
+    pub String,
+    pub i64,
+    pub String,
+    pub u64,
+    pub String,
+    pub String,
+    pub serde_json::Value,
+);
+
+#[derive(Deserialize, Debug)]
+pub struct ExchangeInfo {
+    pub symbols: Vec<BinanceSymbol>,
+}
+
+/// Entry of `/api/v3/exchangeInfo`, fields the collector doesn't use are skipped
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BinanceSymbol {
+    pub symbol: String,
+    /// `TRADING` while the market is open
+    pub status: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+}
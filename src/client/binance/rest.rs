@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::{Proxy, Url};
+use serde::de::DeserializeOwned;
+
+use super::models::{BinanceKline, BinanceSymbol, ExchangeInfo};
+use super::{make_kline_from_binance, to_binance_symbol};
+use crate::common::models::{Kline, TimeFrame};
+use crate::exchange::ExchangeError;
+use crate::metrics::metrics;
+
+pub const BINANCE_ENDPOINT: &str = "https://api.binance.com/api/v3/";
+/// Most klines Binance returns for one request
+pub const MAX_KLINES_PER_REQUEST: i64 = 1000;
+static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct BinanceRest {
+    session: reqwest::Client,
+    endpoint: String,
+}
+
+impl Default for BinanceRest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures the HTTP client used by `BinanceRest`
+pub struct BinanceRestBuilder {
+    endpoint: String,
+    timeout: Option<Duration>,
+    proxy: Option<String>,
+    accept_invalid_certs: bool,
+}
+
+impl BinanceRestBuilder {
+    /// `endpoint` is the `/api/v3/` base URL, request paths are appended to it
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Disables certificate validation. Only meant for local test servers
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn build(self) -> Result<BinanceRest, reqwest::Error> {
+        let mut builder =
+            reqwest::Client::builder().danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(BinanceRest {
+            session: builder.build()?,
+            endpoint: self.endpoint,
+        })
+    }
+}
+
+impl BinanceRest {
+    pub fn new() -> Self {
+        Self::with_endpoint(BINANCE_ENDPOINT)
+    }
+
+    pub fn with_endpoint(endpoint: &str) -> Self {
+        Self {
+            session: reqwest::Client::new(),
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    pub fn builder() -> BinanceRestBuilder {
+        BinanceRestBuilder {
+            endpoint: BINANCE_ENDPOINT.to_string(),
+            timeout: None,
+            proxy: None,
+            accept_invalid_certs: false,
+        }
+    }
+
+    fn url(&self, path: &str, params: &[(&str, String)]) -> Url {
+        Url::parse_with_params(&format!("{}{}", self.endpoint, path), params)
+            .expect("Failed to parse URL with parameters")
+    }
+
+    #[tracing::instrument(
+        name = "rest_request",
+        skip_all,
+        fields(
+            exchange = "binance",
+            request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            request = path,
+        )
+    )]
+    async fn get<T: DeserializeOwned>(&self, path: &str, url: Url) -> Result<T, ExchangeError> {
+        metrics().rest_requests.with_label_values(&[path]).inc();
+
+        let response = self
+            .session
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let text = match response {
+            Ok(response) => response.text().await?,
+            Err(err) => {
+                metrics().rest_errors.with_label_values(&[path]).inc();
+                tracing::warn!(error = %err, "Request failed");
+                return Err(err.into());
+            }
+        };
+        tracing::info!(bytes = text.len(), "Received response");
+
+        serde_json::from_str(&text).map_err(|err| {
+            metrics().rest_errors.with_label_values(&[path]).inc();
+            tracing::warn!(error = %err, "Failed to decode response");
+            ExchangeError::Decode(err)
+        })
+    }
+
+    /// Raw klines of a Binance symbol, e.g. `BTCUSDT`, opened in `[start_time, end_time]`
+    pub async fn klines_raw(
+        &self,
+        symbol: &str,
+        interval: &str,
+        start_time: i64,
+        end_time: i64,
+    ) -> Result<Vec<BinanceKline>, ExchangeError> {
+        let url = self.url(
+            "klines",
+            &[
+                ("symbol", symbol.to_string()),
+                ("interval", interval.to_string()),
+                ("startTime", start_time.to_string()),
+                ("endTime", end_time.to_string()),
+                ("limit", MAX_KLINES_PER_REQUEST.to_string()),
+            ],
+        );
+        self.get("klines", url).await
+    }
+
+    /// Klines of `symbol` in the collector's form, e.g. `BTC_USDT`, starting in
+    /// `[start_time, end_time)`. Long ranges are split into several requests
+    pub async fn klines(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, ExchangeError> {
+        let chunk = timeframe.duration() * MAX_KLINES_PER_REQUEST as i32;
+        let binance_symbol = to_binance_symbol(symbol);
+        let mut klines = Vec::new();
+
+        let mut chunk_start = start_time;
+        while chunk_start < end_time {
+            let chunk_end = (chunk_start + chunk).min(end_time);
+            let rows = self
+                .klines_raw(
+                    &binance_symbol,
+                    timeframe.as_ref(),
+                    chunk_start.timestamp_millis(),
+                    chunk_end.timestamp_millis() - 1,
+                )
+                .await?;
+
+            klines.extend(
+                rows.iter()
+                    .filter_map(|row| make_kline_from_binance(symbol, timeframe.clone(), row))
+                    .filter(|kline| {
+                        kline.utc_begin >= chunk_start.timestamp()
+                            && kline.utc_begin < chunk_end.timestamp()
+                    }),
+            );
+            chunk_start = chunk_end;
+        }
+
+        klines.sort_by_key(|kline| kline.utc_begin);
+        Ok(klines)
+    }
+
+    pub async fn exchange_info(&self) -> Result<Vec<BinanceSymbol>, ExchangeError> {
+        let info: ExchangeInfo = self
+            .get("exchangeInfo", self.url("exchangeInfo", &[]))
+            .await?;
+        Ok(info.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn klines_url_uses_binance_symbol_and_interval() {
+        let client = BinanceRest::with_endpoint("http://127.0.0.1:8080/api/v3/");
+        let url = client.url(
+            "klines",
+            &[
+                ("symbol", to_binance_symbol("BTC_USDT")),
+                ("interval", TimeFrame::Minutes15.as_ref().to_string()),
+            ],
+        );
+
+        assert_eq!(
+            url.as_str(),
+            "http://127.0.0.1:8080/api/v3/klines?symbol=BTCUSDT&interval=15m"
+        );
+    }
+}
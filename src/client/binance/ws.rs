@@ -0,0 +1,404 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_util::{
+    stream::{SplitSink, SplitStream, StreamExt},
+    SinkExt, Stream,
+};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
+use tracing::Instrument;
+use tungstenite::{error::Error, Message};
+
+use super::{
+    make_trade_from_binance,
+    models::{BinanceWsEvent, BinanceWsRequest},
+    to_binance_symbol, SymbolMap,
+};
+use crate::{
+    bus::{ConnectionState, Event},
+    exchange::{ExchangeError, MarketStream},
+    metrics::metrics,
+    stream::{
+        publish_connection, reconnect, store_published_trades, ReconnectPolicy, TradeBufferConfig,
+        WsConnectOptions, WsStream,
+    },
+    SharedState,
+};
+
+pub const BINANCE_ENDPOINT: &str = "wss://stream.binance.com:9443/ws";
+type WsSink = SplitSink<WsStream, Message>;
+type WsSource = SplitStream<WsStream>;
+
+/// Configures how `BinanceWs` connects, through the same path as `PoloniexWs`
+#[derive(Clone)]
+pub struct BinanceWsBuilder {
+    connection: WsConnectOptions,
+    reconnect: ReconnectPolicy,
+}
+
+impl BinanceWsBuilder {
+    /// Raw stream endpoint, streams are subscribed after connecting
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.connection = self.connection.endpoint(endpoint);
+        self
+    }
+
+    /// See `WsConnectOptions::connect_timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection = self.connection.connect_timeout(timeout);
+        self
+    }
+
+    /// See `WsConnectOptions::proxy`
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.connection = self.connection.proxy(proxy);
+        self
+    }
+
+    /// See `WsConnectOptions::root_certificate`
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.connection = self.connection.root_certificate(pem);
+        self
+    }
+
+    /// See `WsConnectOptions::accept_invalid_certs`
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.connection = self.connection.accept_invalid_certs(accept);
+        self
+    }
+
+    /// Backoff of `read_and_store` between attempts to reopen a lost connection
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    pub async fn connect(self) -> Result<BinanceWs, Error> {
+        let (sink, source) = self.connection.open().await?.split();
+
+        Ok(BinanceWs {
+            sink: Arc::new(Mutex::new(sink)),
+            source: Arc::new(Mutex::new(source)),
+            buffer_config: TradeBufferConfig::default(),
+            request_id: Arc::new(AtomicU64::new(1)),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            symbols: Arc::new(RwLock::new(SymbolMap::default())),
+            builder: self,
+        })
+    }
+}
+
+/// Binance pings the client itself and tungstenite answers, so no heartbeat is sent
+pub struct BinanceWs {
+    sink: Arc<Mutex<WsSink>>,
+    source: Arc<Mutex<WsSource>>,
+    buffer_config: TradeBufferConfig,
+    request_id: Arc<AtomicU64>,
+    /// Symbols of every `subscribe_trade_streams` call, subscribed again after a reconnect
+    subscriptions: Arc<Mutex<Vec<Vec<String>>>>,
+    /// Maps stream symbols back to the subscribed ones
+    symbols: Arc<RwLock<SymbolMap>>,
+    builder: BinanceWsBuilder,
+}
+
+impl BinanceWs {
+    pub async fn new() -> Result<Self, Error> {
+        Self::connect(BINANCE_ENDPOINT).await
+    }
+
+    pub async fn connect(endpoint: &str) -> Result<Self, Error> {
+        Self::builder().endpoint(endpoint).connect().await
+    }
+
+    pub fn builder() -> BinanceWsBuilder {
+        BinanceWsBuilder {
+            connection: WsConnectOptions::new(BINANCE_ENDPOINT),
+            reconnect: ReconnectPolicy::default(),
+        }
+    }
+
+    pub fn with_buffer_config(mut self, buffer_config: TradeBufferConfig) -> Self {
+        self.buffer_config = buffer_config;
+        self
+    }
+
+    /// Subscribes the `<symbol>@trade` streams of `symbols`, given in the collector's form
+    pub async fn subscribe_trade_streams(&self, symbols: &[String]) -> Result<(), Error> {
+        self.subscriptions.lock().await.push(symbols.to_vec());
+        self.symbols.write().unwrap().extend(symbols);
+        let mut sink = self.sink.lock().await;
+        Self::send_subscription(&mut sink, &self.request_id, symbols).await
+    }
+
+    async fn send_subscription(
+        sink: &mut WsSink,
+        request_id: &AtomicU64,
+        symbols: &[String],
+    ) -> Result<(), Error> {
+        let request = BinanceWsRequest {
+            method: "SUBSCRIBE".to_string(),
+            params: symbols
+                .iter()
+                .map(|symbol| format!("{}@trade", to_binance_symbol(symbol).to_lowercase()))
+                .collect(),
+            id: request_id.fetch_add(1, Ordering::Relaxed),
+        };
+        let json_message =
+            serde_json::to_string(&request).expect("failed to serialize subscription msg");
+
+        sink.send(Message::Text(json_message.into())).await?;
+
+        tracing::info!(streams = ?request.params, id = request.id, "Sent subscription");
+        Ok(())
+    }
+
+    /// Publishes received trades on the bus and stores them like `PoloniexWs::read_and_store`,
+    /// reopening a lost connection with backoff and subscribing its streams again
+    pub fn read_and_store(
+        &self,
+        state: SharedState,
+        shutdown: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        let source = self.source.clone();
+        let sink = self.sink.clone();
+        let request_id = self.request_id.clone();
+        let subscriptions = self.subscriptions.clone();
+        let symbols = self.symbols.clone();
+        let builder = self.builder.clone();
+        let buffer_config = self.buffer_config.clone();
+
+        let reader = async move {
+            let mut stream_lock = source.lock().await;
+            store_published_trades(&state, buffer_config, async {
+                loop {
+                    publish_connection(&state, ConnectionState::Connected).await;
+                    Self::publish_frames(&mut *stream_lock, &state, &symbols, shutdown.clone())
+                        .await;
+                    publish_connection(&state, ConnectionState::Disconnected).await;
+
+                    let Some(stream) = reconnect(&builder.reconnect, shutdown.clone(), || {
+                        builder.connection.open()
+                    })
+                    .await
+                    else {
+                        break;
+                    };
+                    let (new_sink, new_source) = stream.split();
+                    *stream_lock = new_source;
+                    let mut sink_lock = sink.lock().await;
+                    *sink_lock = new_sink;
+                    for symbols in subscriptions.lock().await.iter() {
+                        if let Err(err) =
+                            Self::send_subscription(&mut sink_lock, &request_id, symbols).await
+                        {
+                            tracing::warn!("Failed to resubscribe: {}", err);
+                        }
+                    }
+                    tracing::info!("Reconnected to Binance");
+                }
+            })
+            .await;
+        };
+        tokio::spawn(reader.instrument(tracing::info_span!("read_and_store", exchange = "binance")))
+    }
+
+    /// Decodes frames from a live connection or a `Replay` until the stream ends
+    /// and stores the trades of `symbols` among them
+    pub async fn read_frames<S>(
+        frames: &mut S,
+        state: SharedState,
+        symbols: SymbolMap,
+        buffer_config: TradeBufferConfig,
+        shutdown: watch::Receiver<bool>,
+    ) where
+        S: Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        store_published_trades(
+            &state,
+            buffer_config,
+            Self::publish_frames(frames, &state, &RwLock::new(symbols), shutdown),
+        )
+        .await;
+    }
+
+    async fn publish_frames<S>(
+        frames: &mut S,
+        state: &SharedState,
+        symbols: &RwLock<SymbolMap>,
+        mut shutdown: watch::Receiver<bool>,
+    ) where
+        S: Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        loop {
+            let msg = tokio::select! {
+                msg = frames.next() => msg,
+                _ = shutdown.changed() => {
+                    tracing::info!("Stopping trades reader");
+                    break;
+                }
+            };
+            let Some(msg) = msg else {
+                tracing::warn!("Binance stream ended");
+                break;
+            };
+
+            let data = match msg {
+                Ok(Message::Text(data)) => data,
+                Ok(Message::Close(frame)) => {
+                    tracing::warn!("Binance closed the stream: {:?}", frame);
+                    break;
+                }
+                // control frames are answered by tungstenite itself
+                Ok(_) => continue,
+                Err(err) => {
+                    tracing::error!("Failed to read Binance stream: {}", err);
+                    break;
+                }
+            };
+
+            match serde_json::from_str::<BinanceWsEvent>(&data) {
+                Ok(BinanceWsEvent::Trade(trade)) => {
+                    metrics().ws_messages.with_label_values(&["trade"]).inc();
+                    let trade = make_trade_from_binance(trade, &symbols.read().unwrap());
+                    let Some(trade) = trade else {
+                        metrics().ws_decode_failures.inc();
+                        tracing::warn!(frame = data.as_str(), "Failed to normalize trade");
+                        continue;
+                    };
+                    state.health.trade_received(&trade.symbol);
+                    let span = tracing::debug_span!(
+                        "trades",
+                        channel = "trade",
+                        symbol = trade.symbol.as_str(),
+                        count = 1,
+                    );
+                    state
+                        .bus
+                        .publish(Event::Trade(trade))
+                        .instrument(span)
+                        .await;
+                }
+                Ok(BinanceWsEvent::Response { result, id }) => {
+                    metrics().ws_messages.with_label_values(&["response"]).inc();
+                    tracing::info!(id, ?result, "Received subscription response");
+                }
+                Ok(BinanceWsEvent::Error { error, id }) => {
+                    metrics().ws_messages.with_label_values(&["error"]).inc();
+                    tracing::error!(?id, code = error.code, msg = error.msg, "Request rejected");
+                }
+                Err(err) => {
+                    metrics().ws_decode_failures.inc();
+                    tracing::warn!(frame = data.as_str(), error = %err, "Failed to decode frame");
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MarketStream for BinanceWs {
+    /// Only `trades` has a Binance counterpart, the `@trade` stream
+    async fn subscribe(
+        &self,
+        channels: &[String],
+        symbols: &[String],
+    ) -> Result<(), ExchangeError> {
+        if let Some(channel) = channels.iter().find(|channel| *channel != "trades") {
+            return Err(ExchangeError::Unsupported(format!(
+                "{} channel on binance",
+                channel
+            )));
+        }
+        if channels.is_empty() {
+            return Ok(());
+        }
+
+        Ok(self.subscribe_trade_streams(symbols).await?)
+    }
+
+    fn run(self: Box<Self>, state: SharedState, shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        self.read_and_store(state, shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        aggregator::Aggregator,
+        bus::{Delivery, Topic},
+        client::recording::Replay,
+        common::models::{TimeFrame, Trade},
+        test_support::memory_state,
+    };
+
+    const TRADES: &str = include_str!("../../../fixtures/binance/trades.jsonl");
+
+    #[tokio::test]
+    async fn recorded_trades_are_normalized_and_stored() {
+        let state = memory_state().await;
+        let mut events = state
+            .bus
+            .subscribe("test", &[Topic::Trades], Delivery::Lossy);
+
+        let mut frames = Box::pin(
+            Replay::parse(TRADES)
+                .unwrap()
+                .speed(f64::INFINITY)
+                .into_stream(),
+        );
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        BinanceWs::read_frames(
+            &mut frames,
+            state.clone(),
+            SymbolMap::new(&["BTC_USDT".to_string(), "ETH_USDT".to_string()]),
+            TradeBufferConfig::default(),
+            shutdown_rx,
+        )
+        .await;
+        state.db.flush().await.unwrap();
+
+        match events.recv().await {
+            Ok(Event::Trade(trade)) => assert_eq!(
+                trade,
+                Trade {
+                    symbol: "BTC_USDT".to_string(),
+                    amount: "210.0005".to_string(),
+                    taker_side: "buy".to_string(),
+                    quantity: "0.005".to_string(),
+                    create_time: 1704067200100,
+                    price: "42000.10".to_string(),
+                    id: "3300000001".to_string(),
+                    ts: 1704067200102,
+                }
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let start = DateTime::from_timestamp(1704067200, 0).unwrap();
+        let end = DateTime::from_timestamp(1704070800, 0).unwrap();
+        let klines = Aggregator::aggregate(&state, TimeFrame::Hour, start, end)
+            .await
+            .unwrap();
+
+        // the unknown symbol and the subscription response don't reach storage
+        assert_eq!(klines.len(), 2);
+        let btc = &klines[0];
+        assert_eq!(btc.pair, "BTC_USDT");
+        assert_eq!((btc.open, btc.close), (42000.10, 42001.00));
+        assert_eq!(btc.volume_bs.buy_base, 0.005);
+        assert_eq!(btc.volume_bs.sell_base, 0.01);
+        assert_eq!(klines[1].pair, "ETH_USDT");
+    }
+}
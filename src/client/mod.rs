@@ -1,3 +1,6 @@
+pub mod binance;
 pub mod models;
+pub mod poloniex;
+pub mod recording;
 pub mod rest;
 pub mod ws;
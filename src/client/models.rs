@@ -1,5 +1,11 @@
 use serde::{Deserialize, Serialize};
-use strum_macros::AsRefStr;
+use std::str::FromStr;
+
+use strum_macros::{AsRefStr, EnumString};
+
+use crate::common::models::{
+    FundingRate, OpenInterest, PriceKind, ReferencePrice, TimeFrame, Trade,
+};
 
 // WS models
 
@@ -9,18 +15,45 @@ use strum_macros::AsRefStr;
 pub enum PoloniexWsEvent {
     Trades {
         channel: String,
-        data: Vec<Trade>,
+        data: Vec<PoloniexTrade>,
+    },
+    Book {
+        channel: String,
+        data: Vec<BookUpdate>,
+    },
+    /// `trade` channel of the futures stream
+    FuturesTrades {
+        channel: String,
+        data: Vec<FuturesTrade>,
+    },
+    /// `mark_price` channel of the futures stream
+    MarkPrices {
+        channel: String,
+        data: Vec<FuturesMarkPrice>,
+    },
+    /// `index_price` channel of the futures stream
+    IndexPrices {
+        channel: String,
+        data: Vec<FuturesIndexPrice>,
+    },
+    /// `funding_rate` channel of the futures stream
+    FundingRates {
+        channel: String,
+        data: Vec<FuturesFundingRate>,
     },
     Confirmation {
         channel: String,
         event: String,
         symbols: Vec<String>,
     },
+    /// Any other event, e.g. `pong` or `error`
+    Event { event: String },
 }
 
-#[derive(Deserialize, Debug)]
+/// Trade as pushed on the `trades` channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
-pub struct Trade {
+pub struct PoloniexTrade {
     pub symbol: String,
     pub amount: String,
     pub taker_side: String,
@@ -31,6 +64,151 @@ pub struct Trade {
     pub ts: u64,
 }
 
+impl From<PoloniexTrade> for Trade {
+    fn from(trade: PoloniexTrade) -> Self {
+        Self {
+            symbol: trade.symbol,
+            id: trade.id,
+            price: trade.price,
+            quantity: trade.quantity,
+            amount: trade.amount,
+            taker_side: trade.taker_side.to_lowercase(),
+            create_time: trade.create_time,
+            ts: trade.ts,
+        }
+    }
+}
+
+/// Perpetual trade, pushed on the futures `trade` channel and returned by `trades`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FuturesTrade {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    pub id: u64,
+    #[serde(rename = "px")]
+    pub price: String,
+    #[serde(rename = "qty")]
+    pub quantity: String,
+    #[serde(rename = "amt")]
+    pub amount: String,
+    /// Taker side, `buy` or `sell`
+    pub side: String,
+    #[serde(rename = "cT")]
+    pub create_time: u64,
+    pub ts: u64,
+}
+
+impl From<FuturesTrade> for Trade {
+    fn from(trade: FuturesTrade) -> Self {
+        Self {
+            symbol: trade.symbol,
+            id: trade.id.to_string(),
+            price: trade.price,
+            quantity: trade.quantity,
+            amount: trade.amount,
+            taker_side: trade.side.to_lowercase(),
+            create_time: trade.create_time,
+            ts: trade.ts,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FuturesMarkPrice {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "mPx")]
+    pub mark_price: String,
+    pub ts: u64,
+}
+
+impl From<FuturesMarkPrice> for ReferencePrice {
+    fn from(price: FuturesMarkPrice) -> Self {
+        Self {
+            symbol: price.symbol,
+            kind: PriceKind::Mark,
+            price: price.mark_price,
+            ts: price.ts,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FuturesIndexPrice {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "iPx")]
+    pub index_price: String,
+    pub ts: u64,
+}
+
+impl From<FuturesIndexPrice> for ReferencePrice {
+    fn from(price: FuturesIndexPrice) -> Self {
+        Self {
+            symbol: price.symbol,
+            kind: PriceKind::Index,
+            price: price.index_price,
+            ts: price.ts,
+        }
+    }
+}
+
+/// Current funding period, the history endpoint leaves out `ts`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FuturesFundingRate {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "fR")]
+    pub rate: String,
+    /// Settlement time of the period, unix milliseconds
+    #[serde(rename = "fT")]
+    pub funding_time: u64,
+    pub ts: Option<u64>,
+}
+
+impl From<FuturesFundingRate> for FundingRate {
+    fn from(rate: FuturesFundingRate) -> Self {
+        Self {
+            symbol: rate.symbol,
+            rate: rate.rate,
+            funding_time: rate.funding_time,
+            ts: rate.ts.unwrap_or(rate.funding_time),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FuturesOpenInterest {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "oInterest")]
+    pub open_interest: String,
+    pub ts: u64,
+}
+
+impl From<FuturesOpenInterest> for OpenInterest {
+    fn from(interest: FuturesOpenInterest) -> Self {
+        Self {
+            symbol: interest.symbol,
+            open_interest: interest.open_interest,
+            ts: interest.ts,
+        }
+    }
+}
+
+/// Top of the order book from the `book` channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BookUpdate {
+    pub symbol: String,
+    pub create_time: u64,
+    /// `[price, quantity]` pairs, best first
+    pub asks: Vec<[String; 2]>,
+    pub bids: Vec<[String; 2]>,
+    pub id: u64,
+    pub ts: u64,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "event")]
@@ -46,6 +224,8 @@ pub enum WebSocketMessage {
 
 #[derive(Debug, AsRefStr)]
 pub enum PoloniexRequest {
+    #[strum(serialize = "markets")]
+    Markets,
     #[strum(serialize = "candles")]
     Candles {
         symbol: String,
@@ -53,6 +233,59 @@ pub enum PoloniexRequest {
         start_time: u64,
         end_time: u64,
     },
+    /// Latest perpetual trades, newest first
+    #[strum(serialize = "trades")]
+    Trades { symbol: String, limit: u32 },
+    #[strum(serialize = "markPrice")]
+    MarkPrice { symbol: String },
+    #[strum(serialize = "indexPrice")]
+    IndexPrice { symbol: String },
+    #[strum(serialize = "fundingRate")]
+    FundingRate { symbol: String },
+    /// Settled funding periods with `fT` in `[start_time, end_time]`, unix milliseconds
+    #[strum(serialize = "fundingRate/history")]
+    FundingRateHistory {
+        symbol: String,
+        start_time: u64,
+        end_time: u64,
+        limit: u32,
+    },
+    #[strum(serialize = "openInterest")]
+    OpenInterest { symbol: String },
+}
+
+impl PoloniexRequest {
+    pub fn symbol(&self) -> Option<&str> {
+        match self {
+            PoloniexRequest::Markets => None,
+            PoloniexRequest::Candles { symbol, .. }
+            | PoloniexRequest::Trades { symbol, .. }
+            | PoloniexRequest::MarkPrice { symbol }
+            | PoloniexRequest::IndexPrice { symbol }
+            | PoloniexRequest::FundingRate { symbol }
+            | PoloniexRequest::FundingRateHistory { symbol, .. }
+            | PoloniexRequest::OpenInterest { symbol } => Some(symbol),
+        }
+    }
+}
+
+/// Entry of the `markets` response, fields the collector doesn't use are skipped
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PoloniexMarket {
+    pub symbol: String,
+    pub base_currency_name: String,
+    pub quote_currency_name: String,
+    /// `NORMAL` while the market is open for trading
+    pub state: String,
+}
+
+/// Envelope of the v3 market data responses
+#[derive(Deserialize, Debug)]
+pub struct PoloniexResponse<T> {
+    pub code: u32,
+    pub msg: String,
+    pub data: T,
 }
 
 pub type RawKLHistory = Vec<Vec<String>>;
@@ -64,7 +297,8 @@ pub struct KL {
     pub data: RawKLHistory,
 }
 
-#[derive(Debug, AsRefStr)]
+#[derive(Debug, Clone, PartialEq, AsRefStr, EnumString, Deserialize)]
+#[serde(try_from = "String")]
 pub enum PoloniexKLineIntervals {
     #[strum(serialize = "MINUTE_1")]
     Minute1,
@@ -89,3 +323,20 @@ pub enum PoloniexKLineIntervals {
     #[strum(serialize = "WEEK_1")]
     Week1,
 }
+
+impl From<&TimeFrame> for PoloniexKLineIntervals {
+    fn from(timeframe: &TimeFrame) -> Self {
+        match timeframe {
+            TimeFrame::Minutes15 => PoloniexKLineIntervals::Minute15,
+            TimeFrame::Hour => PoloniexKLineIntervals::Hour1,
+        }
+    }
+}
+
+impl TryFrom<String> for PoloniexKLineIntervals {
+    type Error = strum::ParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::{sync::watch, task::JoinHandle};
+
+use super::{
+    models::PoloniexMarket,
+    rest::PoloniexRest,
+    ws::{PoloniexWs, PoloniexWsBuilder},
+};
+use crate::{
+    common::models::{Kline, TimeFrame},
+    exchange::{Exchange, ExchangeError, Market, MarketStream},
+    stream::TradeBufferConfig,
+    SharedState,
+};
+
+/// Poloniex spot market data over `PoloniexRest` and `PoloniexWs`.
+///
+/// Poloniex already uses `BASE_QUOTE` symbols, so they are passed through unchanged
+pub struct Poloniex {
+    rest: Arc<PoloniexRest>,
+    ws: PoloniexWsBuilder,
+    buffer_config: TradeBufferConfig,
+}
+
+impl Poloniex {
+    /// Every `connect` opens a new WebSocket configured by `ws`
+    pub fn new(rest: Arc<PoloniexRest>, ws: PoloniexWsBuilder) -> Self {
+        Self {
+            rest,
+            ws,
+            buffer_config: TradeBufferConfig::default(),
+        }
+    }
+
+    pub fn with_buffer_config(mut self, buffer_config: TradeBufferConfig) -> Self {
+        self.buffer_config = buffer_config;
+        self
+    }
+}
+
+#[async_trait]
+impl Exchange for Poloniex {
+    fn name(&self) -> &'static str {
+        "poloniex"
+    }
+
+    async fn connect(&self) -> Result<Box<dyn MarketStream>, ExchangeError> {
+        let ws = self.ws.clone().connect().await?;
+        Ok(Box::new(ws.with_buffer_config(self.buffer_config.clone())))
+    }
+
+    async fn candles(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, ExchangeError> {
+        Ok(self
+            .rest
+            .klines(symbol, timeframe, start_time, end_time)
+            .await?)
+    }
+
+    async fn markets(&self) -> Result<Vec<Market>, ExchangeError> {
+        let markets = self.rest.markets().await?;
+        Ok(markets.into_iter().map(Market::from).collect())
+    }
+}
+
+impl From<PoloniexMarket> for Market {
+    fn from(market: PoloniexMarket) -> Self {
+        Self {
+            symbol: market.symbol,
+            base: market.base_currency_name,
+            quote: market.quote_currency_name,
+            trading: market.state == "NORMAL",
+        }
+    }
+}
+
+#[async_trait]
+impl MarketStream for PoloniexWs {
+    async fn subscribe(
+        &self,
+        channels: &[String],
+        symbols: &[String],
+    ) -> Result<(), ExchangeError> {
+        PoloniexWs::subscribe(self, channels.to_vec(), symbols.to_vec()).await;
+        Ok(())
+    }
+
+    fn run(self: Box<Self>, state: SharedState, shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        self.init_heartbeat();
+        self.read_and_store(state, shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::MockPoloniex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn markets_are_normalized() {
+        let mock = MockPoloniex::start().await;
+
+        let markets = mock.exchange().markets().await.unwrap();
+        assert_eq!(
+            markets,
+            vec![
+                Market {
+                    symbol: "BTC_USDT".to_string(),
+                    base: "BTC".to_string(),
+                    quote: "USDT".to_string(),
+                    trading: true,
+                },
+                Market {
+                    symbol: "LUNA_USDT".to_string(),
+                    base: "LUNA".to_string(),
+                    quote: "USDT".to_string(),
+                    trading: false,
+                },
+            ]
+        );
+        assert_eq!(mock.requests(), vec!["/v3/market/markets"]);
+    }
+}
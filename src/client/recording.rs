@@ -0,0 +1,191 @@
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::{watch, Mutex},
+    task::JoinHandle,
+    time::sleep,
+};
+use tungstenite::{error::Error, Message};
+
+use crate::SharedState;
+
+use super::ws::PoloniexWs;
+use crate::stream::TradeBufferConfig;
+
+/// One inbound text frame as it was received, stored as a JSON line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    /// Unix milliseconds
+    pub received_at: i64,
+    pub data: String,
+}
+
+/// Appends every raw frame read by `PoloniexWs` to a file
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub async fn record(&mut self, data: &str) -> io::Result<()> {
+        let frame = RecordedFrame {
+            received_at: Utc::now().timestamp_millis(),
+            data: data.to_string(),
+        };
+        let mut line = serde_json::to_string(&frame)?;
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes()).await
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+pub type SharedRecorder = Arc<Mutex<Recorder>>;
+
+/// Feeds a recorded session through the same decoding and buffering as a live connection.
+///
+/// Frames are delayed by their original spacing divided by `speed`,
+/// `f64::INFINITY` replays them without any delay
+pub struct Replay {
+    frames: Vec<RecordedFrame>,
+    speed: f64,
+}
+
+impl Replay {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let frames = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<RecordedFrame>, _>>()?;
+
+        Ok(Self { frames, speed: 1.0 })
+    }
+
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Receive times of the first and the last frame
+    pub fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let first = DateTime::from_timestamp_millis(self.frames.first()?.received_at)?;
+        let last = DateTime::from_timestamp_millis(self.frames.last()?.received_at)?;
+        Some((first, last))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, Error>> {
+        let speed = self.speed;
+        let mut previous = self.frames.first().map(|frame| frame.received_at);
+
+        stream::iter(self.frames).then(move |frame| {
+            let gap = frame.received_at - previous.unwrap_or(frame.received_at);
+            previous = Some(frame.received_at);
+
+            async move {
+                if gap > 0 && speed.is_finite() && speed > 0.0 {
+                    sleep(Duration::from_millis(gap as u64).div_f64(speed)).await;
+                }
+                Ok(Message::Text(frame.data.into()))
+            }
+        })
+    }
+
+    /// Stores the replayed trades, the task finishes after the last frame or on `shutdown`
+    pub fn run(
+        self,
+        state: SharedState,
+        buffer_config: TradeBufferConfig,
+        shutdown: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut frames = Box::pin(self.into_stream());
+            PoloniexWs::read_frames(&mut frames, state, buffer_config, None, shutdown).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{aggregator::Aggregator, common::models::TimeFrame, test_support::memory_state};
+
+    #[tokio::test]
+    async fn recorded_session_is_replayed_into_klines() {
+        let path = std::env::temp_dir().join(format!("session-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut recorder = Recorder::create(&path).await.unwrap();
+        recorder
+            .record(r#"{"event":"subscribe","channel":"trades","symbols":["BTC_USDT"]}"#)
+            .await
+            .unwrap();
+        for (id, price, side) in [(1, "100", "buy"), (2, "80", "sell"), (3, "90", "buy")] {
+            let trade = format!(
+                r#"{{"channel":"trades","data":[{{"symbol":"BTC_USDT","amount":"{price}","takerSide":"{side}","quantity":"1","createTime":{ts},"price":"{price}","id":"{id}","ts":{ts}}}]}}"#,
+                price = price,
+                side = side,
+                id = id,
+                ts = id * 1000,
+            );
+            recorder.record(&trade).await.unwrap();
+        }
+        recorder.flush().await.unwrap();
+
+        let replay = Replay::open(&path).await.unwrap().speed(f64::INFINITY);
+        std::fs::remove_file(&path).unwrap();
+        assert!(replay.time_range().is_some());
+
+        let state = memory_state().await;
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        replay
+            .run(state.clone(), TradeBufferConfig::default(), shutdown_rx)
+            .await
+            .unwrap();
+        state.db.flush().await.unwrap();
+
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let end = DateTime::from_timestamp(1800, 0).unwrap();
+        let klines = Aggregator::aggregate_windows(&state, TimeFrame::Minutes15, start, end)
+            .await
+            .unwrap();
+
+        assert_eq!(klines.len(), 1);
+        assert_eq!(
+            (
+                klines[0].open,
+                klines[0].high,
+                klines[0].low,
+                klines[0].close
+            ),
+            (100.0, 100.0, 80.0, 90.0)
+        );
+        assert_eq!(klines[0].volume_bs.buy_base, 2.0);
+    }
+}
@@ -1,26 +1,145 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::Certificate;
 use reqwest::Method;
+use reqwest::Proxy;
 use reqwest::Url;
+use serde::de::DeserializeOwned;
 
-use super::models::PoloniexRequest;
 use super::models::KL;
+use super::models::{
+    FuturesFundingRate, FuturesIndexPrice, FuturesMarkPrice, FuturesOpenInterest, FuturesTrade,
+    PoloniexKLineIntervals, PoloniexMarket, PoloniexRequest, PoloniexResponse, RawKLHistory,
+};
+use crate::common::models::{FundingRate, Kline, OpenInterest, ReferencePrice, TimeFrame, Trade};
+use crate::common::utils::make_kline_from_candle;
+use crate::exchange::ExchangeError;
+use crate::metrics::metrics;
 
-const POLONIEX_ENDPOINT: &str = "https://api.poloniex.com/v3/market/";
+pub const POLONIEX_ENDPOINT: &str = "https://api.poloniex.com/v3/market/";
+/// Most candles Poloniex returns for one request
+pub const MAX_CANDLES_PER_REQUEST: i64 = 500;
+/// Most settled funding periods Poloniex returns for one request
+pub const MAX_FUNDING_RATES_PER_REQUEST: u32 = 100;
+/// Shortest funding period of Poloniex perpetuals, a funding history request spans
+/// `MAX_FUNDING_RATES_PER_REQUEST` of them, so no page is ever cut off
+pub const MIN_FUNDING_PERIOD_HOURS: i64 = 1;
+/// Tags the span of every REST request, so its log lines can be told apart
+static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct PoloniexRest {
     session: reqwest::Client,
+    endpoint: String,
+}
+
+impl Default for PoloniexRest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures the HTTP client used by `PoloniexRest`, e.g. to point it at a mock server
+/// in tests or at a regional mirror
+pub struct PoloniexRestBuilder {
+    endpoint: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    proxy: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    accept_invalid_certs: bool,
+}
+
+impl PoloniexRestBuilder {
+    /// `endpoint` is the market data base URL, request paths are appended to it
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
+    /// Total time of a request including reading the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// HTTP(S) or SOCKS proxy URL used for every request
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Trusts an additional PEM encoded root certificate
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Disables certificate validation. Only meant for local test servers
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn build(self) -> Result<PoloniexRest, reqwest::Error> {
+        let mut builder =
+            reqwest::Client::builder().danger_accept_invalid_certs(self.accept_invalid_certs);
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+
+        Ok(PoloniexRest {
+            session: builder.build()?,
+            endpoint: self.endpoint,
+        })
+    }
 }
 
 impl PoloniexRest {
     pub fn new() -> Self {
+        Self::with_endpoint(POLONIEX_ENDPOINT)
+    }
+
+    /// `endpoint` is the market data base URL, request paths are appended to it
+    pub fn with_endpoint(endpoint: &str) -> Self {
         Self {
             session: reqwest::Client::new(),
+            endpoint: endpoint.to_string(),
         }
     }
 
-    fn build_request(&self, req: PoloniexRequest) -> reqwest::Request {
-        let base_url = format!("{}{}", POLONIEX_ENDPOINT, req.as_ref());
+    pub fn builder() -> PoloniexRestBuilder {
+        PoloniexRestBuilder {
+            endpoint: POLONIEX_ENDPOINT.to_string(),
+            timeout: None,
+            connect_timeout: None,
+            proxy: None,
+            root_certificates: Vec::new(),
+            accept_invalid_certs: false,
+        }
+    }
+
+    fn build_request(&self, req: PoloniexRequest) -> Result<reqwest::Request, reqwest::Error> {
+        let base_url = format!("{}{}", self.endpoint, req.as_ref());
 
         let url = match req {
+            PoloniexRequest::Markets => Url::parse(&base_url).expect("Failed to parse URL"),
             PoloniexRequest::Candles {
                 symbol,
                 interval,
@@ -34,31 +153,257 @@ impl PoloniexRest {
                     ("endTime", end_time.to_string()),
                 ];
 
+                Url::parse_with_params(&base_url, &params)
+                    .expect("Failed to parse URL with parameters")
+            }
+            PoloniexRequest::Trades { symbol, limit } => {
+                let params = [("symbol", symbol), ("limit", limit.to_string())];
+
+                Url::parse_with_params(&base_url, &params)
+                    .expect("Failed to parse URL with parameters")
+            }
+            PoloniexRequest::MarkPrice { symbol }
+            | PoloniexRequest::IndexPrice { symbol }
+            | PoloniexRequest::FundingRate { symbol }
+            | PoloniexRequest::OpenInterest { symbol } => {
+                Url::parse_with_params(&base_url, &[("symbol", symbol)])
+                    .expect("Failed to parse URL with parameters")
+            }
+            PoloniexRequest::FundingRateHistory {
+                symbol,
+                start_time,
+                end_time,
+                limit,
+            } => {
+                let params = [
+                    ("symbol", symbol),
+                    ("sT", start_time.to_string()),
+                    ("eT", end_time.to_string()),
+                    ("limit", limit.to_string()),
+                ];
+
                 Url::parse_with_params(&base_url, &params)
                     .expect("Failed to parse URL with parameters")
             }
         };
 
-        self.session
-            .request(Method::GET, url)
-            .build()
-            .expect("failed to call poloniex")
+        self.session.request(Method::GET, url).build()
     }
 
-    pub async fn request(&self, req: PoloniexRequest) -> Result<KL, reqwest::Error> {
-        let build_request = self.build_request(req);
-        let response = self.session.execute(build_request).await?;
+    #[tracing::instrument(
+        name = "rest_request",
+        skip_all,
+        fields(
+            request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            request = req.as_ref(),
+            symbol = req.symbol(),
+        )
+    )]
+    async fn fetch(&self, req: PoloniexRequest) -> Result<String, ExchangeError> {
+        let name = req.as_ref().to_string();
+        metrics().rest_requests.with_label_values(&[&name]).inc();
+
+        let build_request = self.build_request(req)?;
+        let response = match self.session.execute(build_request).await {
+            Ok(response) => response,
+            Err(err) => {
+                metrics().rest_errors.with_label_values(&[&name]).inc();
+                tracing::warn!(error = %err, "Request failed");
+                return Err(err.into());
+            }
+        };
+        let status = response.status();
+        if !status.is_success() {
+            metrics().rest_errors.with_label_values(&[&name]).inc();
+        }
         let text = response.text().await?;
 
-        tracing::info!("Received KL");
+        tracing::info!(
+            status = status.as_u16(),
+            bytes = text.len(),
+            "Received response"
+        );
+        Ok(text)
+    }
+
+    pub async fn request(&self, req: PoloniexRequest) -> Result<KL, ExchangeError> {
+        let text = self.fetch(req).await?;
+        // error bodies and changed payloads end up here as well
+        serde_json::from_str(&text).map_err(ExchangeError::Decode)
+    }
+
+    /// Raw `interval` candles of `symbol` in `[start_time, end_time]`, as backfill stores them
+    pub async fn candles(
+        &self,
+        symbol: &str,
+        interval: PoloniexKLineIntervals,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<RawKLHistory, ExchangeError> {
+        self.fetch_data(PoloniexRequest::Candles {
+            symbol: symbol.to_string(),
+            interval,
+            start_time: start_time.timestamp_millis() as u64,
+            end_time: end_time.timestamp_millis() as u64,
+        })
+        .await
+    }
+
+    /// Every spot market, including those currently closed for trading
+    pub async fn markets(&self) -> Result<Vec<PoloniexMarket>, ExchangeError> {
+        let text = self.fetch(PoloniexRequest::Markets).await?;
+        serde_json::from_str(&text).map_err(ExchangeError::Decode)
+    }
+
+    /// `data` of a v3 market data response
+    async fn fetch_data<T: DeserializeOwned>(
+        &self,
+        req: PoloniexRequest,
+    ) -> Result<T, ExchangeError> {
+        let text = self.fetch(req).await?;
+        let response: PoloniexResponse<T> =
+            serde_json::from_str(&text).map_err(ExchangeError::Decode)?;
+        Ok(response.data)
+    }
 
-        Ok(serde_json::from_str(&text).expect("Failed to deserialize kline json"))
+    /// Latest trades of a perpetual, e.g. `BTC_USDT_PERP`, newest first
+    pub async fn recent_trades(
+        &self,
+        symbol: &str,
+        limit: u32,
+    ) -> Result<Vec<Trade>, ExchangeError> {
+        let trades: Vec<FuturesTrade> = self
+            .fetch_data(PoloniexRequest::Trades {
+                symbol: symbol.to_string(),
+                limit,
+            })
+            .await?;
+        Ok(trades.into_iter().map(Trade::from).collect())
     }
+
+    pub async fn mark_price(&self, symbol: &str) -> Result<Vec<ReferencePrice>, ExchangeError> {
+        let prices: Vec<FuturesMarkPrice> = self
+            .fetch_data(PoloniexRequest::MarkPrice {
+                symbol: symbol.to_string(),
+            })
+            .await?;
+        Ok(prices.into_iter().map(ReferencePrice::from).collect())
+    }
+
+    pub async fn index_price(&self, symbol: &str) -> Result<Vec<ReferencePrice>, ExchangeError> {
+        let prices: Vec<FuturesIndexPrice> = self
+            .fetch_data(PoloniexRequest::IndexPrice {
+                symbol: symbol.to_string(),
+            })
+            .await?;
+        Ok(prices.into_iter().map(ReferencePrice::from).collect())
+    }
+
+    /// Rate of the funding period currently running
+    pub async fn funding_rate(&self, symbol: &str) -> Result<Vec<FundingRate>, ExchangeError> {
+        let rates: Vec<FuturesFundingRate> = self
+            .fetch_data(PoloniexRequest::FundingRate {
+                symbol: symbol.to_string(),
+            })
+            .await?;
+        Ok(rates.into_iter().map(FundingRate::from).collect())
+    }
+
+    /// Settled funding periods of `symbol` with `funding_time` in `[start_time, end_time)`.
+    /// Long ranges are split into several requests
+    pub async fn funding_rate_history(
+        &self,
+        symbol: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, ExchangeError> {
+        let chunk = chrono::Duration::hours(MIN_FUNDING_PERIOD_HOURS)
+            * MAX_FUNDING_RATES_PER_REQUEST as i32;
+        let mut rates = Vec::new();
+
+        let mut chunk_start = start_time;
+        while chunk_start < end_time {
+            let chunk_end = (chunk_start + chunk).min(end_time);
+            let response: Vec<FuturesFundingRate> = self
+                .fetch_data(PoloniexRequest::FundingRateHistory {
+                    symbol: symbol.to_string(),
+                    start_time: chunk_start.timestamp_millis() as u64,
+                    end_time: last_millis_before(chunk_end),
+                    limit: MAX_FUNDING_RATES_PER_REQUEST,
+                })
+                .await?;
+
+            rates.extend(response.into_iter().map(FundingRate::from).filter(|rate| {
+                rate.funding_time >= chunk_start.timestamp_millis() as u64
+                    && rate.funding_time < chunk_end.timestamp_millis() as u64
+            }));
+            chunk_start = chunk_end;
+        }
+
+        rates.sort_by_key(|rate| rate.funding_time);
+        Ok(rates)
+    }
+
+    pub async fn open_interest(&self, symbol: &str) -> Result<Vec<OpenInterest>, ExchangeError> {
+        let interest: Vec<FuturesOpenInterest> = self
+            .fetch_data(PoloniexRequest::OpenInterest {
+                symbol: symbol.to_string(),
+            })
+            .await?;
+        Ok(interest.into_iter().map(OpenInterest::from).collect())
+    }
+
+    /// Exchange candles of `symbol` as klines starting in `[start_time, end_time)`.
+    /// Long ranges are split into several requests
+    pub async fn klines(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, ExchangeError> {
+        let chunk = timeframe.duration() * MAX_CANDLES_PER_REQUEST as i32;
+        let mut klines = Vec::new();
+
+        let mut chunk_start = start_time;
+        while chunk_start < end_time {
+            let chunk_end = (chunk_start + chunk).min(end_time);
+            let response = self
+                .request(PoloniexRequest::Candles {
+                    symbol: symbol.to_string(),
+                    interval: timeframe.into(),
+                    start_time: chunk_start.timestamp_millis() as u64,
+                    end_time: last_millis_before(chunk_end),
+                })
+                .await?;
+
+            klines.extend(
+                response
+                    .data
+                    .iter()
+                    .filter_map(|row| make_kline_from_candle(symbol, timeframe.clone(), row))
+                    .filter(|kline| {
+                        kline.utc_begin >= chunk_start.timestamp()
+                            && kline.utc_begin < chunk_end.timestamp()
+                    }),
+            );
+            chunk_start = chunk_end;
+        }
+
+        klines.sort_by_key(|kline| kline.utc_begin);
+        Ok(klines)
+    }
+}
+
+/// Poloniex ranges include their end, this is the last millisecond of a range ending at `end`
+fn last_millis_before(end: DateTime<Utc>) -> u64 {
+    (end.timestamp_millis().max(0) as u64).saturating_sub(1)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::client::models::PoloniexKLineIntervals;
+    use crate::test_support::MockPoloniex;
 
     use super::*;
 
@@ -71,14 +416,133 @@ mod tests {
             end_time: 10001,
         };
         let client = PoloniexRest::new();
-        let request = client.build_request(req);
+        let request = client.build_request(req).unwrap();
 
         assert_eq!(
             request.url().as_str(),
             format!(
                 "{}{}",
-                POLONIEX_ENDPOINT, "candles?symbol=BTC_USDT_PERP&interval=MINUTE_15"
+                POLONIEX_ENDPOINT,
+                "candles?symbol=BTC_USDT_PERP&interval=MINUTE_15&startTime=10000&endTime=10001"
             )
         )
     }
+
+    #[test]
+    fn builder_uses_custom_endpoint() {
+        let client = PoloniexRest::builder()
+            .endpoint("http://127.0.0.1:8080/v3/market/")
+            .timeout(Duration::from_secs(1))
+            .proxy("http://127.0.0.1:3128")
+            .build()
+            .unwrap();
+        let request = client
+            .build_request(PoloniexRequest::Candles {
+                symbol: String::from("BTC_USDT"),
+                interval: PoloniexKLineIntervals::Hour1,
+                start_time: 1,
+                end_time: 2,
+            })
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            "http://127.0.0.1:8080/v3/market/candles?symbol=BTC_USDT&interval=HOUR_1&startTime=1&endTime=2"
+        )
+    }
+
+    #[tokio::test]
+    async fn candles_are_fetched_from_mock_server() {
+        let mock = MockPoloniex::start().await;
+        let row: Vec<String> = ["1", "2", "1.5", "1.8", "100", "60", "7", "0", "899999"]
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        mock.set_candles("BTC_USDT", vec![row.clone()]);
+
+        let client = PoloniexRest::with_endpoint(&mock.rest_endpoint());
+        let candles = client
+            .candles(
+                "BTC_USDT",
+                PoloniexKLineIntervals::Minute15,
+                DateTime::from_timestamp(0, 0).unwrap(),
+                DateTime::from_timestamp(900, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // the range is sent in milliseconds
+        assert_eq!(candles, vec![row]);
+        assert_eq!(
+            mock.requests(),
+            vec![
+                "/v3/market/candles?symbol=BTC_USDT&interval=MINUTE_15&startTime=0&endTime=900000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn error_body_is_reported_instead_of_panicking() {
+        let mock = MockPoloniex::start().await;
+        // the mock answers unknown paths with `{"code":404,"msg":"Not Found"}`
+        let endpoint = mock.rest_endpoint().replace("/v3/", "/v2/");
+        let client = PoloniexRest::with_endpoint(&endpoint);
+
+        let result = client
+            .request(PoloniexRequest::Candles {
+                symbol: String::from("BTC_USDT"),
+                interval: PoloniexKLineIntervals::Minute15,
+                start_time: 0,
+                end_time: 899999,
+            })
+            .await;
+
+        assert!(matches!(result, Err(ExchangeError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn funding_history_is_fetched_oldest_first() {
+        let mock = MockPoloniex::start().await;
+        let client = PoloniexRest::with_endpoint(&mock.rest_endpoint());
+        let start = DateTime::from_timestamp(1704067200, 0).unwrap();
+        let end = DateTime::from_timestamp(1704124800, 0).unwrap();
+
+        let rates = client
+            .funding_rate_history("BTC_USDT_PERP", start, end)
+            .await
+            .unwrap();
+
+        let funding_times: Vec<u64> = rates.iter().map(|rate| rate.funding_time).collect();
+        assert_eq!(funding_times, vec![1704067200000, 1704096000000]);
+        assert_eq!(rates[0].rate, "-0.00003");
+        assert_eq!(rates[0].ts, rates[0].funding_time);
+        assert_eq!(
+            mock.requests(),
+            vec!["/v3/market/fundingRate/history?symbol=BTC_USDT_PERP&sT=1704067200000&eT=1704124799999&limit=100"]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_funding_history_is_split_into_requests() {
+        let mock = MockPoloniex::start().await;
+        let client = PoloniexRest::with_endpoint(&mock.rest_endpoint());
+        let start = DateTime::from_timestamp(1704067200, 0).unwrap();
+        let end = start + chrono::Duration::hours(150);
+
+        let rates = client
+            .funding_rate_history("BTC_USDT_PERP", start, end)
+            .await
+            .unwrap();
+
+        // the mock answers every request with the same periods, they are kept once
+        assert_eq!(rates.len(), 2);
+        assert_eq!(
+            mock.requests(),
+            vec![
+                "/v3/market/fundingRate/history?symbol=BTC_USDT_PERP&sT=1704067200000&eT=1704427199999&limit=100",
+                "/v3/market/fundingRate/history?symbol=BTC_USDT_PERP&sT=1704427200000&eT=1704607199999&limit=100",
+            ]
+        );
+        assert_eq!(last_millis_before(DateTime::UNIX_EPOCH), 0);
+    }
 }
@@ -1,30 +1,154 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use futures_util::{stream::StreamExt, SinkExt};
-use tokio::{sync::Mutex, time::interval};
-use tokio_tungstenite::connect_async;
+use futures_util::{
+    stream::{SplitSink, SplitStream, StreamExt},
+    SinkExt, Stream,
+};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::Instrument;
 use tungstenite::{error::Error, Message};
 
-use crate::SharedState;
+use crate::{
+    bus::{ConnectionState, Event},
+    common::models::Trade,
+    metrics::metrics,
+    stream::{
+        publish_connection, reconnect, store_published_trades, ReconnectPolicy, TradeBufferConfig,
+        WsConnectOptions, WsStream,
+    },
+    SharedState,
+};
 
-use super::models::{PoloniexWsEvent, Trade, WebSocketMessage};
+use super::{
+    models::{PoloniexWsEvent, WebSocketMessage},
+    recording::{Recorder, SharedRecorder},
+};
 
-const POLONIEX_ENDPOINT: &str = "wss://ws.poloniex.com/ws/public";
-const TRADES_BUFFER_SIZE: usize = 100;
-pub type WsStream =
-    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+pub const POLONIEX_ENDPOINT: &str = "wss://ws.poloniex.com/ws/public";
+/// Public perpetual futures stream, with the `trade`, `mark_price`, `index_price`
+/// and `funding_rate` channels
+pub const POLONIEX_FUTURES_ENDPOINT: &str = "wss://ws.poloniex.com/ws/v3/public";
+// Poloniex disconnects after 30 seconds with no ping
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(29);
+type WsSink = SplitSink<WsStream, Message>;
+type WsSource = SplitStream<WsStream>;
 
+/// Configures how `PoloniexWs` connects, e.g. to a mock server in tests or a regional mirror
+#[derive(Clone)]
+pub struct PoloniexWsBuilder {
+    connection: WsConnectOptions,
+    heartbeat_interval: Duration,
+    reconnect: ReconnectPolicy,
+    record_to: Option<PathBuf>,
+}
+
+impl PoloniexWsBuilder {
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.connection = self.connection.endpoint(endpoint);
+        self
+    }
+
+    /// How often `init_heartbeat` pings the server
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Backoff of `read_and_store` between attempts to reopen a lost connection
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Appends every received text frame to `path`, see `Replay` for playing it back
+    pub fn record_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record_to = Some(path.into());
+        self
+    }
+
+    /// See `WsConnectOptions::connect_timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection = self.connection.connect_timeout(timeout);
+        self
+    }
+
+    /// See `WsConnectOptions::proxy`
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.connection = self.connection.proxy(proxy);
+        self
+    }
+
+    /// See `WsConnectOptions::root_certificate`
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.connection = self.connection.root_certificate(pem);
+        self
+    }
+
+    /// See `WsConnectOptions::accept_invalid_certs`
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.connection = self.connection.accept_invalid_certs(accept);
+        self
+    }
+
+    pub async fn connect(self) -> Result<PoloniexWs, Error> {
+        let stream = self.connection.open().await?;
+
+        let recorder = match &self.record_to {
+            Some(path) => Some(Arc::new(Mutex::new(Recorder::create(path).await?))),
+            None => None,
+        };
+        let (sink, source) = stream.split();
+
+        Ok(PoloniexWs {
+            sink: Arc::new(Mutex::new(sink)),
+            source: Arc::new(Mutex::new(source)),
+            buffer_config: TradeBufferConfig::default(),
+            heartbeat_interval: self.heartbeat_interval,
+            recorder,
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            builder: self,
+        })
+    }
+}
+
+/// Reading and writing halves are locked separately, so heartbeats and subscriptions
+/// are sent while `read_and_store` is waiting for messages
 pub struct PoloniexWs {
-    stream: Arc<Mutex<WsStream>>,
+    sink: Arc<Mutex<WsSink>>,
+    source: Arc<Mutex<WsSource>>,
+    buffer_config: TradeBufferConfig,
+    heartbeat_interval: Duration,
+    recorder: Option<SharedRecorder>,
+    /// Subscribe messages sent so far, sent again after a reconnect
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    builder: PoloniexWsBuilder,
 }
 
 impl PoloniexWs {
     pub async fn new() -> Result<Self, Error> {
-        let (stream, _) = connect_async(POLONIEX_ENDPOINT).await?;
+        Self::connect(POLONIEX_ENDPOINT).await
+    }
 
-        Ok(Self {
-            stream: Arc::new(Mutex::new(stream)),
-        })
+    pub async fn connect(endpoint: &str) -> Result<Self, Error> {
+        Self::builder().endpoint(endpoint).connect().await
+    }
+
+    pub fn builder() -> PoloniexWsBuilder {
+        PoloniexWsBuilder {
+            connection: WsConnectOptions::new(POLONIEX_ENDPOINT),
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            reconnect: ReconnectPolicy::default(),
+            record_to: None,
+        }
+    }
+
+    pub fn with_buffer_config(mut self, buffer_config: TradeBufferConfig) -> Self {
+        self.buffer_config = buffer_config;
+        self
     }
 
     pub async fn subscribe(&self, channel: Vec<String>, symbols: Vec<String>) {
@@ -33,74 +157,254 @@ impl PoloniexWs {
         let json_message: String = serde_json::to_string(&subscription_message)
             .expect("failed to serialize subscription msg");
 
-        let mut write = self.stream.lock().await;
+        self.subscriptions.lock().await.push(json_message.clone());
+        let mut write = self.sink.lock().await;
 
         let _ = write.send(Message::Text(json_message.into())).await;
 
         tracing::info!("Sent subscription for {:?} {:?}", channel, symbols);
     }
 
-    pub fn read_and_store(&self, state: SharedState) {
-        let stream = self.stream.clone();
+    /// Publishes received trades and book updates on the bus, which a storage subscriber
+    /// buffers and hands to the storage writer once the buffer holds `max_trades`
+    /// or the oldest trade has waited `max_latency`.
+    /// A lost connection is reopened with backoff and the subscriptions are sent again.
+    /// Whatever is left in the buffer is flushed when `shutdown` fires
+    pub fn read_and_store(
+        &self,
+        state: SharedState,
+        shutdown: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        let source = self.source.clone();
+        let sink = self.sink.clone();
+        let subscriptions = self.subscriptions.clone();
+        let builder = self.builder.clone();
+        let buffer_config = self.buffer_config.clone();
+        let recorder = self.recorder.clone();
 
-        tokio::spawn(async move {
-            let mut stream_lock = stream.lock().await;
-            let state = state.lock().await;
-
-            let mut trade_buffer: Vec<Trade> = Vec::new();
-
-            while let Some(msg) = stream_lock.next().await {
-                match msg.expect("failed to read rt ws") {
-                    Message::Text(data) => {
-                        let data_string = data.to_string();
-                        let ser_message: PoloniexWsEvent =
-                            serde_json::from_str(&data_string).unwrap();
-
-                        match ser_message {
-                            PoloniexWsEvent::Trades {
-                                channel: _,
-                                data: trades,
-                            } => {
-                                trade_buffer.extend(trades);
-                                if trade_buffer.len() >= TRADES_BUFFER_SIZE {
-                                    state.db.insert_recent_trades(&trade_buffer);
-                                    trade_buffer.clear();
-                                }
-                            }
-                            PoloniexWsEvent::Confirmation {
-                                channel: _,
-                                event: _,
-                                symbols: _,
-                            } => {
-                                tracing::info!("Received confirmation on subscription");
-                            }
+        let reader = async move {
+            let mut stream_lock = source.lock().await;
+            store_published_trades(&state, buffer_config, async {
+                loop {
+                    publish_connection(&state, ConnectionState::Connected).await;
+                    Self::publish_frames(&mut *stream_lock, &state, &recorder, shutdown.clone())
+                        .await;
+                    publish_connection(&state, ConnectionState::Disconnected).await;
+
+                    let Some(stream) = reconnect(&builder.reconnect, shutdown.clone(), || {
+                        builder.connection.open()
+                    })
+                    .await
+                    else {
+                        break;
+                    };
+                    let (new_sink, new_source) = stream.split();
+                    *stream_lock = new_source;
+                    let mut sink_lock = sink.lock().await;
+                    *sink_lock = new_sink;
+                    for message in subscriptions.lock().await.iter() {
+                        if let Err(err) =
+                            sink_lock.send(Message::Text(message.clone().into())).await
+                        {
+                            tracing::warn!("Failed to resubscribe: {}", err);
                         }
                     }
-                    _ => panic!("wrong message type received"),
+                    tracing::info!("Reconnected to Poloniex");
+                }
+                flush_recording(&recorder).await;
+            })
+            .await;
+        };
+        tokio::spawn(reader.instrument(tracing::info_span!("read_and_store")))
+    }
+
+    /// Decodes frames from a live connection or a `Replay` until the stream ends
+    /// and stores the trades among them
+    pub async fn read_frames<S>(
+        frames: &mut S,
+        state: SharedState,
+        buffer_config: TradeBufferConfig,
+        recorder: Option<SharedRecorder>,
+        shutdown: watch::Receiver<bool>,
+    ) where
+        S: Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        store_published_trades(&state, buffer_config, async {
+            Self::publish_frames(frames, &state, &recorder, shutdown).await;
+            flush_recording(&recorder).await;
+        })
+        .await;
+    }
+
+    async fn publish_frames<S>(
+        frames: &mut S,
+        state: &SharedState,
+        recorder: &Option<SharedRecorder>,
+        mut shutdown: watch::Receiver<bool>,
+    ) where
+        S: Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        loop {
+            let msg = tokio::select! {
+                msg = frames.next() => msg,
+                _ = shutdown.changed() => {
+                    tracing::info!("Stopping trades reader");
+                    break;
+                }
+            };
+            let Some(msg) = msg else {
+                tracing::warn!("Poloniex stream ended");
+                break;
+            };
+
+            let data = match msg {
+                Ok(Message::Text(data)) => data,
+                Ok(Message::Close(frame)) => {
+                    tracing::warn!("Poloniex closed the stream: {:?}", frame);
+                    break;
+                }
+                // control frames are answered by tungstenite itself
+                Ok(_) => continue,
+                Err(err) => {
+                    tracing::error!("Failed to read Poloniex stream: {}", err);
+                    break;
+                }
+            };
+
+            if let Some(recorder) = recorder {
+                if let Err(err) = recorder.lock().await.record(&data).await {
+                    tracing::error!("Failed to record frame: {}", err);
                 }
             }
-        });
+
+            match serde_json::from_str::<PoloniexWsEvent>(&data) {
+                Ok(ser_message) => match ser_message {
+                    PoloniexWsEvent::Trades {
+                        channel,
+                        data: trades,
+                    } => {
+                        let trades = trades.into_iter().map(Trade::from).collect();
+                        Self::publish_trades(state, &channel, trades).await;
+                    }
+                    PoloniexWsEvent::Book {
+                        channel,
+                        data: updates,
+                    } => {
+                        metrics().ws_messages.with_label_values(&[&channel]).inc();
+                        for update in updates {
+                            let span = tracing::debug_span!(
+                                "book",
+                                channel = channel.as_str(),
+                                symbol = update.symbol.as_str(),
+                            );
+                            state
+                                .bus
+                                .publish(Event::Book(update))
+                                .instrument(span)
+                                .await;
+                        }
+                    }
+                    PoloniexWsEvent::FuturesTrades {
+                        channel,
+                        data: trades,
+                    } => {
+                        let trades = trades.into_iter().map(Trade::from).collect();
+                        Self::publish_trades(state, &channel, trades).await;
+                    }
+                    PoloniexWsEvent::MarkPrices {
+                        channel,
+                        data: prices,
+                    } => {
+                        metrics().ws_messages.with_label_values(&[&channel]).inc();
+                        for price in prices {
+                            state.bus.publish(Event::Price(price.into())).await;
+                        }
+                    }
+                    PoloniexWsEvent::IndexPrices {
+                        channel,
+                        data: prices,
+                    } => {
+                        metrics().ws_messages.with_label_values(&[&channel]).inc();
+                        for price in prices {
+                            state.bus.publish(Event::Price(price.into())).await;
+                        }
+                    }
+                    PoloniexWsEvent::FundingRates {
+                        channel,
+                        data: rates,
+                    } => {
+                        metrics().ws_messages.with_label_values(&[&channel]).inc();
+                        for rate in rates {
+                            state.bus.publish(Event::Funding(rate.into())).await;
+                        }
+                    }
+                    PoloniexWsEvent::Confirmation {
+                        channel,
+                        event,
+                        symbols,
+                    } => {
+                        metrics().ws_messages.with_label_values(&[&event]).inc();
+                        tracing::info!(
+                            channel,
+                            event,
+                            ?symbols,
+                            "Received subscription confirmation"
+                        );
+                    }
+                    PoloniexWsEvent::Event { event } => {
+                        metrics().ws_messages.with_label_values(&[&event]).inc();
+                        if event == "pong" {
+                            metrics().pong_received();
+                            state.health.pong_received();
+                        }
+                        tracing::debug!(event, "Received event");
+                    }
+                },
+                Err(err) => {
+                    metrics().ws_decode_failures.inc();
+                    tracing::warn!(frame = data.as_str(), error = %err, "Failed to decode frame");
+                }
+            }
+        }
+    }
+
+    async fn publish_trades(state: &SharedState, channel: &str, trades: Vec<Trade>) {
+        metrics().ws_messages.with_label_values(&[channel]).inc();
+        for trade in &trades {
+            state.health.trade_received(&trade.symbol);
+        }
+        let span = tracing::debug_span!(
+            "trades",
+            channel,
+            symbol = trades.first().map(|trade| trade.symbol.as_str()),
+            count = trades.len(),
+        );
+        state.bus.publish_trades(&trades).instrument(span).await;
     }
 
     pub fn init_heartbeat(&self) {
         let ping_message = WebSocketMessage::Ping;
         let json_message: String =
             serde_json::to_string(&ping_message).expect("failed to serialize hearbeat ping msg");
-        let stream = self.stream.clone();
+        let sink = self.sink.clone();
 
-        // Poloniex disconnects after 30 seconds with no ping
-        let mut interval = interval(Duration::from_secs(29));
+        let mut interval = interval(self.heartbeat_interval);
 
         tokio::spawn(async move {
-            let mut stream_lock = stream.lock().await;
-
             interval.tick().await; // skip first
             loop {
                 interval.tick().await;
 
-                let _ = stream_lock
+                let mut sink_lock = sink.lock().await;
+                if let Err(err) = sink_lock
                     .send(Message::Text(json_message.clone().into()))
-                    .await;
+                    .await
+                {
+                    // the reader swaps in a new sink once it has reconnected
+                    tracing::warn!("Failed to send heartbeat: {}", err);
+                    continue;
+                }
+                metrics().ping_sent();
 
                 tracing::info!("Sent heartbeat ping");
             }
@@ -114,3 +418,210 @@ impl PoloniexWs {
         }
     }
 }
+
+async fn flush_recording(recorder: &Option<SharedRecorder>) {
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.lock().await.flush().await {
+            tracing::error!("Failed to flush recording: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        aggregator::Aggregator,
+        bus::{Delivery, Topic},
+        common::models::{FundingRate, PriceKind, ReferencePrice, TimeFrame},
+        test_support::{memory_state, trade, MockPoloniex},
+    };
+
+    #[tokio::test]
+    async fn pushed_trades_are_stored_and_aggregated() {
+        let mock = MockPoloniex::start().await;
+        let state = memory_state().await;
+        let mut events =
+            state
+                .bus
+                .subscribe("test", &[Topic::Book, Topic::Connection], Delivery::Lossy);
+
+        let ws = PoloniexWs::builder()
+            .endpoint(&mock.ws_endpoint())
+            .reconnect(ReconnectPolicy {
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+            })
+            .connect()
+            .await
+            .unwrap();
+        ws.subscribe(vec!["trades".to_string()], vec!["BTC_USDT".to_string()])
+            .await;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let reader = ws.read_and_store(state.clone(), shutdown_rx);
+        mock.wait_for_subscriptions(1).await;
+
+        mock.push_raw("not json");
+        mock.push_raw(
+            r#"{"channel":"book","data":[{"symbol":"BTC_USDT","createTime":1000,"asks":[["101","2"]],"bids":[["99","1"]],"id":7,"ts":1001}]}"#,
+        );
+        mock.push_trades(&[
+            trade("1", "BTC_USDT", "buy", "100", 1_000),
+            trade("2", "ETH_USDT", "sell", "10", 2_000),
+        ]);
+        mock.push_trades(&[
+            trade("3", "BTC_USDT", "sell", "90", 3_000),
+            trade("4", "BTC_USDT", "buy", "120", 4_000),
+        ]);
+        let reconnects = metrics().ws_reconnects.get();
+        mock.disconnect();
+
+        // the reader reconnects and subscribes again, then flushes what it has buffered
+        mock.wait_for_subscriptions(2).await;
+        assert!(metrics().ws_reconnects.get() > reconnects);
+        let _ = shutdown_tx.send(true);
+        reader.await.unwrap();
+        state.db.flush().await.unwrap();
+        assert_eq!(
+            events.recv().await,
+            Ok(Event::Connection(ConnectionState::Connected))
+        );
+        match events.recv().await {
+            Ok(Event::Book(book)) => {
+                assert_eq!(book.asks, vec![["101".to_string(), "2".to_string()]])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            events.recv().await,
+            Ok(Event::Connection(ConnectionState::Disconnected))
+        );
+        assert_eq!(
+            events.recv().await,
+            Ok(Event::Connection(ConnectionState::Connected))
+        );
+        let subscription = (vec!["trades".to_string()], vec!["BTC_USDT".to_string()]);
+        assert_eq!(
+            mock.subscriptions(),
+            vec![subscription.clone(), subscription]
+        );
+
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let end = DateTime::from_timestamp(3600, 0).unwrap();
+        let klines = Aggregator::aggregate(&state, TimeFrame::Hour, start, end)
+            .await
+            .unwrap();
+
+        assert_eq!(klines.len(), 2);
+        let btc = &klines[0];
+        assert_eq!(btc.pair, "BTC_USDT");
+        assert_eq!(
+            (btc.open, btc.high, btc.low, btc.close),
+            (100.0, 120.0, 90.0, 120.0)
+        );
+        assert_eq!(btc.volume_bs.buy_base, 2.0);
+        assert_eq!(btc.volume_bs.sell_quote, 90.0);
+        assert_eq!(klines[1].pair, "ETH_USDT");
+
+        let stored = state
+            .db
+            .latest_kline("BTC_USDT".to_string(), TimeFrame::Hour)
+            .await
+            .unwrap();
+        assert_eq!(stored.as_ref(), Some(btc));
+    }
+
+    #[tokio::test]
+    async fn futures_frames_are_published() {
+        let mock = MockPoloniex::start().await;
+        let state = memory_state().await;
+        let mut events =
+            state
+                .bus
+                .subscribe("test", &[Topic::Prices, Topic::Funding], Delivery::Lossy);
+
+        let ws = PoloniexWs::connect(&mock.ws_endpoint()).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let reader = ws.read_and_store(state.clone(), shutdown_rx);
+
+        mock.push_raw(
+            r#"{"channel":"trade","data":[{"s":"BTC_USDT_PERP","id":41,"px":"42000","qty":"2","amt":"84000","side":"sell","cT":1000,"ts":1001}]}"#,
+        );
+        mock.push_raw(
+            r#"{"channel":"mark_price","data":[{"s":"BTC_USDT_PERP","mPx":"42001.5","ts":1002}]}"#,
+        );
+        mock.push_raw(
+            r#"{"channel":"index_price","data":[{"s":"BTC_USDT_PERP","iPx":"41999","ts":1003}]}"#,
+        );
+        mock.push_raw(
+            r#"{"channel":"funding_rate","data":[{"s":"BTC_USDT_PERP","fR":"0.0001","fT":28800000,"ts":1004}]}"#,
+        );
+
+        let price = |kind, price: &str, ts| {
+            Ok(Event::Price(ReferencePrice {
+                symbol: "BTC_USDT_PERP".to_string(),
+                kind,
+                price: price.to_string(),
+                ts,
+            }))
+        };
+        assert_eq!(events.recv().await, price(PriceKind::Mark, "42001.5", 1002));
+        assert_eq!(events.recv().await, price(PriceKind::Index, "41999", 1003));
+        assert_eq!(
+            events.recv().await,
+            Ok(Event::Funding(FundingRate {
+                symbol: "BTC_USDT_PERP".to_string(),
+                rate: "0.0001".to_string(),
+                funding_time: 28800000,
+                ts: 1004,
+            }))
+        );
+
+        // the trade was published before the prices, so the writer has it when stopped
+        let _ = shutdown_tx.send(true);
+        reader.await.unwrap();
+        state.db.flush().await.unwrap();
+
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let end = DateTime::from_timestamp(3600, 0).unwrap();
+        let trades = state
+            .db
+            .retrieve_trades_in_interval(Some("BTC_USDT_PERP".to_string()), start, end, None)
+            .await
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(
+            (trades[0].id.as_str(), trades[0].taker_side.as_str()),
+            ("41", "sell")
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeat_is_sent_while_reading() {
+        let mock = MockPoloniex::start().await;
+        let state = memory_state().await;
+
+        let ws = PoloniexWs::builder()
+            .endpoint(&mock.ws_endpoint())
+            .heartbeat_interval(Duration::from_millis(10))
+            .connect()
+            .await
+            .unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let reader = ws.read_and_store(state, shutdown_rx);
+        ws.init_heartbeat();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while mock.pings() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("no heartbeat reached the server");
+
+        // pongs are decoded without stopping the reader
+        assert!(!reader.is_finished());
+    }
+}
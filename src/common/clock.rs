@@ -0,0 +1,89 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use chrono::{DateTime, Utc};
+
+pub type SleepFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Source of the current time for scheduled jobs, so tests can drive them without waiting
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Resolves once `now()` has reached `deadline`
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> SleepFuture<'_>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Wall clock backed by `tokio::time`
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> SleepFuture<'_> {
+        let duration = (deadline - Utc::now()).to_std().unwrap_or_default();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+pub use manual::ManualClock;
+
+#[cfg(test)]
+mod manual {
+    use chrono::Duration;
+    use tokio::sync::watch;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy)]
+    struct Ticks {
+        now: DateTime<Utc>,
+        /// Number of `sleep_until` calls so far
+        sleeps: usize,
+    }
+
+    /// Clock which only moves when the test calls `advance`
+    pub struct ManualClock {
+        ticks: watch::Sender<Ticks>,
+    }
+
+    impl ManualClock {
+        pub fn new(now: DateTime<Utc>) -> Arc<Self> {
+            let (ticks, _) = watch::channel(Ticks { now, sleeps: 0 });
+            Arc::new(Self { ticks })
+        }
+
+        pub fn advance(&self, by: Duration) {
+            self.ticks.send_modify(|ticks| ticks.now += by);
+        }
+
+        pub fn sleeps(&self) -> usize {
+            self.ticks.borrow().sleeps
+        }
+
+        /// Resolves once `sleep_until` has been called `count` times in total,
+        /// i.e. the scheduled job finished its work and went back to sleep
+        pub async fn wait_for_sleeps(&self, count: usize) {
+            let mut ticks = self.ticks.subscribe();
+            let _ = ticks.wait_for(|ticks| ticks.sleeps >= count).await;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.ticks.borrow().now
+        }
+
+        fn sleep_until(&self, deadline: DateTime<Utc>) -> SleepFuture<'_> {
+            let mut ticks = self.ticks.subscribe();
+            self.ticks.send_modify(|ticks| ticks.sleeps += 1);
+
+            Box::pin(async move {
+                let _ = ticks.wait_for(|ticks| ticks.now >= deadline).await;
+            })
+        }
+    }
+}
@@ -1,4 +1,72 @@
-#[derive(Debug, Clone)]
+use std::str::FromStr;
+
+use chrono::Duration;
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{AsRefStr, EnumString};
+
+/// Trade normalized across exchanges.
+///
+/// `symbol` is `BASE_QUOTE`, e.g. `BTC_USDT`, `taker_side` is `buy` or `sell`.
+/// Decimals are kept as the exchange formatted them, `create_time` and `ts` are unix milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Trade {
+    pub symbol: String,
+    pub amount: String,
+    pub taker_side: String,
+    pub quantity: String,
+    pub create_time: u64,
+    pub price: String,
+    pub id: String,
+    pub ts: u64,
+}
+
+/// Which reference price of a perpetual contract a `ReferencePrice` carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString, Serialize)]
+#[serde(into = "String")]
+pub enum PriceKind {
+    /// Fair price positions are marked to, used for liquidations and unrealized PnL
+    #[strum(serialize = "mark")]
+    Mark,
+    /// Spot index the contract tracks
+    #[strum(serialize = "index")]
+    Index,
+}
+
+/// Mark or index price of a perpetual contract, `ts` is unix milliseconds
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferencePrice {
+    pub symbol: String,
+    pub kind: PriceKind,
+    pub price: String,
+    pub ts: u64,
+}
+
+/// Funding rate of a perpetual contract for the period settled at `funding_time`.
+///
+/// Until then the rate is a prediction and later updates replace it.
+/// `funding_time` and `ts` are unix milliseconds
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FundingRate {
+    pub symbol: String,
+    pub rate: String,
+    pub funding_time: u64,
+    pub ts: u64,
+}
+
+/// Open contracts of a perpetual at `ts`, unix milliseconds
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenInterest {
+    pub symbol: String,
+    pub open_interest: String,
+    pub ts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Kline {
     pub pair: String,
     pub timeframe: TimeFrame,
@@ -9,9 +77,22 @@ pub struct Kline {
     pub utc_begin: i64,
     pub utc_end: i64,
     pub volume_bs: Vbs,
+    pub source: KlineSource,
 }
 
-#[derive(Debug, Clone)]
+/// Where a kline comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString, Serialize)]
+#[serde(into = "String")]
+pub enum KlineSource {
+    /// Built by the `Aggregator` from received trades
+    #[strum(serialize = "aggregated")]
+    Aggregated,
+    /// Converted from a candle downloaded from the exchange
+    #[strum(serialize = "exchange")]
+    Exchange,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Vbs {
     pub buy_base: f64,
     pub sell_base: f64,
@@ -19,8 +100,48 @@ pub struct Vbs {
     pub sell_quote: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, AsRefStr, EnumString, Deserialize, Serialize,
+)]
+#[serde(try_from = "String", into = "String")]
 pub enum TimeFrame {
+    #[strum(serialize = "15m")]
     Minutes15,
+    #[strum(serialize = "1h")]
     Hour,
 }
+
+impl TimeFrame {
+    pub fn duration(&self) -> Duration {
+        match self {
+            TimeFrame::Minutes15 => Duration::minutes(15),
+            TimeFrame::Hour => Duration::hours(1),
+        }
+    }
+}
+
+impl From<TimeFrame> for String {
+    fn from(timeframe: TimeFrame) -> Self {
+        timeframe.as_ref().to_string()
+    }
+}
+
+impl From<PriceKind> for String {
+    fn from(kind: PriceKind) -> Self {
+        kind.as_ref().to_string()
+    }
+}
+
+impl From<KlineSource> for String {
+    fn from(source: KlineSource) -> Self {
+        source.as_ref().to_string()
+    }
+}
+
+impl TryFrom<String> for TimeFrame {
+    type Error = strum::ParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
@@ -1,19 +1,48 @@
-use chrono::{DateTime, Duration};
+use chrono::{DateTime, Duration, Utc};
 
-use crate::client::models::Trade;
+use super::models::{Kline, KlineSource, TimeFrame, Trade, Vbs};
 
-use super::models::{Kline, TimeFrame, Vbs};
+/// Converts a Poloniex candle row
+/// `[low, high, open, close, amount, quantity, tradeCount, startTime, closeTime]`.
+///
+/// Candles don't split volume by taker side, so the whole volume is reported as bought
+pub fn make_kline_from_candle(symbol: &str, timeframe: TimeFrame, row: &[String]) -> Option<Kline> {
+    if row.len() < 9 {
+        return None;
+    }
+    let float = |i: usize| row[i].parse::<f64>().ok();
+    let utc_begin = DateTime::from_timestamp_millis(row[7].parse::<i64>().ok()?)?;
+    let utc_end = utc_begin + timeframe.duration();
+
+    Some(Kline {
+        pair: symbol.to_string(),
+        timeframe,
+        open: float(2)?,
+        high: float(1)?,
+        low: float(0)?,
+        close: float(3)?,
+        utc_begin: utc_begin.timestamp(),
+        utc_end: utc_end.timestamp(),
+        volume_bs: Vbs {
+            buy_base: float(5)?,
+            sell_base: 0.0,
+            buy_quote: float(4)?,
+            sell_quote: 0.0,
+        },
+        source: KlineSource::Exchange,
+    })
+}
 
-pub fn make_kline_from_trades(trades: Vec<Trade>, timeframe: TimeFrame) -> Option<Kline> {
+/// Builds the kline of the window starting at `utc_begin`, trades outside of it are ignored
+pub fn make_kline_from_trades(
+    trades: Vec<Trade>,
+    timeframe: TimeFrame,
+    utc_begin: DateTime<Utc>,
+) -> Option<Kline> {
     if trades.is_empty() {
         return None;
     }
 
-    // Преобразуем минимальный `ts` в `DateTime<Utc>`
-    let utc_begin = DateTime::from_timestamp(trades.iter().map(|t| t.ts as i64).min()?, 0)
-        .ok_or("Invalid timestamp")
-        .unwrap();
-
     // Вычисляем конец временного интервала
     let utc_end = match timeframe {
         TimeFrame::Minutes15 => utc_begin + Duration::minutes(15),
@@ -25,9 +54,11 @@ pub fn make_kline_from_trades(trades: Vec<Trade>, timeframe: TimeFrame) -> Optio
     let utc_end_timestamp = utc_end.timestamp();
 
     // Фильтруем трейды по временному интервалу
+    let begin_millis = utc_begin.timestamp_millis();
+    let end_millis = utc_end.timestamp_millis();
     let filtered_trades: Vec<_> = trades
         .iter()
-        .filter(|t| t.ts as i64 >= utc_begin_timestamp && (t.ts as i64) < utc_end_timestamp)
+        .filter(|t| t.ts as i64 >= begin_millis && (t.ts as i64) < end_millis)
         .collect();
 
     if filtered_trades.is_empty() {
@@ -84,5 +115,6 @@ pub fn make_kline_from_trades(trades: Vec<Trade>, timeframe: TimeFrame) -> Optio
             buy_quote,
             sell_quote,
         },
+        source: KlineSource::Aggregated,
     })
 }
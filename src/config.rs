@@ -0,0 +1,1003 @@
+use std::{fmt, fs, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    aggregator::{MAX_CATCH_UP_HOURS, SETTLE_DELAY_MS},
+    api,
+    client::{binance, models::PoloniexKLineIntervals, rest, ws},
+    common::models::TimeFrame,
+    database::{sqlite_storage::SQLX_ADDR, writer::STORAGE_QUEUE_SIZE},
+    health,
+};
+
+/// Channels of the Poloniex futures stream
+pub const FUTURES_CHANNELS: [&str; 4] = ["trade", "mark_price", "index_price", "funding_rate"];
+
+/// Settings of the collector binary.
+///
+/// Values are taken from the TOML file passed with `--config`, then overridden
+/// by environment variables and finally by CLI flags
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub exchange: ExchangeKind,
+    pub symbols: Vec<String>,
+    /// Stream channels to subscribe, see `Config::channels` for the default
+    pub channels: Option<Vec<String>>,
+    pub timeframes: Vec<TimeFrame>,
+    pub aggregator: AggregatorConfig,
+    pub backfill: BackfillConfig,
+    pub database: DatabaseConfig,
+    pub buffer: BufferConfig,
+    pub endpoints: EndpointsConfig,
+    pub binance: BinanceConfig,
+    pub futures: FuturesConfig,
+    pub retention: RetentionConfig,
+    pub gaps: GapsConfig,
+    pub reconciliation: ReconciliationConfig,
+    pub recording: RecordingConfig,
+    pub api: ApiConfig,
+    pub grpc: GrpcConfig,
+    pub health: HealthConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    /// How long after a window closes its kline is built, so late trades are included
+    pub settle_delay_ms: u64,
+    /// How far back klines are rebuilt on startup for symbols without any stored kline
+    pub max_catch_up_hours: i64,
+}
+
+/// Range of exchange candles downloaded on startup, both ends included.
+/// Times are written as unix seconds in the config file and flags
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackfillConfig {
+    pub enabled: bool,
+    pub interval: PoloniexKLineIntervals,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub end_time: DateTime<Utc>,
+}
+
+/// Venue trades and candles are collected from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeKind {
+    Poloniex,
+    /// Spot market, only the `trades` channel. Backfill and recording stay Poloniex only
+    Binance,
+    /// Poloniex perpetuals, e.g. `BTC_USDT_PERP`, with funding and open interest history
+    #[serde(rename = "poloniex_futures")]
+    PoloniexFutures,
+}
+
+impl ExchangeKind {
+    /// Channel carrying trades, subscribed when no channels are configured
+    pub fn trades_channel(&self) -> &'static str {
+        match self {
+            ExchangeKind::Poloniex | ExchangeKind::Binance => "trades",
+            ExchangeKind::PoloniexFutures => "trade",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Sqlite,
+    Postgres,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    /// SQLite file, `:memory:` keeps everything in memory
+    pub path: String,
+    /// PostgreSQL connection string
+    pub url: Option<String>,
+    pub timescale: bool,
+    pub queue_size: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    pub max_trades: usize,
+    pub max_latency_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointsConfig {
+    pub rest: String,
+    pub ws: String,
+    /// HTTP proxy used for both REST and WebSocket connections
+    pub proxy: Option<String>,
+    pub timeout_ms: u64,
+    /// Disables TLS certificate validation, only for local test servers
+    pub accept_invalid_certs: bool,
+}
+
+/// Binance endpoints, proxy, timeouts and certificate settings are shared with `endpoints`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinanceConfig {
+    pub rest: String,
+    pub ws: String,
+}
+
+/// Poloniex futures endpoints, proxy, timeouts and certificate settings are shared with `endpoints`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FuturesConfig {
+    pub rest: String,
+    pub ws: String,
+    /// Open interest isn't streamed, so it is sampled over REST this often
+    pub open_interest_every_secs: i64,
+    /// Settled funding periods downloaded on startup, 0 disables the download
+    pub funding_history_days: i64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub keep_trades_days: i64,
+    pub run_every_secs: i64,
+}
+
+/// Periodic search for missing klines, which are then filled from exchange candles
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GapsConfig {
+    pub enabled: bool,
+    pub lookback_hours: i64,
+    pub run_every_secs: i64,
+}
+
+/// Comparison of aggregated klines with exchange candles, see `reconciliation`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconciliationConfig {
+    /// Largest accepted relative OHLC difference
+    pub price_tolerance: f64,
+    /// Largest accepted relative volume difference
+    pub volume_tolerance: f64,
+    /// Unix seconds, when both bounds are set the collector reconciles the range and exits
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+/// Raw WebSocket sessions, see `client::recording`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// File every received frame is appended to
+    pub record_to: Option<String>,
+    /// Replays this file instead of connecting to Poloniex, then exits
+    pub replay_from: Option<String>,
+    /// 1 keeps the original pace, `inf` replays without delays
+    pub replay_speed: f64,
+}
+
+/// HTTP query API over stored data, see `api`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub listen: String,
+    /// Largest accepted `limit` parameter
+    pub max_limit: usize,
+    /// Re-publishes live trades and klines to WebSocket clients at `/ws`, see `fanout`
+    pub fanout: bool,
+}
+
+/// gRPC service, see `grpc` and `proto/collector.proto`.
+/// Range queries share `api.max_limit`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub enabled: bool,
+    pub listen: String,
+}
+
+/// `/healthz` and `/readyz` on the API listener, see `health`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// A configured symbol without a trade for this long makes the collector not ready
+    pub stale_after_secs: i64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with span fields
+    Json,
+}
+
+/// Log output and span export, see `telemetry`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `tracing` filter directive, e.g. `info` or `info,bitsgap_tech_task::client=debug`
+    pub level: String,
+    pub format: LogFormat,
+    /// OTLP/gRPC collector spans are exported to, e.g. http://127.0.0.1:4317.
+    /// Needs the `otlp` feature
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            exchange: ExchangeKind::Poloniex,
+            // DOGE_USDC and BCH_USDC requests result in error. They are not available in Poloniex
+            symbols: ["BTC_USDT", "TRX_USDT", "ETH_USDT"]
+                .iter()
+                .map(|sym| sym.to_string())
+                .collect(),
+            channels: None,
+            timeframes: vec![TimeFrame::Minutes15, TimeFrame::Hour],
+            aggregator: AggregatorConfig::default(),
+            backfill: BackfillConfig::default(),
+            database: DatabaseConfig::default(),
+            buffer: BufferConfig::default(),
+            endpoints: EndpointsConfig::default(),
+            binance: BinanceConfig::default(),
+            futures: FuturesConfig::default(),
+            retention: RetentionConfig::default(),
+            gaps: GapsConfig::default(),
+            reconciliation: ReconciliationConfig::default(),
+            recording: RecordingConfig::default(),
+            api: ApiConfig::default(),
+            grpc: GrpcConfig::default(),
+            health: HealthConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            settle_delay_ms: SETTLE_DELAY_MS as u64,
+            max_catch_up_hours: MAX_CATCH_UP_HOURS,
+        }
+    }
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: PoloniexKLineIntervals::Week1,
+            start_time: DateTime::from_timestamp(1733011200, 0).expect("valid timestamp"),
+            end_time: DateTime::from_timestamp(1735689599, 0).expect("valid timestamp"),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            backend: DatabaseBackend::Sqlite,
+            path: SQLX_ADDR.to_string(),
+            url: None,
+            timescale: false,
+            queue_size: STORAGE_QUEUE_SIZE,
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            max_trades: 100,
+            max_latency_ms: 1000,
+        }
+    }
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            rest: rest::POLONIEX_ENDPOINT.to_string(),
+            ws: ws::POLONIEX_ENDPOINT.to_string(),
+            proxy: None,
+            timeout_ms: 10_000,
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+impl Default for BinanceConfig {
+    fn default() -> Self {
+        Self {
+            rest: binance::rest::BINANCE_ENDPOINT.to_string(),
+            ws: binance::ws::BINANCE_ENDPOINT.to_string(),
+        }
+    }
+}
+
+impl Default for FuturesConfig {
+    fn default() -> Self {
+        Self {
+            rest: rest::POLONIEX_ENDPOINT.to_string(),
+            ws: ws::POLONIEX_FUTURES_ENDPOINT.to_string(),
+            open_interest_every_secs: 60,
+            funding_history_days: 7,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep_trades_days: 7,
+            run_every_secs: 3600,
+        }
+    }
+}
+
+impl Default for GapsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            lookback_hours: 24 * 7,
+            run_every_secs: 3600,
+        }
+    }
+}
+
+impl Default for ReconciliationConfig {
+    fn default() -> Self {
+        Self {
+            price_tolerance: 0.0001,
+            volume_tolerance: 0.001,
+            from: None,
+            to: None,
+        }
+    }
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            record_to: None,
+            replay_from: None,
+            replay_speed: 1.0,
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "127.0.0.1:8080".to_string(),
+            max_limit: api::MAX_LIMIT,
+            fanout: true,
+        }
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: "127.0.0.1:50051".to_string(),
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            stale_after_secs: health::STALE_AFTER_SECS,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Command line flags, each of them can also be set with the listed environment variable
+#[derive(Parser, Debug, Default)]
+#[command(about = "Collects exchange trades and builds klines from them")]
+pub struct Cli {
+    /// Path to the TOML config file
+    #[arg(long, env = "COLLECTOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// poloniex, binance or poloniex_futures
+    #[arg(long, env = "COLLECTOR_EXCHANGE")]
+    pub exchange: Option<String>,
+
+    /// Comma separated list of symbols, e.g. BTC_USDT,ETH_USDT
+    #[arg(long, env = "COLLECTOR_SYMBOLS", value_delimiter = ',')]
+    pub symbols: Option<Vec<String>>,
+
+    /// Comma separated list of stream channels, defaults to the exchange's trades channel
+    #[arg(long, env = "COLLECTOR_CHANNELS", value_delimiter = ',')]
+    pub channels: Option<Vec<String>>,
+
+    /// Comma separated list of timeframes, e.g. 15m,1h
+    #[arg(long, env = "COLLECTOR_TIMEFRAMES", value_delimiter = ',')]
+    pub timeframes: Option<Vec<String>>,
+
+    /// Delay after a window closes before its kline is built
+    #[arg(long, env = "COLLECTOR_SETTLE_DELAY_MS")]
+    pub settle_delay_ms: Option<u64>,
+
+    /// Interval of backfilled candles, e.g. WEEK_1
+    #[arg(long, env = "COLLECTOR_BACKFILL_INTERVAL")]
+    pub backfill_interval: Option<String>,
+
+    /// Start of the backfilled range, unix seconds
+    #[arg(long, env = "COLLECTOR_BACKFILL_START")]
+    pub backfill_start: Option<i64>,
+
+    /// End of the backfilled range, unix seconds
+    #[arg(long, env = "COLLECTOR_BACKFILL_END")]
+    pub backfill_end: Option<i64>,
+
+    /// Skip downloading candles on startup
+    #[arg(long, env = "COLLECTOR_NO_BACKFILL")]
+    pub no_backfill: bool,
+
+    /// SQLite database file
+    #[arg(long, env = "COLLECTOR_DB_PATH")]
+    pub db_path: Option<String>,
+
+    /// PostgreSQL connection string, switches the backend to PostgreSQL
+    #[arg(long, env = "COLLECTOR_POSTGRES_URL")]
+    pub postgres_url: Option<String>,
+
+    #[arg(long, env = "COLLECTOR_BUFFER_SIZE")]
+    pub buffer_size: Option<usize>,
+
+    #[arg(long, env = "COLLECTOR_BUFFER_LATENCY_MS")]
+    pub buffer_latency_ms: Option<u64>,
+
+    /// REST endpoint of the selected exchange
+    #[arg(long, env = "COLLECTOR_REST_ENDPOINT")]
+    pub rest_endpoint: Option<String>,
+
+    /// WebSocket endpoint of the selected exchange
+    #[arg(long, env = "COLLECTOR_WS_ENDPOINT")]
+    pub ws_endpoint: Option<String>,
+
+    /// HTTP proxy for REST and WebSocket connections
+    #[arg(long, env = "COLLECTOR_PROXY")]
+    pub proxy: Option<String>,
+
+    /// Start of the range to reconcile with exchange candles, unix seconds
+    #[arg(long, env = "COLLECTOR_RECONCILE_FROM", requires = "reconcile_to")]
+    pub reconcile_from: Option<i64>,
+
+    /// End of the range to reconcile, the collector exits after reporting
+    #[arg(long, env = "COLLECTOR_RECONCILE_TO", requires = "reconcile_from")]
+    pub reconcile_to: Option<i64>,
+
+    /// Append every received WebSocket frame to this file
+    #[arg(long, env = "COLLECTOR_RECORD_TO")]
+    pub record_to: Option<String>,
+
+    /// Replay a recorded session instead of connecting to Poloniex
+    #[arg(long, env = "COLLECTOR_REPLAY_FROM")]
+    pub replay_from: Option<String>,
+
+    /// Replay pace relative to the recording, e.g. 10 or inf
+    #[arg(long, env = "COLLECTOR_REPLAY_SPEED")]
+    pub replay_speed: Option<f64>,
+
+    /// Address of the HTTP query API, e.g. 0.0.0.0:8080
+    #[arg(long, env = "COLLECTOR_API_LISTEN")]
+    pub api_listen: Option<String>,
+
+    /// Don't serve the HTTP query API, `/metrics` and health stay up
+    #[arg(long, env = "COLLECTOR_NO_API")]
+    pub no_api: bool,
+
+    /// Address of the gRPC service, e.g. 0.0.0.0:50051
+    #[arg(long, env = "COLLECTOR_GRPC_LISTEN")]
+    pub grpc_listen: Option<String>,
+
+    /// Don't start the gRPC service
+    #[arg(long, env = "COLLECTOR_NO_GRPC")]
+    pub no_grpc: bool,
+
+    /// Log filter, e.g. debug or info,bitsgap_tech_task::aggregator=trace
+    #[arg(long, env = "COLLECTOR_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// text or json
+    #[arg(long, env = "COLLECTOR_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    /// Export spans to this OTLP/gRPC collector, e.g. http://127.0.0.1:4317
+    #[arg(long, env = "COLLECTOR_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    /// Every problem found by `Config::validate`
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "can't read {}: {}", path.display(), err),
+            ConfigError::Parse(err) => write!(f, "can't parse config: {}", err),
+            ConfigError::Invalid(errors) => write!(f, "invalid config: {}", errors.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the config from the process arguments and environment
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let content =
+                    fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+                Self::from_toml(&content)?
+            }
+            None => Self::default(),
+        };
+
+        let mut errors = config.apply(cli);
+        errors.extend(config.validate());
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(ConfigError::Parse)
+    }
+
+    /// Applies CLI and environment overrides, returns values which can't be parsed
+    fn apply(&mut self, cli: Cli) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(exchange) = cli.exchange {
+            match exchange.as_str() {
+                "poloniex" => self.exchange = ExchangeKind::Poloniex,
+                "binance" => self.exchange = ExchangeKind::Binance,
+                "poloniex_futures" => self.exchange = ExchangeKind::PoloniexFutures,
+                _ => errors.push(format!("unknown exchange {}", exchange)),
+            }
+        }
+        if let Some(symbols) = cli.symbols {
+            self.symbols = symbols;
+        }
+        if let Some(channels) = cli.channels {
+            self.channels = Some(channels);
+        }
+        if let Some(timeframes) = cli.timeframes {
+            self.timeframes = timeframes
+                .into_iter()
+                .filter_map(|tf| match TimeFrame::try_from(tf.clone()) {
+                    Ok(tf) => Some(tf),
+                    Err(_) => {
+                        errors.push(format!("unknown timeframe {}", tf));
+                        None
+                    }
+                })
+                .collect();
+        }
+        if let Some(settle_delay_ms) = cli.settle_delay_ms {
+            self.aggregator.settle_delay_ms = settle_delay_ms;
+        }
+        if let Some(interval) = cli.backfill_interval {
+            match PoloniexKLineIntervals::try_from(interval.clone()) {
+                Ok(interval) => self.backfill.interval = interval,
+                Err(_) => errors.push(format!("unknown backfill interval {}", interval)),
+            }
+        }
+        if let Some(start_time) = cli.backfill_start {
+            match DateTime::from_timestamp(start_time, 0) {
+                Some(start_time) => self.backfill.start_time = start_time,
+                None => errors.push(format!("backfill start {} is out of range", start_time)),
+            }
+        }
+        if let Some(end_time) = cli.backfill_end {
+            match DateTime::from_timestamp(end_time, 0) {
+                Some(end_time) => self.backfill.end_time = end_time,
+                None => errors.push(format!("backfill end {} is out of range", end_time)),
+            }
+        }
+        if cli.no_backfill {
+            self.backfill.enabled = false;
+        }
+        if let Some(path) = cli.db_path {
+            self.database.path = path;
+        }
+        if let Some(url) = cli.postgres_url {
+            self.database.backend = DatabaseBackend::Postgres;
+            self.database.url = Some(url);
+        }
+        if let Some(max_trades) = cli.buffer_size {
+            self.buffer.max_trades = max_trades;
+        }
+        if let Some(max_latency_ms) = cli.buffer_latency_ms {
+            self.buffer.max_latency_ms = max_latency_ms;
+        }
+        // `exchange` is already applied, so the flags reach the endpoints that are used
+        let (rest, ws) = match self.exchange {
+            ExchangeKind::Poloniex => (&mut self.endpoints.rest, &mut self.endpoints.ws),
+            ExchangeKind::Binance => (&mut self.binance.rest, &mut self.binance.ws),
+            ExchangeKind::PoloniexFutures => (&mut self.futures.rest, &mut self.futures.ws),
+        };
+        if let Some(endpoint) = cli.rest_endpoint {
+            *rest = endpoint;
+        }
+        if let Some(endpoint) = cli.ws_endpoint {
+            *ws = endpoint;
+        }
+        if let Some(proxy) = cli.proxy {
+            self.endpoints.proxy = Some(proxy);
+        }
+        if let Some(from) = cli.reconcile_from {
+            self.reconciliation.from = Some(from);
+        }
+        if let Some(to) = cli.reconcile_to {
+            self.reconciliation.to = Some(to);
+        }
+        if let Some(path) = cli.record_to {
+            self.recording.record_to = Some(path);
+        }
+        if let Some(path) = cli.replay_from {
+            self.recording.replay_from = Some(path);
+        }
+        if let Some(speed) = cli.replay_speed {
+            self.recording.replay_speed = speed;
+        }
+        if let Some(listen) = cli.api_listen {
+            self.api.listen = listen;
+        }
+        if cli.no_api {
+            self.api.enabled = false;
+        }
+        if let Some(listen) = cli.grpc_listen {
+            self.grpc.listen = listen;
+        }
+        if cli.no_grpc {
+            self.grpc.enabled = false;
+        }
+        if let Some(level) = cli.log_level {
+            self.logging.level = level;
+        }
+        if let Some(format) = cli.log_format {
+            match format.as_str() {
+                "text" => self.logging.format = LogFormat::Text,
+                "json" => self.logging.format = LogFormat::Json,
+                _ => errors.push(format!("unknown log format {}", format)),
+            }
+        }
+        if let Some(endpoint) = cli.otlp_endpoint {
+            self.logging.otlp_endpoint = Some(endpoint);
+        }
+
+        errors
+    }
+
+    /// Configured channels, or the trades channel of the selected exchange
+    pub fn channels(&self) -> Vec<String> {
+        match &self.channels {
+            Some(channels) => channels.clone(),
+            None => vec![self.exchange.trades_channel().to_string()],
+        }
+    }
+
+    /// Returns every problem found, an empty list means the config is usable
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.symbols.is_empty() {
+            errors.push("at least one symbol is required".to_string());
+        }
+        if self.symbols.iter().any(|s| s.trim().is_empty()) {
+            errors.push("symbols can't be empty".to_string());
+        }
+        let channels = self.channels();
+        if channels.is_empty() {
+            errors.push("at least one channel is required".to_string());
+        }
+        if self.timeframes.is_empty() {
+            errors.push("at least one timeframe is required".to_string());
+        }
+        if self.aggregator.max_catch_up_hours < 0 {
+            errors.push("aggregator max_catch_up_hours can't be negative".to_string());
+        }
+        if self.backfill.enabled && self.backfill.start_time >= self.backfill.end_time {
+            errors.push("backfill start_time must be before end_time".to_string());
+        }
+        if self.database.queue_size == 0 {
+            errors.push("database queue_size must be positive".to_string());
+        }
+        if self.database.backend == DatabaseBackend::Postgres {
+            if self.database.url.is_none() {
+                errors.push("postgres backend requires database url".to_string());
+            }
+            if !cfg!(feature = "postgres") {
+                errors.push("postgres backend requires the postgres feature".to_string());
+            }
+        }
+        if self.buffer.max_trades == 0 {
+            errors.push("buffer max_trades must be positive".to_string());
+        }
+        if self.buffer.max_latency_ms == 0 {
+            errors.push("buffer max_latency_ms must be positive".to_string());
+        }
+        if self.retention.keep_trades_days <= 0 {
+            errors.push("retention keep_trades_days must be positive".to_string());
+        }
+        if self.retention.run_every_secs <= 0 {
+            errors.push("retention run_every_secs must be positive".to_string());
+        }
+
+        if self.gaps.lookback_hours <= 0 {
+            errors.push("gaps lookback_hours must be positive".to_string());
+        }
+        if self.gaps.run_every_secs <= 0 {
+            errors.push("gaps run_every_secs must be positive".to_string());
+        }
+        let reconciliation = &self.reconciliation;
+        if reconciliation.price_tolerance.is_nan() || reconciliation.price_tolerance < 0.0 {
+            errors.push("reconciliation price_tolerance can't be negative".to_string());
+        }
+        if reconciliation.volume_tolerance.is_nan() || reconciliation.volume_tolerance < 0.0 {
+            errors.push("reconciliation volume_tolerance can't be negative".to_string());
+        }
+        match (reconciliation.from, reconciliation.to) {
+            (Some(from), Some(to)) if from >= to => {
+                errors.push("reconciliation from must be before to".to_string())
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                errors.push("reconciliation needs both from and to".to_string())
+            }
+            _ => {}
+        }
+        if self.api.listen.parse::<std::net::SocketAddr>().is_err() {
+            errors.push(format!("invalid api listen address {}", self.api.listen));
+        }
+        if self.grpc.enabled && self.grpc.listen.parse::<std::net::SocketAddr>().is_err() {
+            errors.push(format!("invalid grpc listen address {}", self.grpc.listen));
+        }
+        if self.api.max_limit == 0 {
+            errors.push("api max_limit must be positive".to_string());
+        }
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            errors.push(format!("invalid log level {}: {}", self.logging.level, err));
+        }
+        if let Some(endpoint) = &self.logging.otlp_endpoint {
+            if !cfg!(feature = "otlp") {
+                errors.push("otlp export requires the otlp feature".to_string());
+            } else if let Err(err) = Url::parse(endpoint) {
+                errors.push(format!("otlp endpoint is invalid: {}", err));
+            }
+        }
+        if self.health.stale_after_secs <= 0 {
+            errors.push("health stale_after_secs must be positive".to_string());
+        }
+        if self.recording.replay_speed.is_nan() || self.recording.replay_speed <= 0.0 {
+            errors.push("recording replay_speed must be positive".to_string());
+        }
+
+        if self.endpoints.timeout_ms == 0 {
+            errors.push("endpoints timeout_ms must be positive".to_string());
+        }
+        if let Some(proxy) = &self.endpoints.proxy {
+            if let Err(err) = Url::parse(proxy) {
+                errors.push(format!("proxy is invalid: {}", err));
+            }
+        }
+
+        let (rest, ws) = match self.exchange {
+            ExchangeKind::Poloniex => (&self.endpoints.rest, &self.endpoints.ws),
+            ExchangeKind::Binance => (&self.binance.rest, &self.binance.ws),
+            ExchangeKind::PoloniexFutures => (&self.futures.rest, &self.futures.ws),
+        };
+        if self.exchange == ExchangeKind::Binance {
+            if channels.iter().any(|channel| channel != "trades") {
+                errors.push("binance only supports the trades channel".to_string());
+            }
+            if self.recording.record_to.is_some() || self.recording.replay_from.is_some() {
+                errors.push("recording requires the poloniex exchange".to_string());
+            }
+        }
+        if self.exchange == ExchangeKind::PoloniexFutures {
+            if let Some(channel) = channels
+                .iter()
+                .find(|channel| !FUTURES_CHANNELS.contains(&channel.as_str()))
+            {
+                errors.push(format!("unknown futures channel {}", channel));
+            }
+            if self.futures.open_interest_every_secs <= 0 {
+                errors.push("futures open_interest_every_secs must be positive".to_string());
+            }
+            if self.futures.funding_history_days < 0 {
+                errors.push("futures funding_history_days can't be negative".to_string());
+            }
+        }
+        for (name, endpoint, schemes) in
+            [("rest", rest, ["http", "https"]), ("ws", ws, ["ws", "wss"])]
+        {
+            match Url::parse(endpoint) {
+                Ok(url) if schemes.contains(&url.scheme()) => {}
+                Ok(url) => errors.push(format!(
+                    "{} endpoint has unsupported scheme {}",
+                    name,
+                    url.scheme()
+                )),
+                Err(err) => errors.push(format!("{} endpoint is invalid: {}", name, err)),
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = Config::from_toml(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(
+            config.timeframes,
+            vec![TimeFrame::Minutes15, TimeFrame::Hour]
+        );
+        assert_eq!(config.backfill.interval, PoloniexKLineIntervals::Week1);
+        assert_eq!(config.backfill.start_time.timestamp(), 1733011200);
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn cli_overrides_and_errors_are_reported() {
+        let cli = Cli {
+            symbols: Some(vec!["BTC_USDT".to_string()]),
+            timeframes: Some(vec!["1h".to_string(), "2h".to_string()]),
+            buffer_size: Some(0),
+            ws_endpoint: Some("http://localhost".to_string()),
+            ..Cli::default()
+        };
+
+        let Err(ConfigError::Invalid(errors)) = Config::from_cli(cli) else {
+            panic!("config must be invalid");
+        };
+        assert_eq!(
+            errors,
+            vec![
+                "unknown timeframe 2h",
+                "buffer max_trades must be positive",
+                "ws endpoint has unsupported scheme http",
+            ]
+        );
+    }
+
+    #[test]
+    fn log_settings_are_parsed_and_validated() {
+        let cli = Cli {
+            log_level: Some("debug,tonic=warn".to_string()),
+            log_format: Some("json".to_string()),
+            ..Cli::default()
+        };
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.logging.format, LogFormat::Json);
+
+        let cli = Cli {
+            log_level: Some("collector=loud".to_string()),
+            log_format: Some("xml".to_string()),
+            ..Cli::default()
+        };
+        let Err(ConfigError::Invalid(errors)) = Config::from_cli(cli) else {
+            panic!("config must be invalid");
+        };
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "unknown log format xml");
+        assert!(errors[1].starts_with("invalid log level collector=loud"));
+    }
+
+    #[test]
+    fn endpoint_flags_apply_to_the_selected_exchange() {
+        let cli = Cli {
+            exchange: Some("binance".to_string()),
+            proxy: Some("http://127.0.0.1:3128".to_string()),
+            ws_endpoint: Some("ws://127.0.0.1:9443/ws".to_string()),
+            ..Cli::default()
+        };
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.exchange, ExchangeKind::Binance);
+        assert_eq!(config.binance.ws, "ws://127.0.0.1:9443/ws");
+        assert_eq!(config.binance.rest, "https://api.binance.com/api/v3/");
+        assert_eq!(config.endpoints.ws, "wss://ws.poloniex.com/ws/public");
+
+        let cli = Cli {
+            exchange: Some("poloniex_futures".to_string()),
+            rest_endpoint: Some("http://127.0.0.1:8080/v3/market/".to_string()),
+            ..Cli::default()
+        };
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.futures.rest, "http://127.0.0.1:8080/v3/market/");
+
+        let cli = Cli {
+            exchange: Some("binance".to_string()),
+            ws_endpoint: Some("http://localhost".to_string()),
+            ..Cli::default()
+        };
+        let Err(ConfigError::Invalid(errors)) = Config::from_cli(cli) else {
+            panic!("config must be invalid");
+        };
+        assert_eq!(errors, vec!["ws endpoint has unsupported scheme http"]);
+    }
+
+    #[test]
+    fn futures_channels_are_checked() {
+        let config = Config::from_toml(
+            r#"
+            exchange = "poloniex_futures"
+            symbols = ["BTC_USDT_PERP"]
+            channels = ["trade", "funding_rate"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.exchange, ExchangeKind::PoloniexFutures);
+        assert_eq!(config.futures.ws, "wss://ws.poloniex.com/ws/v3/public");
+        assert!(config.validate().is_empty());
+
+        // without configured channels the futures trade channel is subscribed
+        let cli = Cli {
+            exchange: Some("poloniex_futures".to_string()),
+            ..Cli::default()
+        };
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.channels(), vec!["trade"]);
+
+        let cli = Cli {
+            exchange: Some("poloniex_futures".to_string()),
+            channels: Some(vec!["trade".to_string(), "trades".to_string()]),
+            ..Cli::default()
+        };
+        let Err(ConfigError::Invalid(errors)) = Config::from_cli(cli) else {
+            panic!("config must be invalid");
+        };
+        // the spot channel name is `trades`
+        assert_eq!(errors, vec!["unknown futures channel trades"]);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, Utc};
+
+use crate::client::models::RawKLHistory;
+use crate::common::models::{FundingRate, Kline, OpenInterest, TimeFrame, Trade};
+
+use super::{InsertedTrades, Storage, StorageError};
+
+type KlineKey = (String, TimeFrame, i64);
+
+/// Keeps everything in process memory. Intended for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    trades: Vec<Trade>,
+    trade_keys: HashSet<(String, String)>,
+    klines: BTreeMap<KlineKey, Kline>,
+    candles: BTreeMap<String, RawKLHistory>,
+    /// Keyed by symbol and `funding_time`
+    funding_rates: BTreeMap<(String, u64), FundingRate>,
+    /// Keyed by symbol and `ts`
+    open_interest: BTreeMap<(String, u64), OpenInterest>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn candles(&self, symbol: &str) -> Option<&RawKLHistory> {
+        self.candles.get(symbol)
+    }
+
+    fn key(kline: &Kline) -> KlineKey {
+        (kline.pair.clone(), kline.timeframe.clone(), kline.utc_begin)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn insert_recent_trades(&mut self, trades: &[Trade]) -> Result<InsertedTrades, StorageError> {
+        let mut result = InsertedTrades::default();
+
+        for trade in trades {
+            if self
+                .trade_keys
+                .insert((trade.symbol.clone(), trade.id.clone()))
+            {
+                self.trades.push(trade.clone());
+                result.inserted += 1;
+            } else {
+                result.duplicates += 1;
+            }
+        }
+
+        self.trades.sort_by_key(|t| t.ts);
+        Ok(result)
+    }
+
+    fn insert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        let key = Self::key(kline);
+        if self.klines.contains_key(&key) {
+            return Err(StorageError::Conflict(format!(
+                "kline {} {} {}",
+                kline.pair,
+                kline.timeframe.as_ref(),
+                kline.utc_begin
+            )));
+        }
+
+        self.klines.insert(key, kline.clone());
+        Ok(())
+    }
+
+    fn upsert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        self.klines.insert(Self::key(kline), kline.clone());
+        Ok(())
+    }
+
+    fn insert_candles(&mut self, symbol: &str, data: &RawKLHistory) -> Result<(), StorageError> {
+        self.candles
+            .entry(symbol.to_string())
+            .or_default()
+            .extend(data.iter().cloned());
+        Ok(())
+    }
+
+    fn retrieve_trades_in_interval(
+        &self,
+        symbol: Option<&str>,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Trade>, StorageError> {
+        let start = start_time.timestamp_millis();
+        let end = end_time.timestamp_millis();
+
+        Ok(self
+            .trades
+            .iter()
+            .filter(|t| symbol.is_none_or(|s| t.symbol == s))
+            .filter(|t| t.ts as i64 >= start && (t.ts as i64) < end)
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn retrieve_klines_in_interval(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let from = (
+            symbol.to_string(),
+            timeframe.clone(),
+            start_time.timestamp(),
+        );
+        let to = (symbol.to_string(), timeframe.clone(), end_time.timestamp());
+
+        Ok(self
+            .klines
+            .range(from..to)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(_, k)| k.clone())
+            .collect())
+    }
+
+    fn latest_kline(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+    ) -> Result<Option<Kline>, StorageError> {
+        let from = (symbol.to_string(), timeframe.clone(), i64::MIN);
+        let to = (symbol.to_string(), timeframe.clone(), i64::MAX);
+
+        Ok(self
+            .klines
+            .range(from..=to)
+            .next_back()
+            .map(|(_, k)| k.clone()))
+    }
+
+    fn upsert_funding_rates(&mut self, rates: &[FundingRate]) -> Result<(), StorageError> {
+        for rate in rates {
+            self.funding_rates
+                .insert((rate.symbol.clone(), rate.funding_time), rate.clone());
+        }
+        Ok(())
+    }
+
+    fn retrieve_funding_rates(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, StorageError> {
+        let from = (
+            symbol.to_string(),
+            start_time.timestamp_millis().max(0) as u64,
+        );
+        let to = (
+            symbol.to_string(),
+            end_time.timestamp_millis().max(0) as u64,
+        );
+
+        Ok(self
+            .funding_rates
+            .range(from..to)
+            .map(|(_, rate)| rate.clone())
+            .collect())
+    }
+
+    fn insert_open_interest(&mut self, samples: &[OpenInterest]) -> Result<(), StorageError> {
+        for sample in samples {
+            self.open_interest
+                .entry((sample.symbol.clone(), sample.ts))
+                .or_insert_with(|| sample.clone());
+        }
+        Ok(())
+    }
+
+    fn retrieve_open_interest(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<OpenInterest>, StorageError> {
+        let from = (
+            symbol.to_string(),
+            start_time.timestamp_millis().max(0) as u64,
+        );
+        let to = (
+            symbol.to_string(),
+            end_time.timestamp_millis().max(0) as u64,
+        );
+
+        Ok(self
+            .open_interest
+            .range(from..to)
+            .map(|(_, sample)| sample.clone())
+            .collect())
+    }
+
+    fn trade_symbols(&self) -> Result<Vec<String>, StorageError> {
+        let symbols: HashSet<&String> = self.trades.iter().map(|t| &t.symbol).collect();
+        Ok(symbols.into_iter().cloned().collect())
+    }
+
+    fn delete_trades_before(
+        &mut self,
+        symbol: &str,
+        before: &DateTime<Utc>,
+    ) -> Result<usize, StorageError> {
+        let before = before.timestamp_millis();
+        let count = self.trades.len();
+
+        self.trades
+            .retain(|t| t.symbol != symbol || t.ts as i64 >= before);
+        self.trade_keys = self
+            .trades
+            .iter()
+            .map(|t| (t.symbol.clone(), t.id.clone()))
+            .collect();
+
+        Ok(count - self.trades.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::trade;
+
+    #[test]
+    fn trades_are_filtered_by_symbol_and_range() {
+        let mut db = MemoryStorage::new();
+        db.insert_recent_trades(&[
+            trade("1", "BTC_USDT", "buy", "10", 1_000),
+            trade("2", "ETH_USDT", "buy", "10", 1_500),
+            trade("3", "BTC_USDT", "buy", "10", 2_000),
+        ])
+        .unwrap();
+
+        let start = DateTime::from_timestamp_millis(1_000).unwrap();
+        let end = DateTime::from_timestamp_millis(2_000).unwrap();
+
+        let btc = db
+            .retrieve_trades_in_interval(Some("BTC_USDT"), &start, &end, None)
+            .unwrap();
+        assert_eq!(btc, vec![trade("1", "BTC_USDT", "buy", "10", 1_000)]);
+
+        let all = db
+            .retrieve_trades_in_interval(None, &start, &end, None)
+            .unwrap();
+        assert_eq!(all.len(), 2);
+    }
+
+    #[test]
+    fn replayed_trades_are_skipped() {
+        let mut db = MemoryStorage::new();
+        db.insert_recent_trades(&[trade("1", "BTC_USDT", "buy", "10", 1_000)])
+            .unwrap();
+
+        let result = db
+            .insert_recent_trades(&[
+                trade("1", "BTC_USDT", "buy", "10", 1_000),
+                trade("1", "ETH_USDT", "buy", "10", 1_000),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            result,
+            InsertedTrades {
+                inserted: 1,
+                duplicates: 1
+            }
+        );
+    }
+}
@@ -1,154 +1,153 @@
+pub mod memory_storage;
+#[cfg(feature = "postgres")]
+pub mod postgres_storage;
 pub mod queries;
+pub mod sqlite_storage;
+pub mod writer;
+
+use std::fmt;
 
 use chrono::{DateTime, Utc};
 
-use crate::database::queries::*;
-use crate::{client::models::Trade, common::models::Kline};
+pub use memory_storage::MemoryStorage;
+#[cfg(feature = "postgres")]
+pub use postgres_storage::PostgresStorage;
+pub use sqlite_storage::SqliteStorage;
+pub use writer::StorageHandle;
+
+use crate::{
+    client::models::RawKLHistory,
+    common::models::{FundingRate, Kline, OpenInterest, TimeFrame, Trade},
+};
+
+/// Persistence layer for trades, klines and perpetual funding and open interest history.
+///
+/// `SqliteStorage` is what the collector runs with, `MemoryStorage` is meant for tests
+/// and `PostgresStorage` is available with the `postgres` feature.
+/// Other stores can be plugged in by implementing this trait and spawning them
+/// behind a `StorageHandle`.
+pub trait Storage: Send {
+    /// Idempotent: trades already stored under the same symbol and exchange id are skipped
+    fn insert_recent_trades(&mut self, trades: &[Trade]) -> Result<InsertedTrades, StorageError>;
+
+    /// Fails if a kline for the same symbol, timeframe and `utc_begin` already exists
+    fn insert_kline(&mut self, kline: &Kline) -> Result<(), StorageError>;
+
+    /// Inserts the kline or replaces the one with the same symbol, timeframe and `utc_begin`
+    fn upsert_kline(&mut self, kline: &Kline) -> Result<(), StorageError>;
+
+    /// Stores raw candles downloaded from the exchange
+    fn insert_candles(&mut self, symbol: &str, data: &RawKLHistory) -> Result<(), StorageError>;
+
+    /// Trades with `ts` in `[start_time, end_time)`, ordered by `ts`, the first `limit` of them.
+    /// `None` as symbol returns trades of every symbol
+    fn retrieve_trades_in_interval(
+        &self,
+        symbol: Option<&str>,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Trade>, StorageError>;
 
-const SQLX_ADDR: &str = ":memory:";
+    /// Klines with `utc_begin` in `[start_time, end_time)`, ordered by `utc_begin`,
+    /// the first `limit` of them
+    fn retrieve_klines_in_interval(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Kline>, StorageError>;
 
-pub struct Database {
-    connection: sqlite::Connection,
-}
+    fn latest_kline(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+    ) -> Result<Option<Kline>, StorageError>;
 
-impl Database {
-    pub fn new() -> Self {
-        let connection = sqlite::open(SQLX_ADDR).unwrap();
-        connection.execute(CREATE_CANDLES_TABLE_SQL).unwrap();
-        connection.execute(CREATE_TRADES_TABLE_SQL).unwrap();
+    /// Inserts funding periods or replaces those with the same symbol and `funding_time`,
+    /// so a predicted rate is overwritten by later updates of its period
+    fn upsert_funding_rates(&mut self, rates: &[FundingRate]) -> Result<(), StorageError>;
 
-        let database = Self { connection };
-        tracing::info!("Database created");
+    /// Funding periods with `funding_time` in `[start_time, end_time)`, ordered by `funding_time`
+    fn retrieve_funding_rates(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, StorageError>;
 
-        database
-    }
+    /// Idempotent: samples already stored under the same symbol and `ts` are skipped
+    fn insert_open_interest(&mut self, samples: &[OpenInterest]) -> Result<(), StorageError>;
 
-    pub fn insert_recent_trades(&self, trades: &[Trade]) {
-        let mut statement = self.connection.prepare(INSERT_TRADE_SQL).unwrap();
+    /// Open interest samples with `ts` in `[start_time, end_time)`, ordered by `ts`
+    fn retrieve_open_interest(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<OpenInterest>, StorageError>;
 
-        self.connection.execute("BEGIN TRANSACTION;").unwrap();
+    /// Distinct symbols present in the trades table
+    fn trade_symbols(&self) -> Result<Vec<String>, StorageError>;
 
-        for trade in trades {
-            statement.bind((1, trade.id.as_str())).unwrap();
-            statement.bind((2, trade.symbol.as_str())).unwrap();
-            statement.bind((3, trade.amount.as_str())).unwrap();
-            statement.bind((4, trade.taker_side.as_str())).unwrap();
-            statement.bind((5, trade.quantity.as_str())).unwrap();
-            statement.bind((6, trade.create_time as i64)).unwrap();
-            statement.bind((7, trade.price.as_str())).unwrap();
-            statement.bind((8, trade.ts as i64)).unwrap();
+    /// Deletes trades of `symbol` with `ts` before `before`, returns the number of deleted rows
+    fn delete_trades_before(
+        &mut self,
+        symbol: &str,
+        before: &DateTime<Utc>,
+    ) -> Result<usize, StorageError>;
 
-            statement.next().unwrap();
-            statement.reset().unwrap();
-        }
-
-        self.connection.execute("COMMIT;").unwrap();
+    /// Reclaims space after deletions, if the backend needs it
+    fn compact(&mut self) -> Result<(), StorageError> {
+        Ok(())
     }
+}
 
-    pub fn retrieve_trades_in_interval(
-        &self,
-        start_time: &DateTime<Utc>,
-        end_time: &DateTime<Utc>,
-    ) -> Vec<Trade> {
-        let mut statement = self
-            .connection
-            .prepare(RETRIEVE_TRADES_BY_TIMEFRAME_SQL)
-            .unwrap();
-
-        statement
-            .bind((1, start_time.to_rfc3339().as_str()))
-            .unwrap();
-        statement.bind((2, end_time.to_rfc3339().as_str())).unwrap();
-
-        let mut trades = Vec::new();
-        while let Ok(sqlite::State::Row) = statement.next() {
-            let trade = Trade {
-                id: statement.read::<String, _>(0).unwrap(),
-                symbol: statement.read::<String, _>(1).unwrap(),
-                amount: statement.read::<String, _>(2).unwrap(),
-                taker_side: statement.read::<String, _>(3).unwrap(),
-                quantity: statement.read::<String, _>(4).unwrap(),
-                create_time: statement.read::<i64, _>(5).unwrap() as u64,
-                price: statement.read::<String, _>(6).unwrap(),
-                ts: statement.read::<i64, _>(7).unwrap() as u64,
-            };
-            trades.push(trade);
-        }
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InsertedTrades {
+    pub inserted: usize,
+    pub duplicates: usize,
+}
 
-        trades
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(sqlite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Error),
+    /// A row with the same key already exists
+    Conflict(String),
+    /// Stored or received data can't be converted into models
+    InvalidData(String),
+    /// The storage writer thread has stopped
+    WriterClosed,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+            #[cfg(feature = "postgres")]
+            StorageError::Postgres(err) => write!(f, "postgres error: {}", err),
+            StorageError::Conflict(msg) => write!(f, "conflict: {}", msg),
+            StorageError::InvalidData(msg) => write!(f, "invalid data: {}", msg),
+            StorageError::WriterClosed => write!(f, "storage writer is closed"),
+        }
     }
+}
 
-    // TODO Generalize `insert_kline` and `insert_candles`
-    pub fn insert_kline(&self, kline: &Kline) -> Result<(), sqlite::Error> {
-        let mut statement = self.connection.prepare(INSERT_CANDLE_SQL).unwrap();
-
-        statement.bind((1, kline.pair.as_str())).unwrap();
-        statement.bind((2, kline.pair.as_str())).unwrap();
-        statement.bind((3, kline.low)).unwrap();
-        statement.bind((4, kline.high)).unwrap();
-        statement.bind((5, kline.open)).unwrap();
-        statement.bind((6, kline.close)).unwrap();
-        statement
-            .bind((7, kline.volume_bs.buy_quote + kline.volume_bs.sell_quote))
-            .unwrap();
-        statement
-            .bind((8, kline.volume_bs.buy_base + kline.volume_bs.sell_base))
-            .unwrap();
-        statement.bind((9, 0)).unwrap();
-        statement
-            .bind((
-                10,
-                DateTime::from_timestamp(kline.utc_begin, 0)
-                    .unwrap()
-                    .to_rfc3339()
-                    .as_str(),
-            ))
-            .unwrap();
-        statement
-            .bind((
-                11,
-                DateTime::from_timestamp(kline.utc_end, 0)
-                    .unwrap()
-                    .to_rfc3339()
-                    .as_str(),
-            ))
-            .unwrap();
-
-        statement.next()?;
-        Ok(())
+impl std::error::Error for StorageError {}
+
+impl From<sqlite::Error> for StorageError {
+    fn from(err: sqlite::Error) -> Self {
+        StorageError::Sqlite(err)
     }
+}
 
-    pub fn insert_candles(&self, symbol: String, data: Vec<Vec<String>>) {
-        let mut statement = self.connection.prepare(INSERT_CANDLE_SQL).unwrap();
-
-        for row in data {
-            statement.bind((1, symbol.as_str())).unwrap();
-            statement
-                .bind((2, row[0].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((3, row[1].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((4, row[2].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((5, row[3].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((6, row[4].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((7, row[5].parse::<f64>().unwrap_or(0.0)))
-                .unwrap();
-            statement
-                .bind((8, row[6].parse::<i64>().unwrap_or(0)))
-                .unwrap();
-            statement.bind((9, row[7].as_str())).unwrap();
-            statement.bind((10, row[8].as_str())).unwrap();
-
-            statement.next().unwrap();
-            statement.reset().unwrap();
-        }
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for StorageError {
+    fn from(err: postgres::Error) -> Self {
+        StorageError::Postgres(err)
     }
 }
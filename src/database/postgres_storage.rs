@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use postgres::{Client, NoTls, Row};
+
+use crate::client::models::RawKLHistory;
+use crate::common::models::{FundingRate, Kline, KlineSource, OpenInterest, TimeFrame, Trade, Vbs};
+use crate::database::queries::*;
+
+use super::{InsertedTrades, Storage, StorageError};
+
+/// PostgreSQL backend, optionally with TimescaleDB hypertables for trades and klines.
+///
+/// The underlying client is blocking, so it must not be called from inside an async task
+pub struct PostgresStorage {
+    client: RefCell<Client>,
+}
+
+impl PostgresStorage {
+    /// `params` is a libpq-style connection string or a `postgresql://` URL
+    pub fn new(params: &str, timescale: bool) -> Result<Self, StorageError> {
+        let mut client = Client::connect(params, NoTls)?;
+        client.batch_execute(PG_CREATE_TABLES_SQL)?;
+        if timescale {
+            client.batch_execute(PG_CREATE_HYPERTABLES_SQL)?;
+        }
+
+        tracing::info!("Connected to PostgreSQL, timescale: {}", timescale);
+
+        Ok(Self {
+            client: RefCell::new(client),
+        })
+    }
+
+    fn write_kline(&mut self, sql: &str, kline: &Kline) -> Result<(), StorageError> {
+        self.client.get_mut().execute(
+            sql,
+            &[
+                &kline.pair,
+                &kline.timeframe.as_ref(),
+                &kline.utc_begin,
+                &kline.utc_end,
+                &kline.open,
+                &kline.high,
+                &kline.low,
+                &kline.close,
+                &kline.volume_bs.buy_base,
+                &kline.volume_bs.sell_base,
+                &kline.volume_bs.buy_quote,
+                &kline.volume_bs.sell_quote,
+                &kline.source.as_ref(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn read_kline(row: &Row) -> Result<Kline, StorageError> {
+        let timeframe: String = row.try_get(1)?;
+        let source: String = row.try_get(12)?;
+
+        Ok(Kline {
+            pair: row.try_get(0)?,
+            timeframe: TimeFrame::from_str(&timeframe)
+                .map_err(|_| StorageError::InvalidData(format!("timeframe {}", timeframe)))?,
+            utc_begin: row.try_get(2)?,
+            utc_end: row.try_get(3)?,
+            open: row.try_get(4)?,
+            high: row.try_get(5)?,
+            low: row.try_get(6)?,
+            close: row.try_get(7)?,
+            volume_bs: Vbs {
+                buy_base: row.try_get(8)?,
+                sell_base: row.try_get(9)?,
+                buy_quote: row.try_get(10)?,
+                sell_quote: row.try_get(11)?,
+            },
+            source: KlineSource::from_str(&source)
+                .map_err(|_| StorageError::InvalidData(format!("kline source {}", source)))?,
+        })
+    }
+}
+
+/// Escapes a value for the text format of `COPY`
+fn copy_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+impl Storage for PostgresStorage {
+    /// The whole batch is sent with a single `COPY` into the staging table
+    fn insert_recent_trades(&mut self, trades: &[Trade]) -> Result<InsertedTrades, StorageError> {
+        let mut buffer = Vec::new();
+        for trade in trades {
+            writeln!(
+                buffer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                copy_text(&trade.id),
+                copy_text(&trade.symbol),
+                copy_text(&trade.amount),
+                copy_text(&trade.taker_side),
+                copy_text(&trade.quantity),
+                trade.create_time,
+                copy_text(&trade.price),
+                trade.ts
+            )
+            .map_err(|err| StorageError::InvalidData(err.to_string()))?;
+        }
+
+        let client = self.client.get_mut();
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(PG_CREATE_TRADES_STAGING_SQL)?;
+        let mut writer = transaction.copy_in(PG_COPY_TRADES_SQL)?;
+        writer
+            .write_all(&buffer)
+            .map_err(|err| StorageError::InvalidData(err.to_string()))?;
+        writer.finish()?;
+        let inserted = transaction.execute(PG_MOVE_STAGED_TRADES_SQL, &[])? as usize;
+        transaction.commit()?;
+
+        Ok(InsertedTrades {
+            inserted,
+            duplicates: trades.len() - inserted,
+        })
+    }
+
+    fn insert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        self.write_kline(PG_INSERT_KLINE_SQL, kline)
+    }
+
+    fn upsert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        self.write_kline(PG_UPSERT_KLINE_SQL, kline)
+    }
+
+    fn insert_candles(&mut self, symbol: &str, data: &RawKLHistory) -> Result<(), StorageError> {
+        let client = self.client.get_mut();
+        let mut transaction = client.transaction()?;
+        let statement = transaction.prepare(PG_INSERT_CANDLE_SQL)?;
+
+        for row in data {
+            if row.len() < 9 {
+                return Err(StorageError::InvalidData(format!("candle row {:?}", row)));
+            }
+
+            let float = |i: usize| row[i].parse::<f64>().unwrap_or(0.0);
+            let int = |i: usize| row[i].parse::<i64>().unwrap_or(0);
+
+            transaction.execute(
+                &statement,
+                &[
+                    &format!("{}_{}", symbol, row[7]),
+                    &symbol,
+                    &float(0),
+                    &float(1),
+                    &float(2),
+                    &float(3),
+                    &float(4),
+                    &float(5),
+                    &int(6),
+                    &int(7),
+                    &int(8),
+                ],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn retrieve_trades_in_interval(
+        &self,
+        symbol: Option<&str>,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Trade>, StorageError> {
+        // `LIMIT NULL` doesn't limit
+        let rows = self.client.borrow_mut().query(
+            PG_RETRIEVE_TRADES_BY_TIMEFRAME_SQL,
+            &[
+                &symbol,
+                &start_time.timestamp_millis(),
+                &end_time.timestamp_millis(),
+                &limit.map(|limit| limit as i64),
+            ],
+        )?;
+
+        let mut trades = Vec::with_capacity(rows.len());
+        for row in rows {
+            trades.push(Trade {
+                id: row.try_get(0)?,
+                symbol: row.try_get(1)?,
+                amount: row.try_get(2)?,
+                taker_side: row.try_get(3)?,
+                quantity: row.try_get(4)?,
+                create_time: row.try_get::<_, i64>(5)? as u64,
+                price: row.try_get(6)?,
+                ts: row.try_get::<_, i64>(7)? as u64,
+            });
+        }
+
+        Ok(trades)
+    }
+
+    fn retrieve_klines_in_interval(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let rows = self.client.borrow_mut().query(
+            PG_RETRIEVE_KLINES_BY_TIMEFRAME_SQL,
+            &[
+                &symbol,
+                &timeframe.as_ref(),
+                &start_time.timestamp(),
+                &end_time.timestamp(),
+                &limit.map(|limit| limit as i64),
+            ],
+        )?;
+
+        rows.iter().map(Self::read_kline).collect()
+    }
+
+    fn latest_kline(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+    ) -> Result<Option<Kline>, StorageError> {
+        let row = self.client.borrow_mut().query_opt(
+            PG_RETRIEVE_LATEST_KLINE_SQL,
+            &[&symbol, &timeframe.as_ref()],
+        )?;
+
+        row.as_ref().map(Self::read_kline).transpose()
+    }
+
+    fn upsert_funding_rates(&mut self, rates: &[FundingRate]) -> Result<(), StorageError> {
+        let client = self.client.get_mut();
+        let mut transaction = client.transaction()?;
+        let statement = transaction.prepare(PG_UPSERT_FUNDING_RATE_SQL)?;
+
+        for rate in rates {
+            transaction.execute(
+                &statement,
+                &[
+                    &rate.symbol,
+                    &(rate.funding_time as i64),
+                    &rate.rate,
+                    &(rate.ts as i64),
+                ],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn retrieve_funding_rates(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, StorageError> {
+        let rows = self.client.borrow_mut().query(
+            PG_RETRIEVE_FUNDING_RATES_SQL,
+            &[
+                &symbol,
+                &start_time.timestamp_millis(),
+                &end_time.timestamp_millis(),
+            ],
+        )?;
+
+        let mut rates = Vec::with_capacity(rows.len());
+        for row in rows {
+            rates.push(FundingRate {
+                symbol: row.try_get(0)?,
+                funding_time: row.try_get::<_, i64>(1)? as u64,
+                rate: row.try_get(2)?,
+                ts: row.try_get::<_, i64>(3)? as u64,
+            });
+        }
+
+        Ok(rates)
+    }
+
+    fn insert_open_interest(&mut self, samples: &[OpenInterest]) -> Result<(), StorageError> {
+        let client = self.client.get_mut();
+        let mut transaction = client.transaction()?;
+        let statement = transaction.prepare(PG_INSERT_OPEN_INTEREST_SQL)?;
+
+        for sample in samples {
+            transaction.execute(
+                &statement,
+                &[&sample.symbol, &(sample.ts as i64), &sample.open_interest],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn retrieve_open_interest(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<OpenInterest>, StorageError> {
+        let rows = self.client.borrow_mut().query(
+            PG_RETRIEVE_OPEN_INTEREST_SQL,
+            &[
+                &symbol,
+                &start_time.timestamp_millis(),
+                &end_time.timestamp_millis(),
+            ],
+        )?;
+
+        let mut samples = Vec::with_capacity(rows.len());
+        for row in rows {
+            samples.push(OpenInterest {
+                symbol: row.try_get(0)?,
+                ts: row.try_get::<_, i64>(1)? as u64,
+                open_interest: row.try_get(2)?,
+            });
+        }
+
+        Ok(samples)
+    }
+
+    fn trade_symbols(&self) -> Result<Vec<String>, StorageError> {
+        let rows = self
+            .client
+            .borrow_mut()
+            .query(PG_RETRIEVE_TRADE_SYMBOLS_SQL, &[])?;
+
+        rows.iter()
+            .map(|row| row.try_get(0).map_err(StorageError::from))
+            .collect()
+    }
+
+    fn delete_trades_before(
+        &mut self,
+        symbol: &str,
+        before: &DateTime<Utc>,
+    ) -> Result<usize, StorageError> {
+        let deleted = self.client.get_mut().execute(
+            PG_DELETE_TRADES_BEFORE_SQL,
+            &[&symbol, &before.timestamp_millis()],
+        )?;
+
+        Ok(deleted as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::kline;
+
+    /// Runs against a local instance, e.g.
+    /// `POSTGRES_URL=postgresql://postgres@localhost/test cargo test --features postgres -- --ignored`
+    #[test]
+    #[ignore]
+    fn trades_and_klines_round_trip() {
+        let url = std::env::var("POSTGRES_URL").expect("POSTGRES_URL is not set");
+        let mut db = PostgresStorage::new(&url, false).unwrap();
+        db.client
+            .borrow_mut()
+            .batch_execute("TRUNCATE trades, klines;")
+            .unwrap();
+
+        let trade = Trade {
+            symbol: "BTC_USDT".to_string(),
+            amount: "9.5".to_string(),
+            taker_side: "sell".to_string(),
+            quantity: "0.1".to_string(),
+            create_time: 1_000,
+            price: "95".to_string(),
+            id: "1".to_string(),
+            ts: 1_000,
+        };
+        db.insert_recent_trades(std::slice::from_ref(&trade))
+            .unwrap();
+        let replayed = db
+            .insert_recent_trades(&[trade.clone(), trade.clone()])
+            .unwrap();
+        assert_eq!(replayed.duplicates, 2);
+        // the same trade id delivered again with a later event time
+        let redelivered = Trade {
+            ts: 1_500,
+            ..trade.clone()
+        };
+        let replayed = db.insert_recent_trades(&[redelivered]).unwrap();
+        assert_eq!(replayed.duplicates, 1);
+
+        let start = DateTime::from_timestamp_millis(0).unwrap();
+        let end = DateTime::from_timestamp_millis(2_000).unwrap();
+        let trades = db
+            .retrieve_trades_in_interval(Some("BTC_USDT"), &start, &end, None)
+            .unwrap();
+        assert_eq!(trades, vec![trade]);
+
+        let kline = kline("BTC_USDT", TimeFrame::Hour, 0, 3600)
+            .prices(1.0, 2.0, 0.5, 1.5)
+            .volume(1.0, 2.0, 3.0, 4.0)
+            .source(KlineSource::Exchange)
+            .build();
+        db.insert_kline(&kline).unwrap();
+        assert!(db.insert_kline(&kline).is_err());
+
+        let latest = db.latest_kline("BTC_USDT", &TimeFrame::Hour).unwrap();
+        assert_eq!(latest, Some(kline));
+    }
+}
@@ -1,3 +1,5 @@
+// SQLite queries
+
 pub const CREATE_CANDLES_TABLE_SQL: &str = "
 CREATE TABLE IF NOT EXISTS candles (
     id TEXT PRIMARY KEY,
@@ -15,18 +17,62 @@ CREATE TABLE IF NOT EXISTS candles (
 
 pub const CREATE_TRADES_TABLE_SQL: &str = "
 CREATE TABLE IF NOT EXISTS trades (
-    id TEXT PRIMARY KEY,
-    symbol TEXT,
+    id TEXT NOT NULL,
+    symbol TEXT NOT NULL,
     amount TEXT,
     taker_side TEXT,
     quantity TEXT,
     create_time INTEGER,
     price TEXT,
-    ts INTEGER
+    ts INTEGER,
+    PRIMARY KEY (symbol, id)
+);
+CREATE INDEX IF NOT EXISTS trades_symbol_ts_idx ON trades (symbol, ts);";
+
+pub const CREATE_KLINES_TABLE_SQL: &str = "
+CREATE TABLE IF NOT EXISTS klines (
+    symbol TEXT NOT NULL,
+    timeframe TEXT NOT NULL,
+    utc_begin INTEGER NOT NULL,
+    utc_end INTEGER NOT NULL,
+    open REAL,
+    high REAL,
+    low REAL,
+    close REAL,
+    buy_base REAL,
+    sell_base REAL,
+    buy_quote REAL,
+    sell_quote REAL,
+    source TEXT NOT NULL DEFAULT 'aggregated',
+    PRIMARY KEY (symbol, timeframe, utc_begin)
 );";
 
+/// `funding_time` and `ts` are unix milliseconds
+pub const CREATE_FUNDING_RATES_TABLE_SQL: &str = "
+CREATE TABLE IF NOT EXISTS funding_rates (
+    symbol TEXT NOT NULL,
+    funding_time INTEGER NOT NULL,
+    rate TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    PRIMARY KEY (symbol, funding_time)
+);";
+
+pub const CREATE_OPEN_INTEREST_TABLE_SQL: &str = "
+CREATE TABLE IF NOT EXISTS open_interest (
+    symbol TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    open_interest TEXT NOT NULL,
+    PRIMARY KEY (symbol, ts)
+);";
+
+/// Databases created before klines were tagged with their source
+pub const ADD_KLINES_SOURCE_COLUMN_SQL: &str = "
+ALTER TABLE klines ADD COLUMN source TEXT NOT NULL DEFAULT 'aggregated';";
+
+pub const KLINES_COLUMNS_SQL: &str = "PRAGMA table_info(klines);";
+
 pub const INSERT_CANDLE_SQL: &str = "
-INSERT INTO candles (
+INSERT OR REPLACE INTO candles (
     id,
     symbol,
     lowest_price,
@@ -40,8 +86,9 @@ INSERT INTO candles (
     end_time
 ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
 
+/// Trades already stored under the same symbol and id are skipped
 pub const INSERT_TRADE_SQL: &str = "
-INSERT INTO trades (
+INSERT OR IGNORE INTO trades (
     id,
     symbol,
     amount,
@@ -52,7 +99,324 @@ INSERT INTO trades (
     ts
 ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);";
 
+pub const INSERT_KLINE_SQL: &str = "
+INSERT INTO klines (
+    symbol,
+    timeframe,
+    utc_begin,
+    utc_end,
+    open,
+    high,
+    low,
+    close,
+    buy_base,
+    sell_base,
+    buy_quote,
+    sell_quote,
+    source
+) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
+
+pub const UPSERT_KLINE_SQL: &str = "
+INSERT INTO klines (
+    symbol,
+    timeframe,
+    utc_begin,
+    utc_end,
+    open,
+    high,
+    low,
+    close,
+    buy_base,
+    sell_base,
+    buy_quote,
+    sell_quote,
+    source
+) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+ON CONFLICT (symbol, timeframe, utc_begin) DO UPDATE SET
+    utc_end = excluded.utc_end,
+    open = excluded.open,
+    high = excluded.high,
+    low = excluded.low,
+    close = excluded.close,
+    buy_base = excluded.buy_base,
+    sell_base = excluded.sell_base,
+    buy_quote = excluded.buy_quote,
+    sell_quote = excluded.sell_quote,
+    source = excluded.source;";
+
+/// `ts` is in milliseconds, the range is half-open `[start, end)`.
+/// A NULL symbol matches every symbol
 pub const RETRIEVE_TRADES_BY_TIMEFRAME_SQL: &str = "
 SELECT id, symbol, amount, taker_side, quantity, create_time, price, ts
 FROM trades
-WHERE create_time BETWEEN ? AND ?;";
+WHERE (?1 IS NULL OR symbol = ?1) AND ts >= ?2 AND ts < ?3
+ORDER BY ts
+LIMIT ?4;";
+
+pub const RETRIEVE_KLINES_BY_TIMEFRAME_SQL: &str = "
+SELECT symbol, timeframe, utc_begin, utc_end, open, high, low, close,
+    buy_base, sell_base, buy_quote, sell_quote, source
+FROM klines
+WHERE symbol = ? AND timeframe = ? AND utc_begin >= ? AND utc_begin < ?
+ORDER BY utc_begin
+LIMIT ?;";
+
+pub const RETRIEVE_LATEST_KLINE_SQL: &str = "
+SELECT symbol, timeframe, utc_begin, utc_end, open, high, low, close,
+    buy_base, sell_base, buy_quote, sell_quote, source
+FROM klines
+WHERE symbol = ? AND timeframe = ?
+ORDER BY utc_begin DESC
+LIMIT 1;";
+
+pub const UPSERT_FUNDING_RATE_SQL: &str = "
+INSERT INTO funding_rates (symbol, funding_time, rate, ts) VALUES (?, ?, ?, ?)
+ON CONFLICT (symbol, funding_time) DO UPDATE SET
+    rate = excluded.rate,
+    ts = excluded.ts;";
+
+pub const RETRIEVE_FUNDING_RATES_SQL: &str = "
+SELECT symbol, funding_time, rate, ts
+FROM funding_rates
+WHERE symbol = ? AND funding_time >= ? AND funding_time < ?
+ORDER BY funding_time;";
+
+pub const INSERT_OPEN_INTEREST_SQL: &str = "
+INSERT OR IGNORE INTO open_interest (symbol, ts, open_interest) VALUES (?, ?, ?);";
+
+pub const RETRIEVE_OPEN_INTEREST_SQL: &str = "
+SELECT symbol, ts, open_interest
+FROM open_interest
+WHERE symbol = ? AND ts >= ? AND ts < ?
+ORDER BY ts;";
+
+pub const RETRIEVE_TRADE_SYMBOLS_SQL: &str = "
+SELECT DISTINCT symbol FROM trades;";
+
+pub const DELETE_TRADES_BEFORE_SQL: &str = "
+DELETE FROM trades
+WHERE symbol = ? AND ts < ?;";
+
+/// Only applies to a database without tables, or to the next `VACUUM`
+pub const SET_INCREMENTAL_VACUUM_SQL: &str = "PRAGMA auto_vacuum = INCREMENTAL;";
+
+pub const AUTO_VACUUM_SQL: &str = "PRAGMA auto_vacuum;";
+
+/// `auto_vacuum` mode of databases which free pages with `INCREMENTAL_VACUUM_SQL`
+pub const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// Returns at most 1024 free pages to the file system, 4 MiB with the default page size
+pub const INCREMENTAL_VACUUM_SQL: &str = "PRAGMA incremental_vacuum(1024);";
+
+// PostgreSQL queries
+
+pub const PG_CREATE_TABLES_SQL: &str = "
+CREATE TABLE IF NOT EXISTS candles (
+    id TEXT PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    lowest_price DOUBLE PRECISION,
+    highest_price DOUBLE PRECISION,
+    opening_price DOUBLE PRECISION,
+    closing_price DOUBLE PRECISION,
+    trading_unit_quote_currency DOUBLE PRECISION,
+    trading_unit_base_currency DOUBLE PRECISION,
+    trades BIGINT,
+    start_time BIGINT,
+    end_time BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS trades (
+    id TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    amount NUMERIC,
+    taker_side TEXT,
+    quantity NUMERIC,
+    create_time BIGINT,
+    price NUMERIC,
+    ts BIGINT NOT NULL,
+    PRIMARY KEY (symbol, id, ts)
+);
+CREATE INDEX IF NOT EXISTS trades_symbol_ts_idx ON trades (symbol, ts);
+
+CREATE TABLE IF NOT EXISTS klines (
+    symbol TEXT NOT NULL,
+    timeframe TEXT NOT NULL,
+    utc_begin BIGINT NOT NULL,
+    utc_end BIGINT NOT NULL,
+    open DOUBLE PRECISION,
+    high DOUBLE PRECISION,
+    low DOUBLE PRECISION,
+    close DOUBLE PRECISION,
+    buy_base DOUBLE PRECISION,
+    sell_base DOUBLE PRECISION,
+    buy_quote DOUBLE PRECISION,
+    sell_quote DOUBLE PRECISION,
+    source TEXT NOT NULL DEFAULT 'aggregated',
+    PRIMARY KEY (symbol, timeframe, utc_begin)
+);
+ALTER TABLE klines ADD COLUMN IF NOT EXISTS source TEXT NOT NULL DEFAULT 'aggregated';
+
+CREATE TABLE IF NOT EXISTS funding_rates (
+    symbol TEXT NOT NULL,
+    funding_time BIGINT NOT NULL,
+    rate NUMERIC NOT NULL,
+    ts BIGINT NOT NULL,
+    PRIMARY KEY (symbol, funding_time)
+);
+
+CREATE TABLE IF NOT EXISTS open_interest (
+    symbol TEXT NOT NULL,
+    ts BIGINT NOT NULL,
+    open_interest NUMERIC NOT NULL,
+    PRIMARY KEY (symbol, ts)
+);";
+
+/// Trades are partitioned by `ts` in milliseconds and klines by `utc_begin` in seconds,
+/// both in one day chunks
+pub const PG_CREATE_HYPERTABLES_SQL: &str = "
+CREATE EXTENSION IF NOT EXISTS timescaledb;
+SELECT create_hypertable('trades', 'ts', chunk_time_interval => 86400000, if_not_exists => TRUE);
+SELECT create_hypertable('klines', 'utc_begin', chunk_time_interval => 86400, if_not_exists => TRUE);";
+
+/// Trades are copied into a per-session staging table first, so duplicates can be skipped
+/// when moving them into `trades`
+pub const PG_CREATE_TRADES_STAGING_SQL: &str = "
+CREATE TEMP TABLE IF NOT EXISTS trades_staging (LIKE trades) ON COMMIT DELETE ROWS;";
+
+pub const PG_COPY_TRADES_SQL: &str = "
+COPY trades_staging (id, symbol, amount, taker_side, quantity, create_time, price, ts) FROM STDIN";
+
+/// The hypertable key has to include `ts`, so a trade replayed with another `ts`
+/// wouldn't conflict. Trades are deduplicated on `(symbol, id)` here instead, the
+/// lookup uses the key's `(symbol, id)` prefix. Only one writer moves trades
+pub const PG_MOVE_STAGED_TRADES_SQL: &str = "
+INSERT INTO trades (id, symbol, amount, taker_side, quantity, create_time, price, ts)
+SELECT DISTINCT ON (symbol, id) id, symbol, amount, taker_side, quantity, create_time, price, ts
+FROM trades_staging staged
+WHERE NOT EXISTS (
+    SELECT 1 FROM trades stored WHERE stored.symbol = staged.symbol AND stored.id = staged.id
+)
+ORDER BY symbol, id, ts
+ON CONFLICT DO NOTHING;";
+
+pub const PG_INSERT_CANDLE_SQL: &str = "
+INSERT INTO candles (
+    id,
+    symbol,
+    lowest_price,
+    highest_price,
+    opening_price,
+    closing_price,
+    trading_unit_quote_currency,
+    trading_unit_base_currency,
+    trades,
+    start_time,
+    end_time
+) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+ON CONFLICT (id) DO UPDATE SET
+    lowest_price = excluded.lowest_price,
+    highest_price = excluded.highest_price,
+    opening_price = excluded.opening_price,
+    closing_price = excluded.closing_price,
+    trading_unit_quote_currency = excluded.trading_unit_quote_currency,
+    trading_unit_base_currency = excluded.trading_unit_base_currency,
+    trades = excluded.trades,
+    end_time = excluded.end_time;";
+
+pub const PG_INSERT_KLINE_SQL: &str = "
+INSERT INTO klines (
+    symbol,
+    timeframe,
+    utc_begin,
+    utc_end,
+    open,
+    high,
+    low,
+    close,
+    buy_base,
+    sell_base,
+    buy_quote,
+    sell_quote,
+    source
+) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13);";
+
+pub const PG_UPSERT_KLINE_SQL: &str = "
+INSERT INTO klines (
+    symbol,
+    timeframe,
+    utc_begin,
+    utc_end,
+    open,
+    high,
+    low,
+    close,
+    buy_base,
+    sell_base,
+    buy_quote,
+    sell_quote,
+    source
+) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+ON CONFLICT (symbol, timeframe, utc_begin) DO UPDATE SET
+    utc_end = excluded.utc_end,
+    open = excluded.open,
+    high = excluded.high,
+    low = excluded.low,
+    close = excluded.close,
+    buy_base = excluded.buy_base,
+    sell_base = excluded.sell_base,
+    buy_quote = excluded.buy_quote,
+    sell_quote = excluded.sell_quote,
+    source = excluded.source;";
+
+pub const PG_RETRIEVE_TRADES_BY_TIMEFRAME_SQL: &str = "
+SELECT id, symbol, amount::TEXT, taker_side, quantity::TEXT, create_time, price::TEXT, ts
+FROM trades
+WHERE ($1::TEXT IS NULL OR symbol = $1) AND ts >= $2 AND ts < $3
+ORDER BY ts
+LIMIT $4;";
+
+pub const PG_RETRIEVE_KLINES_BY_TIMEFRAME_SQL: &str = "
+SELECT symbol, timeframe, utc_begin, utc_end, open, high, low, close,
+    buy_base, sell_base, buy_quote, sell_quote, source
+FROM klines
+WHERE symbol = $1 AND timeframe = $2 AND utc_begin >= $3 AND utc_begin < $4
+ORDER BY utc_begin
+LIMIT $5;";
+
+pub const PG_RETRIEVE_LATEST_KLINE_SQL: &str = "
+SELECT symbol, timeframe, utc_begin, utc_end, open, high, low, close,
+    buy_base, sell_base, buy_quote, sell_quote, source
+FROM klines
+WHERE symbol = $1 AND timeframe = $2
+ORDER BY utc_begin DESC
+LIMIT 1;";
+
+/// Decimals are bound as text and converted by the server, like `COPY` does for trades
+pub const PG_UPSERT_FUNDING_RATE_SQL: &str = "
+INSERT INTO funding_rates (symbol, funding_time, rate, ts) VALUES ($1, $2, $3::TEXT::NUMERIC, $4)
+ON CONFLICT (symbol, funding_time) DO UPDATE SET
+    rate = excluded.rate,
+    ts = excluded.ts;";
+
+pub const PG_RETRIEVE_FUNDING_RATES_SQL: &str = "
+SELECT symbol, funding_time, rate::TEXT, ts
+FROM funding_rates
+WHERE symbol = $1 AND funding_time >= $2 AND funding_time < $3
+ORDER BY funding_time;";
+
+pub const PG_INSERT_OPEN_INTEREST_SQL: &str = "
+INSERT INTO open_interest (symbol, ts, open_interest) VALUES ($1, $2, $3::TEXT::NUMERIC)
+ON CONFLICT DO NOTHING;";
+
+pub const PG_RETRIEVE_OPEN_INTEREST_SQL: &str = "
+SELECT symbol, ts, open_interest::TEXT
+FROM open_interest
+WHERE symbol = $1 AND ts >= $2 AND ts < $3
+ORDER BY ts;";
+
+pub const PG_RETRIEVE_TRADE_SYMBOLS_SQL: &str = "
+SELECT DISTINCT symbol FROM trades;";
+
+pub const PG_DELETE_TRADES_BEFORE_SQL: &str = "
+DELETE FROM trades
+WHERE symbol = $1 AND ts < $2;";
@@ -0,0 +1,502 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+
+use crate::client::models::RawKLHistory;
+use crate::common::models::{FundingRate, Kline, KlineSource, OpenInterest, TimeFrame, Trade, Vbs};
+use crate::database::queries::*;
+
+use super::{InsertedTrades, Storage, StorageError};
+
+pub const SQLX_ADDR: &str = ":memory:";
+
+pub struct SqliteStorage {
+    connection: sqlite::Connection,
+}
+
+impl SqliteStorage {
+    pub fn new(addr: &str) -> Result<Self, StorageError> {
+        let connection = sqlite::open(addr)?;
+        connection.execute(SET_INCREMENTAL_VACUUM_SQL)?;
+        connection.execute(CREATE_CANDLES_TABLE_SQL)?;
+        connection.execute(CREATE_TRADES_TABLE_SQL)?;
+        connection.execute(CREATE_KLINES_TABLE_SQL)?;
+        connection.execute(CREATE_FUNDING_RATES_TABLE_SQL)?;
+        connection.execute(CREATE_OPEN_INTEREST_TABLE_SQL)?;
+
+        let database = Self { connection };
+        database.migrate()?;
+        tracing::info!("Database created at {}", addr);
+
+        Ok(database)
+    }
+
+    fn migrate(&self) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(KLINES_COLUMNS_SQL)?;
+        let mut has_source = false;
+        while let sqlite::State::Row = statement.next()? {
+            has_source |= statement.read::<String, _>("name")? == "source";
+        }
+
+        if !has_source {
+            self.connection.execute(ADD_KLINES_SOURCE_COLUMN_SQL)?;
+        }
+
+        // databases created without it are rebuilt once, so `compact` can free pages gradually
+        if self.auto_vacuum()? != AUTO_VACUUM_INCREMENTAL {
+            tracing::info!("Enabling incremental vacuum, rebuilding the database");
+            self.connection.execute(SET_INCREMENTAL_VACUUM_SQL)?;
+            self.connection.execute("VACUUM;")?;
+        }
+        Ok(())
+    }
+
+    fn auto_vacuum(&self) -> Result<i64, StorageError> {
+        let mut statement = self.connection.prepare(AUTO_VACUUM_SQL)?;
+        statement.next()?;
+        Ok(statement.read::<i64, _>(0)?)
+    }
+
+    fn write_trades(&self, trades: &[Trade]) -> Result<InsertedTrades, StorageError> {
+        let mut statement = self.connection.prepare(INSERT_TRADE_SQL)?;
+        let mut result = InsertedTrades::default();
+
+        for trade in trades {
+            statement.bind((1, trade.id.as_str()))?;
+            statement.bind((2, trade.symbol.as_str()))?;
+            statement.bind((3, trade.amount.as_str()))?;
+            statement.bind((4, trade.taker_side.as_str()))?;
+            statement.bind((5, trade.quantity.as_str()))?;
+            statement.bind((6, trade.create_time as i64))?;
+            statement.bind((7, trade.price.as_str()))?;
+            statement.bind((8, trade.ts as i64))?;
+
+            statement.next()?;
+            statement.reset()?;
+
+            if self.connection.change_count() > 0 {
+                result.inserted += 1;
+            } else {
+                result.duplicates += 1;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn write_kline(&self, sql: &str, kline: &Kline) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(sql)?;
+
+        statement.bind((1, kline.pair.as_str()))?;
+        statement.bind((2, kline.timeframe.as_ref()))?;
+        statement.bind((3, kline.utc_begin))?;
+        statement.bind((4, kline.utc_end))?;
+        statement.bind((5, kline.open))?;
+        statement.bind((6, kline.high))?;
+        statement.bind((7, kline.low))?;
+        statement.bind((8, kline.close))?;
+        statement.bind((9, kline.volume_bs.buy_base))?;
+        statement.bind((10, kline.volume_bs.sell_base))?;
+        statement.bind((11, kline.volume_bs.buy_quote))?;
+        statement.bind((12, kline.volume_bs.sell_quote))?;
+        statement.bind((13, kline.source.as_ref()))?;
+
+        statement.next()?;
+        Ok(())
+    }
+
+    fn read_kline(statement: &sqlite::Statement) -> Result<Kline, StorageError> {
+        let timeframe = statement.read::<String, _>(1)?;
+        let source = statement.read::<String, _>(12)?;
+
+        Ok(Kline {
+            pair: statement.read::<String, _>(0)?,
+            timeframe: TimeFrame::from_str(&timeframe)
+                .map_err(|_| StorageError::InvalidData(format!("timeframe {}", timeframe)))?,
+            utc_begin: statement.read::<i64, _>(2)?,
+            utc_end: statement.read::<i64, _>(3)?,
+            open: statement.read::<f64, _>(4)?,
+            high: statement.read::<f64, _>(5)?,
+            low: statement.read::<f64, _>(6)?,
+            close: statement.read::<f64, _>(7)?,
+            volume_bs: Vbs {
+                buy_base: statement.read::<f64, _>(8)?,
+                sell_base: statement.read::<f64, _>(9)?,
+                buy_quote: statement.read::<f64, _>(10)?,
+                sell_quote: statement.read::<f64, _>(11)?,
+            },
+            source: KlineSource::from_str(&source)
+                .map_err(|_| StorageError::InvalidData(format!("kline source {}", source)))?,
+        })
+    }
+}
+
+/// SQLite reads a negative `LIMIT` as no limit
+fn sql_limit(limit: Option<usize>) -> i64 {
+    limit.map_or(-1, |limit| limit as i64)
+}
+
+impl Storage for SqliteStorage {
+    fn insert_recent_trades(&mut self, trades: &[Trade]) -> Result<InsertedTrades, StorageError> {
+        self.connection.execute("BEGIN TRANSACTION;")?;
+
+        match self.write_trades(trades) {
+            Ok(result) => {
+                self.connection.execute("COMMIT;")?;
+                Ok(result)
+            }
+            Err(err) => {
+                self.connection.execute("ROLLBACK;")?;
+                Err(err)
+            }
+        }
+    }
+
+    fn insert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        self.write_kline(INSERT_KLINE_SQL, kline)
+    }
+
+    fn upsert_kline(&mut self, kline: &Kline) -> Result<(), StorageError> {
+        self.write_kline(UPSERT_KLINE_SQL, kline)
+    }
+
+    /// Poloniex candle rows are
+    /// `[low, high, open, close, amount, quantity, tradeCount, startTime, closeTime]`
+    fn insert_candles(&mut self, symbol: &str, data: &RawKLHistory) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(INSERT_CANDLE_SQL)?;
+
+        for row in data {
+            if row.len() < 9 {
+                return Err(StorageError::InvalidData(format!("candle row {:?}", row)));
+            }
+
+            statement.bind((1, format!("{}_{}", symbol, row[7]).as_str()))?;
+            statement.bind((2, symbol))?;
+            statement.bind((3, row[0].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((4, row[1].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((5, row[2].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((6, row[3].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((7, row[4].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((8, row[5].parse::<f64>().unwrap_or(0.0)))?;
+            statement.bind((9, row[6].parse::<i64>().unwrap_or(0)))?;
+            statement.bind((10, row[7].as_str()))?;
+            statement.bind((11, row[8].as_str()))?;
+
+            statement.next()?;
+            statement.reset()?;
+        }
+
+        Ok(())
+    }
+
+    fn retrieve_trades_in_interval(
+        &self,
+        symbol: Option<&str>,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Trade>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_TRADES_BY_TIMEFRAME_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, start_time.timestamp_millis()))?;
+        statement.bind((3, end_time.timestamp_millis()))?;
+        statement.bind((4, sql_limit(limit)))?;
+
+        let mut trades = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let trade = Trade {
+                id: statement.read::<String, _>(0)?,
+                symbol: statement.read::<String, _>(1)?,
+                amount: statement.read::<String, _>(2)?,
+                taker_side: statement.read::<String, _>(3)?,
+                quantity: statement.read::<String, _>(4)?,
+                create_time: statement.read::<i64, _>(5)? as u64,
+                price: statement.read::<String, _>(6)?,
+                ts: statement.read::<i64, _>(7)? as u64,
+            };
+            trades.push(trade);
+        }
+
+        Ok(trades)
+    }
+
+    fn retrieve_klines_in_interval(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_KLINES_BY_TIMEFRAME_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, timeframe.as_ref()))?;
+        statement.bind((3, start_time.timestamp()))?;
+        statement.bind((4, end_time.timestamp()))?;
+        statement.bind((5, sql_limit(limit)))?;
+
+        let mut klines = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            klines.push(Self::read_kline(&statement)?);
+        }
+
+        Ok(klines)
+    }
+
+    fn latest_kline(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+    ) -> Result<Option<Kline>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_LATEST_KLINE_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, timeframe.as_ref()))?;
+
+        match statement.next()? {
+            sqlite::State::Row => Ok(Some(Self::read_kline(&statement)?)),
+            sqlite::State::Done => Ok(None),
+        }
+    }
+
+    fn upsert_funding_rates(&mut self, rates: &[FundingRate]) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(UPSERT_FUNDING_RATE_SQL)?;
+
+        for rate in rates {
+            statement.bind((1, rate.symbol.as_str()))?;
+            statement.bind((2, rate.funding_time as i64))?;
+            statement.bind((3, rate.rate.as_str()))?;
+            statement.bind((4, rate.ts as i64))?;
+
+            statement.next()?;
+            statement.reset()?;
+        }
+
+        Ok(())
+    }
+
+    fn retrieve_funding_rates(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_FUNDING_RATES_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, start_time.timestamp_millis()))?;
+        statement.bind((3, end_time.timestamp_millis()))?;
+
+        let mut rates = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            rates.push(FundingRate {
+                symbol: statement.read::<String, _>(0)?,
+                funding_time: statement.read::<i64, _>(1)? as u64,
+                rate: statement.read::<String, _>(2)?,
+                ts: statement.read::<i64, _>(3)? as u64,
+            });
+        }
+
+        Ok(rates)
+    }
+
+    fn insert_open_interest(&mut self, samples: &[OpenInterest]) -> Result<(), StorageError> {
+        let mut statement = self.connection.prepare(INSERT_OPEN_INTEREST_SQL)?;
+
+        for sample in samples {
+            statement.bind((1, sample.symbol.as_str()))?;
+            statement.bind((2, sample.ts as i64))?;
+            statement.bind((3, sample.open_interest.as_str()))?;
+
+            statement.next()?;
+            statement.reset()?;
+        }
+
+        Ok(())
+    }
+
+    fn retrieve_open_interest(
+        &self,
+        symbol: &str,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> Result<Vec<OpenInterest>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_OPEN_INTEREST_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, start_time.timestamp_millis()))?;
+        statement.bind((3, end_time.timestamp_millis()))?;
+
+        let mut samples = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            samples.push(OpenInterest {
+                symbol: statement.read::<String, _>(0)?,
+                ts: statement.read::<i64, _>(1)? as u64,
+                open_interest: statement.read::<String, _>(2)?,
+            });
+        }
+
+        Ok(samples)
+    }
+
+    fn trade_symbols(&self) -> Result<Vec<String>, StorageError> {
+        let mut statement = self.connection.prepare(RETRIEVE_TRADE_SYMBOLS_SQL)?;
+
+        let mut symbols = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            symbols.push(statement.read::<String, _>(0)?);
+        }
+
+        Ok(symbols)
+    }
+
+    fn delete_trades_before(
+        &mut self,
+        symbol: &str,
+        before: &DateTime<Utc>,
+    ) -> Result<usize, StorageError> {
+        let mut statement = self.connection.prepare(DELETE_TRADES_BEFORE_SQL)?;
+
+        statement.bind((1, symbol))?;
+        statement.bind((2, before.timestamp_millis()))?;
+        statement.next()?;
+
+        Ok(self.connection.change_count())
+    }
+
+    /// Frees a bounded number of pages, so a purge never rewrites the whole file
+    fn compact(&mut self) -> Result<(), StorageError> {
+        self.connection.execute(INCREMENTAL_VACUUM_SQL)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{kline, trade};
+
+    #[test]
+    fn upsert_replaces_and_latest_returns_newest() {
+        let mut db = SqliteStorage::new(SQLX_ADDR).unwrap();
+        let kline = |utc_begin, close| {
+            kline("BTC_USDT", TimeFrame::Minutes15, utc_begin, utc_begin + 900)
+                .prices(1.0, 2.0, 0.5, close)
+                .volume(1.0, 2.0, 3.0, 4.0)
+                .build()
+        };
+
+        db.insert_kline(&kline(0, 1.0)).unwrap();
+        db.insert_kline(&kline(900, 1.0)).unwrap();
+        assert!(db.insert_kline(&kline(900, 1.5)).is_err());
+
+        db.upsert_kline(&kline(900, 1.5)).unwrap();
+
+        let latest = db
+            .latest_kline("BTC_USDT", &TimeFrame::Minutes15)
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest, kline(900, 1.5));
+
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let end = DateTime::from_timestamp(900, 0).unwrap();
+        let klines = db
+            .retrieve_klines_in_interval("BTC_USDT", &TimeFrame::Minutes15, &start, &end, None)
+            .unwrap();
+        assert_eq!(klines, vec![kline(0, 1.0)]);
+
+        let end = DateTime::from_timestamp(1800, 0).unwrap();
+        let klines = db
+            .retrieve_klines_in_interval("BTC_USDT", &TimeFrame::Minutes15, &start, &end, Some(1))
+            .unwrap();
+        assert_eq!(klines, vec![kline(0, 1.0)]);
+    }
+
+    #[test]
+    fn replayed_trades_are_counted_as_duplicates() {
+        let mut db = SqliteStorage::new(SQLX_ADDR).unwrap();
+        let trade = |id| trade(id, "BTC_USDT", "buy", "1", 1);
+
+        db.insert_recent_trades(&[trade("1"), trade("2")]).unwrap();
+        let result = db
+            .insert_recent_trades(&[trade("2"), trade("3"), trade("1")])
+            .unwrap();
+
+        assert_eq!(
+            result,
+            InsertedTrades {
+                inserted: 1,
+                duplicates: 2
+            }
+        );
+    }
+
+    #[test]
+    fn funding_rates_are_replaced_and_open_interest_deduplicated() {
+        let mut db = SqliteStorage::new(SQLX_ADDR).unwrap();
+        let rate = |rate: &str, funding_time: u64, ts: u64| FundingRate {
+            symbol: "BTC_USDT_PERP".to_string(),
+            rate: rate.to_string(),
+            funding_time,
+            ts,
+        };
+
+        db.upsert_funding_rates(&[
+            rate("0.0001", 28_800_000, 1_000),
+            rate("0.0002", 57_600_000, 2_000),
+        ])
+        .unwrap();
+        // the prediction for the first period is settled with a different rate
+        db.upsert_funding_rates(&[rate("0.00015", 28_800_000, 28_800_000)])
+            .unwrap();
+
+        let start = DateTime::from_timestamp_millis(0).unwrap();
+        let end = DateTime::from_timestamp_millis(57_600_000).unwrap();
+        let rates = db
+            .retrieve_funding_rates("BTC_USDT_PERP", &start, &end)
+            .unwrap();
+        assert_eq!(rates, vec![rate("0.00015", 28_800_000, 28_800_000)]);
+
+        let sample = OpenInterest {
+            symbol: "BTC_USDT_PERP".to_string(),
+            open_interest: "1520.5".to_string(),
+            ts: 1_000,
+        };
+        db.insert_open_interest(&[sample.clone(), sample.clone()])
+            .unwrap();
+        let samples = db
+            .retrieve_open_interest("BTC_USDT_PERP", &start, &end)
+            .unwrap();
+        assert_eq!(samples, vec![sample]);
+    }
+
+    #[test]
+    fn klines_table_without_source_is_migrated() {
+        let path = std::env::temp_dir().join(format!("klines-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = path.to_str().unwrap();
+
+        let legacy = sqlite::open(addr).unwrap();
+        legacy
+            .execute(
+                CREATE_KLINES_TABLE_SQL.replace("source TEXT NOT NULL DEFAULT 'aggregated',", ""),
+            )
+            .unwrap();
+        drop(legacy);
+
+        let mut db = SqliteStorage::new(addr).unwrap();
+        let exchange = kline("BTC_USDT", TimeFrame::Minutes15, 0, 900)
+            .source(KlineSource::Exchange)
+            .build();
+        db.insert_kline(&exchange).unwrap();
+
+        let latest = db.latest_kline("BTC_USDT", &TimeFrame::Minutes15).unwrap();
+        let auto_vacuum = db.auto_vacuum().unwrap();
+        db.compact().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(latest, Some(exchange));
+        assert_eq!(auto_vacuum, AUTO_VACUUM_INCREMENTAL);
+    }
+}
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::client::models::RawKLHistory;
+use crate::common::models::{FundingRate, Kline, OpenInterest, TimeFrame, Trade};
+use crate::metrics::metrics;
+
+use super::{Storage, StorageError};
+
+pub const STORAGE_QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut dyn Storage) + Send>;
+
+/// Async facade over a `Storage` that lives on its own thread.
+///
+/// Every call is queued into a bounded channel and executed by the writer thread in order,
+/// so tokio tasks never block on disk I/O. They only wait when the queue is full.
+/// The thread stops once every handle is dropped
+#[derive(Clone)]
+pub struct StorageHandle {
+    sender: mpsc::Sender<Job>,
+    duplicate_trades: Arc<AtomicU64>,
+    /// Set while the latest trade batch failed to be stored
+    write_failing: Arc<AtomicBool>,
+}
+
+impl StorageHandle {
+    /// `open` runs on the writer thread, so blocking clients can be created there
+    pub async fn spawn<F>(capacity: usize, open: F) -> Result<Self, StorageError>
+    where
+        F: FnOnce() -> Result<Box<dyn Storage>, StorageError> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Job>(capacity);
+        let (opened_tx, opened_rx) = oneshot::channel();
+
+        thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn(move || {
+                let mut storage = match open() {
+                    Ok(storage) => {
+                        let _ = opened_tx.send(Ok(()));
+                        storage
+                    }
+                    Err(err) => {
+                        let _ = opened_tx.send(Err(err));
+                        return;
+                    }
+                };
+
+                while let Some(job) = receiver.blocking_recv() {
+                    job(storage.as_mut());
+                }
+
+                tracing::info!("Storage writer stopped");
+            })
+            .map_err(|err| StorageError::InvalidData(err.to_string()))?;
+
+        opened_rx.await.map_err(|_| StorageError::WriterClosed)??;
+
+        Ok(Self {
+            sender,
+            duplicate_trades: Arc::new(AtomicU64::new(0)),
+            write_failing: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Runs `f` on the writer thread and waits for its result
+    pub async fn call<R, F>(&self, f: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut dyn Storage) -> Result<R, StorageError> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |storage| {
+            let _ = tx.send(f(storage));
+        });
+
+        self.sender
+            .send(job)
+            .await
+            .map_err(|_| StorageError::WriterClosed)?;
+
+        rx.await.map_err(|_| StorageError::WriterClosed)?
+    }
+
+    /// Queues the trades without waiting for the write, failures are logged by the writer
+    pub async fn insert_recent_trades(&self, trades: Vec<Trade>) -> Result<(), StorageError> {
+        let duplicate_trades = self.duplicate_trades.clone();
+        let write_failing = self.write_failing.clone();
+        let job: Job = Box::new(move |storage| {
+            let timer = metrics().db_insert_latency.start_timer();
+            let inserted = storage.insert_recent_trades(&trades);
+            timer.observe_duration();
+
+            write_failing.store(inserted.is_err(), Ordering::Relaxed);
+            match inserted {
+                Ok(result) => {
+                    if result.duplicates > 0 {
+                        duplicate_trades.fetch_add(result.duplicates as u64, Ordering::Relaxed);
+                        metrics().trades_duplicate.inc_by(result.duplicates as u64);
+                        tracing::debug!("Skipped {} duplicate trades", result.duplicates);
+                    }
+                }
+                Err(err) => tracing::error!("Failed to store {} trades: {}", trades.len(), err),
+            }
+        });
+
+        self.sender
+            .send(job)
+            .await
+            .map_err(|_| StorageError::WriterClosed)
+    }
+
+    /// Total number of already stored trades skipped by `insert_recent_trades`
+    pub fn duplicate_trades(&self) -> u64 {
+        self.duplicate_trades.load(Ordering::Relaxed)
+    }
+
+    /// Whether the latest `insert_recent_trades` batch failed
+    pub fn write_failing(&self) -> bool {
+        self.write_failing.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once everything queued before it has been executed
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        self.call(|_| Ok(())).await
+    }
+
+    pub async fn insert_kline(&self, kline: Kline) -> Result<(), StorageError> {
+        self.call(move |storage| storage.insert_kline(&kline)).await
+    }
+
+    pub async fn upsert_kline(&self, kline: Kline) -> Result<(), StorageError> {
+        self.call(move |storage| storage.upsert_kline(&kline)).await
+    }
+
+    pub async fn insert_candles(
+        &self,
+        symbol: String,
+        data: RawKLHistory,
+    ) -> Result<(), StorageError> {
+        self.call(move |storage| storage.insert_candles(&symbol, &data))
+            .await
+    }
+
+    pub async fn retrieve_trades_in_interval(
+        &self,
+        symbol: Option<String>,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Trade>, StorageError> {
+        self.call(move |storage| {
+            storage.retrieve_trades_in_interval(symbol.as_deref(), &start_time, &end_time, limit)
+        })
+        .await
+    }
+
+    pub async fn retrieve_klines_in_interval(
+        &self,
+        symbol: String,
+        timeframe: TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Kline>, StorageError> {
+        self.call(move |storage| {
+            storage.retrieve_klines_in_interval(&symbol, &timeframe, &start_time, &end_time, limit)
+        })
+        .await
+    }
+
+    pub async fn upsert_funding_rates(&self, rates: Vec<FundingRate>) -> Result<(), StorageError> {
+        self.call(move |storage| storage.upsert_funding_rates(&rates))
+            .await
+    }
+
+    pub async fn retrieve_funding_rates(
+        &self,
+        symbol: String,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<FundingRate>, StorageError> {
+        self.call(move |storage| storage.retrieve_funding_rates(&symbol, &start_time, &end_time))
+            .await
+    }
+
+    pub async fn insert_open_interest(
+        &self,
+        samples: Vec<OpenInterest>,
+    ) -> Result<(), StorageError> {
+        self.call(move |storage| storage.insert_open_interest(&samples))
+            .await
+    }
+
+    pub async fn retrieve_open_interest(
+        &self,
+        symbol: String,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<OpenInterest>, StorageError> {
+        self.call(move |storage| storage.retrieve_open_interest(&symbol, &start_time, &end_time))
+            .await
+    }
+
+    pub async fn latest_kline(
+        &self,
+        symbol: String,
+        timeframe: TimeFrame,
+    ) -> Result<Option<Kline>, StorageError> {
+        self.call(move |storage| storage.latest_kline(&symbol, &timeframe))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::MemoryStorage;
+
+    #[tokio::test]
+    async fn queued_trades_are_visible_after_flush() {
+        let db = StorageHandle::spawn(1, || Ok(Box::new(MemoryStorage::new()) as Box<dyn Storage>))
+            .await
+            .unwrap();
+
+        for id in [0, 1, 2, 1] {
+            let trade = Trade {
+                symbol: "BTC_USDT".to_string(),
+                amount: "1".to_string(),
+                taker_side: "buy".to_string(),
+                quantity: "1".to_string(),
+                create_time: id,
+                price: "1".to_string(),
+                id: id.to_string(),
+                ts: id,
+            };
+            db.insert_recent_trades(vec![trade]).await.unwrap();
+        }
+        db.flush().await.unwrap();
+
+        let start = DateTime::from_timestamp_millis(0).unwrap();
+        let end = DateTime::from_timestamp_millis(10).unwrap();
+        let trades = db
+            .retrieve_trades_in_interval(None, start, end, None)
+            .await
+            .unwrap();
+        assert_eq!(trades.len(), 3);
+        assert_eq!(db.duplicate_trades(), 1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    common::models::{Kline, TimeFrame},
+    SharedState,
+};
+
+/// Market listed by an exchange, `symbol` uses the collector's `BASE_QUOTE` form
+#[derive(Debug, Clone, PartialEq)]
+pub struct Market {
+    pub symbol: String,
+    pub base: String,
+    pub quote: String,
+    /// False while trading is halted
+    pub trading: bool,
+}
+
+#[derive(Debug)]
+pub enum ExchangeError {
+    Http(reqwest::Error),
+    WebSocket(tungstenite::Error),
+    /// The exchange answered with something that couldn't be decoded
+    Decode(serde_json::Error),
+    /// The venue doesn't offer what was asked for, e.g. a channel it has no stream for
+    Unsupported(String),
+}
+
+impl fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExchangeError::Http(err) => write!(f, "request failed: {}", err),
+            ExchangeError::WebSocket(err) => write!(f, "stream failed: {}", err),
+            ExchangeError::Decode(err) => write!(f, "unexpected response: {}", err),
+            ExchangeError::Unsupported(what) => write!(f, "not supported: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for ExchangeError {}
+
+impl From<reqwest::Error> for ExchangeError {
+    fn from(err: reqwest::Error) -> Self {
+        ExchangeError::Http(err)
+    }
+}
+
+impl From<tungstenite::Error> for ExchangeError {
+    fn from(err: tungstenite::Error) -> Self {
+        ExchangeError::WebSocket(err)
+    }
+}
+
+/// Market data venue the collector ingests from.
+///
+/// Implementations translate symbols and payloads, so everything behind the event bus
+/// only sees normalized `Trade` and `Kline` values
+#[async_trait]
+pub trait Exchange: Send + Sync {
+    /// Lowercase venue name used in logs, e.g. `poloniex`
+    fn name(&self) -> &'static str;
+
+    /// Opens the public market data stream, nothing is received until `subscribe`
+    async fn connect(&self) -> Result<Box<dyn MarketStream>, ExchangeError>;
+
+    /// Exchange candles of `symbol` as klines starting in `[start_time, end_time)`
+    async fn candles(
+        &self,
+        symbol: &str,
+        timeframe: &TimeFrame,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<Kline>, ExchangeError>;
+
+    async fn markets(&self) -> Result<Vec<Market>, ExchangeError>;
+}
+
+/// Live connection returned by `Exchange::connect`
+#[async_trait]
+pub trait MarketStream: Send + Sync {
+    /// `channels` use the collector's names, `trades` and `book`
+    async fn subscribe(&self, channels: &[String], symbols: &[String])
+        -> Result<(), ExchangeError>;
+
+    async fn subscribe_trades(&self, symbols: &[String]) -> Result<(), ExchangeError> {
+        self.subscribe(&["trades".to_string()], symbols).await
+    }
+
+    /// Publishes normalized events on the bus and stores trades until the stream ends
+    /// or `shutdown` fires. Keep-alive messages the venue needs are sent meanwhile
+    fn run(self: Box<Self>, state: SharedState, shutdown: watch::Receiver<bool>) -> JoinHandle<()>;
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeSet;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::{
+    bus::{Delivery, Event, KlineUpdate, RecvError, Topic},
+    common::models::{TimeFrame, Trade},
+    SharedState,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Trades,
+    /// Forming and finalized klines from the aggregator
+    Klines,
+}
+
+/// Requests sent by downstream consumers, modelled after the Poloniex subscription messages
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Subscribe {
+        channel: Channel,
+        symbols: Vec<String>,
+        #[serde(default)]
+        timeframes: Vec<TimeFrame>,
+    },
+    Unsubscribe {
+        channel: Channel,
+        symbols: Vec<String>,
+        #[serde(default)]
+        timeframes: Vec<TimeFrame>,
+    },
+    Ping,
+}
+
+#[derive(Clone)]
+struct FanoutState {
+    state: SharedState,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// `GET /ws` re-publishes live trades and kline updates to subscribed clients,
+/// so they share the collector's upstream connection
+pub fn router(state: SharedState, shutdown: watch::Receiver<bool>) -> Router {
+    Router::new()
+        .route("/ws", get(upgrade))
+        .with_state(FanoutState { state, shutdown })
+}
+
+async fn upgrade(State(fanout): State<FanoutState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve_client(socket, fanout))
+}
+
+async fn serve_client(mut socket: WebSocket, fanout: FanoutState) {
+    let FanoutState {
+        state,
+        mut shutdown,
+    } = fanout;
+    let mut events = state.bus.subscribe(
+        "fan-out client",
+        &[Topic::Trades, Topic::Klines],
+        Delivery::Lossy,
+    );
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        let outgoing = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => subscriptions.handle(&text),
+                // pings are answered by axum itself
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            event = events.recv() => match event {
+                Ok(Event::Trade(trade)) => subscriptions.on_trade(trade),
+                Ok(Event::Kline(update)) => subscriptions.on_kline(update),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => vec![lagged(missed)],
+                Err(RecvError::Closed) => break,
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+
+        for message in outgoing {
+            if socket.send(Message::Text(message.into())).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+fn lagged(missed: u64) -> String {
+    tracing::warn!("Fan-out client missed {} messages", missed);
+    json!({ "event": "error", "message": format!("missed {} messages", missed) }).to_string()
+}
+
+/// Channels one client is subscribed to, turns bus events into what the client receives
+#[derive(Default)]
+pub struct Subscriptions {
+    trades: BTreeSet<String>,
+    klines: BTreeSet<(String, TimeFrame)>,
+}
+
+impl Subscriptions {
+    /// Applies a client request, returns the confirmation or error to send back
+    pub fn handle(&mut self, text: &str) -> Vec<String> {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(err) => {
+                return vec![json!({ "event": "error", "message": err.to_string() }).to_string()]
+            }
+        };
+
+        let (event, channel, symbols, timeframes) = match message {
+            ClientMessage::Ping => return vec![json!({ "event": "pong" }).to_string()],
+            ClientMessage::Subscribe {
+                channel,
+                symbols,
+                timeframes,
+            } => {
+                match channel {
+                    Channel::Trades => self.trades.extend(symbols.iter().cloned()),
+                    Channel::Klines if timeframes.is_empty() => {
+                        return vec![json!({
+                            "event": "error",
+                            "message": "klines subscription requires timeframes"
+                        })
+                        .to_string()]
+                    }
+                    Channel::Klines => {
+                        for symbol in &symbols {
+                            for timeframe in &timeframes {
+                                self.klines.insert((symbol.clone(), timeframe.clone()));
+                            }
+                        }
+                    }
+                }
+                ("subscribe", channel, symbols, timeframes)
+            }
+            ClientMessage::Unsubscribe {
+                channel,
+                symbols,
+                timeframes,
+            } => {
+                match channel {
+                    Channel::Trades => self.trades.retain(|s| !symbols.contains(s)),
+                    // no timeframes drops every timeframe of the symbols
+                    Channel::Klines => self.klines.retain(|(symbol, timeframe)| {
+                        !symbols.contains(symbol)
+                            || !(timeframes.is_empty() || timeframes.contains(timeframe))
+                    }),
+                }
+                ("unsubscribe", channel, symbols, timeframes)
+            }
+        };
+
+        vec![json!({
+            "event": event,
+            "channel": channel,
+            "symbols": symbols,
+            "timeframes": timeframes,
+        })
+        .to_string()]
+    }
+
+    pub fn on_trade(&self, trade: Trade) -> Vec<String> {
+        if self.trades.contains(&trade.symbol) {
+            vec![json!({ "channel": "trades", "data": trade }).to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn on_kline(&self, update: KlineUpdate) -> Vec<String> {
+        let key = (update.kline.pair.clone(), update.kline.timeframe.clone());
+        if self.klines.contains(&key) {
+            vec![json!({
+                "channel": "klines",
+                "isFinal": update.is_final,
+                "data": update.kline
+            })
+            .to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{connect_async, tungstenite};
+
+    use super::*;
+    use crate::{
+        common::{models::Kline, utils::make_kline_from_trades},
+        stream::WsStream,
+        test_support::{memory_state, trade},
+    };
+
+    fn kline(symbol: &str, timeframe: TimeFrame) -> Kline {
+        make_kline_from_trades(
+            vec![trade("2000", symbol, "sell", "2", 2_000)],
+            timeframe,
+            chrono::DateTime::from_timestamp(0, 0).unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn next(client: &mut WsStream) -> Value {
+        loop {
+            if let tungstenite::Message::Text(text) = client.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn klines_need_timeframes_and_unsubscribe_removes_them() {
+        let mut subscriptions = Subscriptions::default();
+        let update = |timeframe| KlineUpdate {
+            kline: kline("BTC_USDT", timeframe),
+            is_final: false,
+        };
+
+        let reply = subscriptions
+            .handle(r#"{"event":"subscribe","channel":"klines","symbols":["BTC_USDT"]}"#);
+        assert!(reply[0].contains("requires timeframes"));
+
+        subscriptions.handle(
+            r#"{"event":"subscribe","channel":"klines","symbols":["BTC_USDT"],"timeframes":["15m","1h"]}"#,
+        );
+        assert_eq!(subscriptions.on_kline(update(TimeFrame::Hour)).len(), 1);
+
+        subscriptions.handle(
+            r#"{"event":"unsubscribe","channel":"klines","symbols":["BTC_USDT"],"timeframes":["1h"]}"#,
+        );
+        assert!(subscriptions.on_kline(update(TimeFrame::Hour)).is_empty());
+        assert_eq!(
+            subscriptions.on_kline(update(TimeFrame::Minutes15)).len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_receive_only_subscribed_symbols() {
+        let state = memory_state().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(crate::api::serve(
+            listener,
+            router(state.clone(), shutdown_rx.clone()),
+            shutdown_rx,
+        ));
+
+        let (mut client, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        let trades = r#"{"event":"subscribe","channel":"trades","symbols":["BTC_USDT"]}"#;
+        let klines = r#"{"event":"subscribe","channel":"klines","symbols":["BTC_USDT"],"timeframes":["1h"]}"#;
+        client.send(trades.into()).await.unwrap();
+        client.send(klines.into()).await.unwrap();
+        assert_eq!(next(&mut client).await["channel"], "trades");
+        assert_eq!(next(&mut client).await["timeframes"][0], "1h");
+
+        state
+            .bus
+            .publish_trades(&[
+                trade("1000", "ETH_USDT", "sell", "3", 1_000),
+                trade("2000", "BTC_USDT", "sell", "2", 2_000),
+            ])
+            .await;
+        let received = next(&mut client).await;
+        assert_eq!(received["channel"], "trades");
+        assert_eq!(received["data"]["price"], "2");
+
+        state
+            .bus
+            .publish_kline(kline("BTC_USDT", TimeFrame::Minutes15), true)
+            .await;
+        state
+            .bus
+            .publish_kline(kline("BTC_USDT", TimeFrame::Hour), false)
+            .await;
+        let forming = next(&mut client).await;
+        assert_eq!(forming["isFinal"], false);
+        assert_eq!(forming["data"]["timeframe"], "1h");
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
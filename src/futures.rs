@@ -0,0 +1,220 @@
+//! Funding rate and open interest history of perpetual futures, collected next to the
+//! trades of the `poloniex_futures` stream
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::{sync::watch, time::sleep};
+
+use crate::{
+    bus::{Event, RecvError, Subscription},
+    client::rest::PoloniexRest,
+    common::models::FundingRate,
+    database::StorageError,
+    SharedState,
+};
+
+/// Stores funding rates published on the bus by the futures stream.
+/// A period is pushed repeatedly while it runs, the latest rate replaces the stored one.
+/// Rates still queued when `stop` fires are stored before returning
+pub async fn store_funding_rates(
+    state: SharedState,
+    mut rates: Subscription,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            event = rates.recv() => match event {
+                Ok(Event::Funding(rate)) => store_funding_rate(&state, rate).await,
+                // reliable subscriptions don't lag
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = stop.changed() => break,
+        }
+    }
+
+    while let Some(event) = rates.try_recv() {
+        if let Event::Funding(rate) = event {
+            store_funding_rate(&state, rate).await;
+        }
+    }
+}
+
+async fn store_funding_rate(state: &SharedState, rate: FundingRate) {
+    if let Err(err) = state.db.upsert_funding_rates(vec![rate]).await {
+        tracing::error!("Failed to store funding rate: {}", err);
+    }
+}
+
+/// Downloads the funding periods of `symbols` settled in `[start, end)`,
+/// so the history has no holes after downtime. Returns how many were stored
+pub async fn backfill_funding_rates(
+    state: &SharedState,
+    rest: &PoloniexRest,
+    symbols: &[String],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<usize, StorageError> {
+    let mut stored = 0;
+    for symbol in symbols {
+        let rates = match rest.funding_rate_history(symbol, start, end).await {
+            Ok(rates) => rates,
+            Err(err) => {
+                tracing::warn!("Failed to fetch {} funding history: {}", symbol, err);
+                continue;
+            }
+        };
+        stored += rates.len();
+        state.db.upsert_funding_rates(rates).await?;
+    }
+    Ok(stored)
+}
+
+/// Samples the open interest of `symbols` over REST, the futures stream doesn't push it
+pub struct OpenInterestPoller {
+    rest: Arc<PoloniexRest>,
+    symbols: Vec<String>,
+    run_every: Duration,
+    state: SharedState,
+}
+
+impl OpenInterestPoller {
+    pub fn new(
+        rest: Arc<PoloniexRest>,
+        symbols: Vec<String>,
+        run_every: Duration,
+        state: SharedState,
+    ) -> Self {
+        Self {
+            rest,
+            symbols,
+            run_every,
+            state,
+        }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            match self.poll().await {
+                Ok(sampled) => tracing::debug!("Took {} open interest samples", sampled),
+                Err(err) => tracing::error!("Failed to store open interest: {}", err),
+            }
+
+            sleep(self.run_every.to_std().unwrap_or_default()).await;
+        }
+    }
+
+    /// Fetches, stores and publishes the current open interest of every symbol,
+    /// returns how many samples were taken
+    pub async fn poll(&self) -> Result<usize, StorageError> {
+        let mut sampled = 0;
+        for symbol in &self.symbols {
+            let samples = match self.rest.open_interest(symbol).await {
+                Ok(samples) => samples,
+                Err(err) => {
+                    tracing::warn!("Failed to fetch {} open interest: {}", symbol, err);
+                    continue;
+                }
+            };
+
+            self.state.db.insert_open_interest(samples.clone()).await?;
+            for sample in samples {
+                sampled += 1;
+                self.state.bus.publish(Event::OpenInterest(sample)).await;
+            }
+        }
+        Ok(sampled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::{Delivery, Topic},
+        common::models::OpenInterest,
+        test_support::{memory_state, MockPoloniex},
+    };
+
+    fn rate(rate: &str, ts: u64) -> FundingRate {
+        FundingRate {
+            symbol: "BTC_USDT_PERP".to_string(),
+            rate: rate.to_string(),
+            funding_time: 1704096000000,
+            ts,
+        }
+    }
+
+    #[tokio::test]
+    async fn latest_published_funding_rate_is_stored() {
+        let state = memory_state().await;
+        let rates = state
+            .bus
+            .subscribe("funding", &[Topic::Funding], Delivery::Reliable);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let writer = tokio::spawn(store_funding_rates(state.clone(), rates, stop_rx));
+
+        state
+            .bus
+            .publish(Event::Funding(rate("0.0001", 1704067200000)))
+            .await;
+        state
+            .bus
+            .publish(Event::Funding(rate("0.00012", 1704067260000)))
+            .await;
+        let _ = stop_tx.send(true);
+        writer.await.unwrap();
+
+        let stored = state
+            .db
+            .retrieve_funding_rates(
+                "BTC_USDT_PERP".to_string(),
+                DateTime::from_timestamp(1704067200, 0).unwrap(),
+                DateTime::from_timestamp(1704153600, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![rate("0.00012", 1704067260000)]);
+    }
+
+    #[tokio::test]
+    async fn open_interest_is_stored_and_published() {
+        let mock = MockPoloniex::start().await;
+        let state = memory_state().await;
+        let mut events = state
+            .bus
+            .subscribe("test", &[Topic::OpenInterest], Delivery::Lossy);
+        let rest = Arc::new(PoloniexRest::with_endpoint(&mock.rest_endpoint()));
+        let poller = OpenInterestPoller::new(
+            rest,
+            vec!["BTC_USDT_PERP".to_string()],
+            Duration::seconds(60),
+            state.clone(),
+        );
+
+        // a second poll at the same `ts` doesn't add a sample
+        assert_eq!(poller.poll().await.unwrap(), 1);
+        assert_eq!(poller.poll().await.unwrap(), 1);
+
+        let expected = OpenInterest {
+            symbol: "BTC_USDT_PERP".to_string(),
+            open_interest: "1234.5".to_string(),
+            ts: 1704067200000,
+        };
+        assert_eq!(
+            events.recv().await,
+            Ok(Event::OpenInterest(expected.clone()))
+        );
+        let stored = state
+            .db
+            .retrieve_open_interest(
+                "BTC_USDT_PERP".to_string(),
+                DateTime::from_timestamp(1704067200, 0).unwrap(),
+                DateTime::from_timestamp(1704067260, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![expected]);
+    }
+}
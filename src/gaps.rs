@@ -0,0 +1,273 @@
+use std::{fmt, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::time::sleep;
+
+use crate::{
+    aggregator::Aggregator,
+    common::models::{Kline, TimeFrame},
+    database::StorageError,
+    exchange::Exchange,
+    SharedState,
+};
+
+pub struct GapPolicy {
+    /// How far back stored klines are checked
+    pub lookback: Duration,
+    pub run_every: Duration,
+    /// The aggregator's settle delay, windows which closed less than this ago are left to it
+    pub settle_delay: Duration,
+}
+
+/// Consecutive windows without a stored kline
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub symbol: String,
+    pub timeframe: TimeFrame,
+    pub start: DateTime<Utc>,
+    /// Exclusive
+    pub end: DateTime<Utc>,
+    /// Windows filled from exchange candles, the rest had no trades on the exchange either
+    pub filled: usize,
+}
+
+impl Gap {
+    pub fn windows(&self) -> usize {
+        ((self.end - self.start).num_seconds() / self.timeframe.duration().num_seconds()) as usize
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GapReport {
+    pub gaps: Vec<Gap>,
+}
+
+impl GapReport {
+    pub fn missing(&self) -> usize {
+        self.gaps.iter().map(Gap::windows).sum()
+    }
+
+    pub fn filled(&self) -> usize {
+        self.gaps.iter().map(|gap| gap.filled).sum()
+    }
+}
+
+impl fmt::Display for GapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} gaps, {} of {} missing klines filled",
+            self.gaps.len(),
+            self.filled(),
+            self.missing()
+        )?;
+        for gap in &self.gaps {
+            write!(
+                f,
+                "\n  {} {} {} - {}: {} of {} filled",
+                gap.symbol,
+                gap.timeframe.as_ref(),
+                gap.start,
+                gap.end,
+                gap.filled,
+                gap.windows()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Periodically looks for windows without a kline, e.g. after downtime or dropped
+/// WebSocket data, and fills them with exchange candles tagged as `KlineSource::Exchange`
+pub struct GapFiller {
+    policy: GapPolicy,
+    symbols: Vec<String>,
+    timeframes: Vec<TimeFrame>,
+    state: SharedState,
+    exchange: Arc<dyn Exchange>,
+}
+
+impl GapFiller {
+    pub fn new(
+        policy: GapPolicy,
+        symbols: Vec<String>,
+        timeframes: Vec<TimeFrame>,
+        state: SharedState,
+        exchange: Arc<dyn Exchange>,
+    ) -> Self {
+        Self {
+            policy,
+            symbols,
+            timeframes,
+            state,
+            exchange,
+        }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            sleep(self.policy.run_every.to_std().unwrap_or_default()).await;
+
+            match self.fill(Utc::now()).await {
+                Ok(report) if report.gaps.is_empty() => tracing::info!("No kline gaps found"),
+                Ok(report) => tracing::warn!("Kline gap report: {}", report),
+                Err(err) => tracing::error!("Gap detection failed: {}", err),
+            }
+        }
+    }
+
+    /// Scans closed windows within the lookback before `now` and fills what the exchange has
+    pub async fn fill(&self, now: DateTime<Utc>) -> Result<GapReport, StorageError> {
+        let mut report = GapReport::default();
+
+        for timeframe in &self.timeframes {
+            let end = Aggregator::next_boundary(now - self.policy.settle_delay, timeframe)
+                - timeframe.duration();
+            let start = Aggregator::next_boundary(now - self.policy.lookback, timeframe)
+                - timeframe.duration();
+
+            for symbol in &self.symbols {
+                let klines = self
+                    .state
+                    .db
+                    .retrieve_klines_in_interval(
+                        symbol.clone(),
+                        timeframe.clone(),
+                        start,
+                        end,
+                        None,
+                    )
+                    .await?;
+
+                for mut gap in find_gaps(symbol, timeframe, &klines, start, end) {
+                    let candles = match self
+                        .exchange
+                        .candles(symbol, timeframe, gap.start, gap.end)
+                        .await
+                    {
+                        Ok(candles) => candles,
+                        Err(err) => {
+                            tracing::warn!("Failed to fetch {} candles: {}", symbol, err);
+                            Vec::new()
+                        }
+                    };
+
+                    for kline in candles {
+                        self.state.db.insert_kline(kline).await?;
+                        gap.filled += 1;
+                    }
+                    report.gaps.push(gap);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Ranges of windows in `[start, end)` not covered by `klines`, which are ordered by `utc_begin`
+pub fn find_gaps(
+    symbol: &str,
+    timeframe: &TimeFrame,
+    klines: &[Kline],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<Gap> {
+    let length = timeframe.duration();
+    let mut gaps = Vec::new();
+    let mut expected = start;
+
+    let begins = klines
+        .iter()
+        .filter_map(|kline| DateTime::from_timestamp(kline.utc_begin, 0))
+        .chain([end]);
+    for begin in begins {
+        if begin > expected {
+            gaps.push(Gap {
+                symbol: symbol.to_string(),
+                timeframe: timeframe.clone(),
+                start: expected,
+                end: begin.min(end),
+                filled: 0,
+            });
+        }
+        expected = expected.max(begin + length);
+    }
+
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::utils::make_kline_from_candle,
+        test_support::{memory_state, MockPoloniex},
+    };
+
+    fn candle(start: DateTime<Utc>) -> Vec<String> {
+        let start = start.timestamp_millis();
+        ["1", "2", "1", "2", "10", "5", "3"]
+            .iter()
+            .map(|v| v.to_string())
+            .chain([start.to_string(), (start + 3_599_999).to_string()])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn gaps_are_reported_and_filled_from_candles() {
+        let midnight = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        let hour = |h: i64| midnight + Duration::hours(h);
+
+        let mock = MockPoloniex::start().await;
+        // the exchange has no candle for 04:00
+        mock.set_candles("BTC_USDT", vec![candle(hour(1)), candle(hour(2))]);
+
+        let state = memory_state().await;
+        for h in [0, 3, 5] {
+            let kline =
+                make_kline_from_candle("BTC_USDT", TimeFrame::Hour, &candle(hour(h))).unwrap();
+            state.db.insert_kline(kline).await.unwrap();
+        }
+
+        let filler = GapFiller::new(
+            GapPolicy {
+                lookback: Duration::hours(6),
+                run_every: Duration::hours(1),
+                settle_delay: Duration::seconds(2),
+            },
+            vec!["BTC_USDT".to_string()],
+            vec![TimeFrame::Hour],
+            state.clone(),
+            mock.exchange(),
+        );
+        // 06:00 is still open, so windows 00:00 - 05:00 are checked
+        let report = filler.fill(hour(6) + Duration::minutes(30)).await.unwrap();
+
+        let ranges: Vec<(DateTime<Utc>, DateTime<Utc>, usize)> = report
+            .gaps
+            .iter()
+            .map(|gap| (gap.start, gap.end, gap.filled))
+            .collect();
+        assert_eq!(ranges, vec![(hour(1), hour(3), 2), (hour(4), hour(5), 0)]);
+        assert_eq!((report.filled(), report.missing()), (2, 3));
+
+        let stored = state
+            .db
+            .retrieve_klines_in_interval(
+                "BTC_USDT".to_string(),
+                TimeFrame::Hour,
+                hour(0),
+                hour(6),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 5);
+
+        // 06:00 closed a second ago, within the settle delay it is left to the aggregator
+        let report = filler.fill(hour(7) + Duration::seconds(1)).await.unwrap();
+        let starts: Vec<DateTime<Utc>> = report.gaps.iter().map(|gap| gap.start).collect();
+        assert_eq!(starts, vec![hour(4)]);
+    }
+}
@@ -0,0 +1,354 @@
+// every tonic handler returns `Status`, which is large by design
+#![allow(clippy::result_large_err)]
+
+use std::{collections::HashSet, future::ready};
+
+use chrono::Utc;
+use futures_util::{stream::BoxStream, StreamExt};
+use tokio::{net::TcpListener, sync::watch};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    api::{self, ApiError},
+    bus::{Delivery, Event, Topic},
+    common::models::{Kline, KlineSource, TimeFrame, Trade},
+    SharedState,
+};
+
+pub mod proto {
+    tonic::include_proto!("collector.v1");
+}
+
+use proto::collector_server::{Collector, CollectorServer};
+
+/// Typed access to stored data plus live trade and kline subscriptions, see `proto/collector.proto`
+pub struct CollectorService {
+    state: SharedState,
+    max_limit: usize,
+}
+
+impl CollectorService {
+    pub fn new(state: SharedState, max_limit: usize) -> Self {
+        Self { state, max_limit }
+    }
+}
+
+/// Serves the collector service until `shutdown` turns true
+pub async fn serve(
+    listener: TcpListener,
+    service: CollectorService,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), tonic::transport::Error> {
+    Server::builder()
+        .add_service(CollectorServer::new(service))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await
+}
+
+#[tonic::async_trait]
+impl Collector for CollectorService {
+    async fn get_klines(
+        &self,
+        request: Request<proto::GetKlinesRequest>,
+    ) -> Result<Response<proto::GetKlinesResponse>, Status> {
+        let request = request.into_inner();
+        let timeframe = timeframe(request.timeframe)?;
+        if request.symbol.is_empty() {
+            return Err(Status::invalid_argument("symbol is required"));
+        }
+        let (start, end) = api::range(
+            Some(request.from),
+            (request.to != 0).then_some(request.to),
+            Utc::now().timestamp(),
+            |secs| chrono::DateTime::from_timestamp(secs, 0),
+        )?;
+        let limit = api::limit(
+            (request.limit != 0).then_some(request.limit as usize),
+            self.max_limit,
+        )?;
+
+        let db = &self.state.db;
+        let symbol = request.symbol;
+        let page = api::paginate(
+            start,
+            end,
+            limit,
+            |kline: &Kline| kline.utc_begin,
+            |secs| chrono::DateTime::from_timestamp(secs, 0),
+            move |start, end, limit| {
+                db.retrieve_klines_in_interval(symbol.clone(), timeframe.clone(), start, end, limit)
+            },
+        )
+        .await
+        .map_err(ApiError::from)?;
+
+        Ok(Response::new(proto::GetKlinesResponse {
+            klines: page.data.iter().map(proto::Kline::from).collect(),
+            next: page.next,
+        }))
+    }
+
+    async fn get_trades(
+        &self,
+        request: Request<proto::GetTradesRequest>,
+    ) -> Result<Response<proto::GetTradesResponse>, Status> {
+        let request = request.into_inner();
+        let (start, end) = api::range(
+            Some(request.from),
+            (request.to != 0).then_some(request.to),
+            Utc::now().timestamp_millis(),
+            chrono::DateTime::from_timestamp_millis,
+        )?;
+        let limit = api::limit(
+            (request.limit != 0).then_some(request.limit as usize),
+            self.max_limit,
+        )?;
+        let symbol = (!request.symbol.is_empty()).then_some(request.symbol);
+
+        let db = &self.state.db;
+        let page = api::paginate(
+            start,
+            end,
+            limit,
+            |trade: &Trade| trade.ts as i64,
+            chrono::DateTime::from_timestamp_millis,
+            move |start, end, limit| {
+                db.retrieve_trades_in_interval(symbol.clone(), start, end, limit)
+            },
+        )
+        .await
+        .map_err(ApiError::from)?;
+
+        Ok(Response::new(proto::GetTradesResponse {
+            trades: page.data.into_iter().map(proto::Trade::from).collect(),
+            next: page.next,
+        }))
+    }
+
+    type SubscribeTradesStream = BoxStream<'static, Result<proto::Trade, Status>>;
+
+    async fn subscribe_trades(
+        &self,
+        request: Request<proto::SubscribeTradesRequest>,
+    ) -> Result<Response<Self::SubscribeTradesStream>, Status> {
+        let symbols: HashSet<String> = request.into_inner().symbols.into_iter().collect();
+
+        let trades = self
+            .state
+            .bus
+            .subscribe("grpc trades", &[Topic::Trades], Delivery::Lossy)
+            .into_stream()
+            .filter_map(move |event| {
+                ready(match event {
+                    Event::Trade(trade)
+                        if symbols.is_empty() || symbols.contains(&trade.symbol) =>
+                    {
+                        Some(Ok(proto::Trade::from(trade)))
+                    }
+                    _ => None,
+                })
+            });
+
+        Ok(Response::new(trades.boxed()))
+    }
+
+    type SubscribeKlinesStream = BoxStream<'static, Result<proto::KlineUpdate, Status>>;
+
+    async fn subscribe_klines(
+        &self,
+        request: Request<proto::SubscribeKlinesRequest>,
+    ) -> Result<Response<Self::SubscribeKlinesStream>, Status> {
+        let request = request.into_inner();
+        let timeframe = timeframe(request.timeframe)?;
+        let symbol = request.symbol;
+        if symbol.is_empty() {
+            return Err(Status::invalid_argument("symbol is required"));
+        }
+        let include_forming = request.include_forming;
+
+        let updates = self
+            .state
+            .bus
+            .subscribe("grpc klines", &[Topic::Klines], Delivery::Lossy)
+            .into_stream()
+            .filter_map(move |event| {
+                ready(match event {
+                    Event::Kline(update)
+                        if update.kline.pair == symbol
+                            && update.kline.timeframe == timeframe
+                            && (update.is_final || include_forming) =>
+                    {
+                        Some(Ok(proto::KlineUpdate {
+                            kline: Some(proto::Kline::from(&update.kline)),
+                            is_final: update.is_final,
+                        }))
+                    }
+                    _ => None,
+                })
+            });
+
+        Ok(Response::new(updates.boxed()))
+    }
+}
+
+fn timeframe(value: i32) -> Result<TimeFrame, Status> {
+    match proto::TimeFrame::try_from(value) {
+        Ok(proto::TimeFrame::Minutes15) => Ok(TimeFrame::Minutes15),
+        Ok(proto::TimeFrame::Hour) => Ok(TimeFrame::Hour),
+        _ => Err(Status::invalid_argument("timeframe is required")),
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::BadRequest(message) => Status::invalid_argument(message),
+            ApiError::Storage(err) => {
+                tracing::error!("gRPC storage query failed: {}", err);
+                Status::internal("storage error")
+            }
+        }
+    }
+}
+
+impl From<&TimeFrame> for proto::TimeFrame {
+    fn from(timeframe: &TimeFrame) -> Self {
+        match timeframe {
+            TimeFrame::Minutes15 => proto::TimeFrame::Minutes15,
+            TimeFrame::Hour => proto::TimeFrame::Hour,
+        }
+    }
+}
+
+impl From<&Kline> for proto::Kline {
+    fn from(kline: &Kline) -> Self {
+        let source = match kline.source {
+            KlineSource::Aggregated => proto::KlineSource::Aggregated,
+            KlineSource::Exchange => proto::KlineSource::Exchange,
+        };
+
+        Self {
+            symbol: kline.pair.clone(),
+            timeframe: proto::TimeFrame::from(&kline.timeframe).into(),
+            open: kline.open,
+            high: kline.high,
+            low: kline.low,
+            close: kline.close,
+            utc_begin: kline.utc_begin,
+            utc_end: kline.utc_end,
+            volume_bs: Some(proto::Vbs {
+                buy_base: kline.volume_bs.buy_base,
+                sell_base: kline.volume_bs.sell_base,
+                buy_quote: kline.volume_bs.buy_quote,
+                sell_quote: kline.volume_bs.sell_quote,
+            }),
+            source: source.into(),
+        }
+    }
+}
+
+impl From<Trade> for proto::Trade {
+    fn from(trade: Trade) -> Self {
+        Self {
+            symbol: trade.symbol,
+            id: trade.id,
+            price: trade.price,
+            quantity: trade.quantity,
+            amount: trade.amount,
+            taker_side: trade.taker_side,
+            create_time: trade.create_time,
+            ts: trade.ts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::utils::make_kline_from_trades,
+        test_support::{memory_state, trade},
+    };
+    use proto::collector_client::CollectorClient;
+
+    #[tokio::test]
+    async fn klines_are_queried_and_streamed() {
+        let state = memory_state().await;
+        let begin = chrono::DateTime::from_timestamp(0, 0).unwrap();
+        let stored = make_kline_from_trades(
+            vec![trade("1", "BTC_USDT", "buy", "10", 1_000)],
+            TimeFrame::Minutes15,
+            begin,
+        )
+        .unwrap();
+        state.db.insert_kline(stored.clone()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(
+            listener,
+            CollectorService::new(state.clone(), 100),
+            shutdown_rx,
+        ));
+        let mut client = CollectorClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let page = client
+            .get_klines(proto::GetKlinesRequest {
+                symbol: "BTC_USDT".to_string(),
+                timeframe: proto::TimeFrame::Minutes15.into(),
+                from: 0,
+                to: 3600,
+                limit: 0,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(page.klines, vec![proto::Kline::from(&stored)]);
+        assert_eq!(page.next, None);
+
+        let status = client
+            .get_klines(proto::GetKlinesRequest {
+                symbol: "BTC_USDT".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut updates = client
+            .subscribe_klines(proto::SubscribeKlinesRequest {
+                symbol: "BTC_USDT".to_string(),
+                timeframe: proto::TimeFrame::Minutes15.into(),
+                include_forming: true,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        let forming_kline = make_kline_from_trades(
+            vec![trade("2", "BTC_USDT", "buy", "12", 2_000)],
+            TimeFrame::Minutes15,
+            begin,
+        )
+        .unwrap();
+        state.bus.publish_kline(forming_kline, false).await;
+        let forming = updates.message().await.unwrap().unwrap();
+        assert!(!forming.is_final);
+        assert_eq!(forming.kline.unwrap().close, 12.0);
+
+        state.bus.publish_kline(stored.clone(), true).await;
+        let finalized = updates.message().await.unwrap().unwrap();
+        assert!(finalized.is_final);
+        assert_eq!(finalized.kline, Some(proto::Kline::from(&stored)));
+
+        shutdown_tx.send(true).unwrap();
+        drop(updates);
+        server.await.unwrap().unwrap();
+    }
+}
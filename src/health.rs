@@ -0,0 +1,232 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{common::models::TimeFrame, SharedState};
+
+/// Seconds without a trade after which a symbol is reported as stale
+pub const STALE_AFTER_SECS: i64 = 60;
+/// How long `/readyz` waits for the storage writer
+const DB_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Liveness facts recorded by the WebSocket reader and the aggregator
+pub struct Health {
+    started_at: DateTime<Utc>,
+    inner: Mutex<Observed>,
+}
+
+#[derive(Default)]
+struct Observed {
+    last_trades: BTreeMap<String, DateTime<Utc>>,
+    connected: bool,
+    last_pong: Option<DateTime<Utc>>,
+    aggregated: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started_at: Utc::now(),
+            inner: Mutex::new(Observed::default()),
+        }
+    }
+
+    pub fn trade_received(&self, symbol: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_trades.insert(symbol.to_string(), Utc::now());
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.inner.lock().unwrap().connected = connected;
+    }
+
+    pub fn pong_received(&self) {
+        self.inner.lock().unwrap().last_pong = Some(Utc::now());
+    }
+
+    pub fn aggregated(&self, timeframe: &TimeFrame) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .aggregated
+            .insert(timeframe.as_ref().to_string(), Utc::now());
+    }
+
+    /// A symbol without any trade counts as stale once the process has run for `stale_after`
+    pub fn report(
+        &self,
+        symbols: &[String],
+        stale_after: Duration,
+        db_writable: bool,
+        now: DateTime<Utc>,
+    ) -> HealthReport {
+        let inner = self.inner.lock().unwrap();
+        let age = |at: DateTime<Utc>| (now - at).num_milliseconds() as f64 / 1000.0;
+
+        let symbols: Vec<SymbolHealth> = symbols
+            .iter()
+            .map(|symbol| {
+                let last_trade = inner.last_trades.get(symbol).copied();
+                SymbolHealth {
+                    symbol: symbol.clone(),
+                    last_trade_age_secs: last_trade.map(age),
+                    stale: now - last_trade.unwrap_or(self.started_at) > stale_after,
+                }
+            })
+            .collect();
+        let ready = inner.connected && db_writable && symbols.iter().all(|symbol| !symbol.stale);
+
+        HealthReport {
+            ready,
+            connected: inner.connected,
+            last_pong_age_secs: inner.last_pong.map(age),
+            db_writable,
+            symbols,
+            aggregator_last_run: inner
+                .aggregated
+                .iter()
+                .map(|(timeframe, at)| (timeframe.clone(), at.timestamp()))
+                .collect(),
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolHealth {
+    pub symbol: String,
+    pub last_trade_age_secs: Option<f64>,
+    pub stale: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    /// Connected, storage writable and no stale symbol
+    pub ready: bool,
+    pub connected: bool,
+    pub last_pong_age_secs: Option<f64>,
+    pub db_writable: bool,
+    pub symbols: Vec<SymbolHealth>,
+    /// Unix seconds of the latest aggregation run per timeframe
+    pub aggregator_last_run: BTreeMap<String, i64>,
+}
+
+#[derive(Clone)]
+struct HealthState {
+    state: SharedState,
+    symbols: Vec<String>,
+    stale_after: Duration,
+}
+
+/// `GET /healthz` always answers 200 with the report, `GET /readyz` answers 503 unless ready
+pub fn router(state: SharedState, symbols: Vec<String>, stale_after: Duration) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(HealthState {
+            state,
+            symbols,
+            stale_after,
+        })
+}
+
+async fn report(health: &HealthState) -> HealthReport {
+    let db_writable = matches!(
+        tokio::time::timeout(DB_PROBE_TIMEOUT, health.state.db.flush()).await,
+        Ok(Ok(()))
+    ) && !health.state.db.write_failing();
+
+    health
+        .state
+        .health
+        .report(&health.symbols, health.stale_after, db_writable, Utc::now())
+}
+
+async fn healthz(State(health): State<HealthState>) -> Json<HealthReport> {
+    Json(report(&health).await)
+}
+
+async fn readyz(State(health): State<HealthState>) -> impl IntoResponse {
+    let report = report(&health).await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::{net::TcpListener, sync::watch};
+
+    use super::*;
+    use crate::test_support::memory_state;
+
+    #[test]
+    fn symbols_without_recent_trades_are_stale() {
+        let health = Health::new();
+        let symbols = vec!["BTC_USDT".to_string(), "ETH_USDT".to_string()];
+        health.set_connected(true);
+        health.trade_received("BTC_USDT");
+        health.aggregated(&TimeFrame::Minutes15);
+
+        let report = health.report(&symbols, Duration::seconds(60), true, Utc::now());
+        assert!(report.ready);
+        assert!(report.symbols[1].last_trade_age_secs.is_none());
+        assert!(report.aggregator_last_run.contains_key("15m"));
+
+        let later = Utc::now() + Duration::seconds(61);
+        let report = health.report(&symbols, Duration::seconds(60), true, later);
+        assert!(!report.ready);
+        assert!(report.symbols.iter().all(|symbol| symbol.stale));
+
+        health.trade_received("ETH_USDT");
+        health.set_connected(false);
+        let report = health.report(&symbols[1..], Duration::seconds(60), true, Utc::now());
+        assert!(!report.symbols[0].stale);
+        assert!(!report.ready);
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_the_connection_state() {
+        let state = memory_state().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(crate::api::serve(
+            listener,
+            router(
+                state.clone(),
+                vec!["BTC_USDT".to_string()],
+                Duration::seconds(60),
+            ),
+            shutdown_rx,
+        ));
+
+        let readyz = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+        assert_eq!(readyz.status().as_u16(), 503);
+
+        state.health.set_connected(true);
+        state.health.trade_received("BTC_USDT");
+        let readyz = reqwest::get(format!("{}/readyz", base)).await.unwrap();
+        assert_eq!(readyz.status().as_u16(), 200);
+
+        let healthz = reqwest::get(format!("{}/healthz", base)).await.unwrap();
+        let body: Value = serde_json::from_str(&healthz.text().await.unwrap()).unwrap();
+        assert_eq!(body["dbWritable"], true);
+        assert_eq!(body["symbols"][0]["stale"], false);
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
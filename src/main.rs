@@ -1,67 +1,447 @@
 pub mod aggregator;
+pub mod api;
+pub mod bus;
 pub mod client;
 pub mod common;
+pub mod config;
 pub mod database;
+pub mod exchange;
+pub mod fanout;
+pub mod futures;
+pub mod gaps;
+pub mod grpc;
+pub mod health;
+pub mod metrics;
+pub mod reconciliation;
+pub mod retention;
+pub mod stream;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
 
 use crate::client::ws::PoloniexWs;
+use aggregator::Aggregator;
+use bus::{Delivery, EventBus, Topic};
+use chrono::{DateTime, Duration};
 use client::{
-    models::{PoloniexKLineIntervals, PoloniexRequest},
+    binance::{rest::BinanceRest, ws::BinanceWs, Binance},
+    poloniex::Poloniex,
+    recording::Replay,
     rest::PoloniexRest,
 };
-use database::Database;
+use config::{Config, DatabaseBackend, DatabaseConfig, ExchangeKind};
+use database::{SqliteStorage, Storage, StorageError, StorageHandle};
+use exchange::Exchange;
+use futures::OpenInterestPoller;
+use gaps::{GapFiller, GapPolicy};
+use health::Health;
+use reconciliation::Tolerance;
+use retention::{Retention, RetentionPolicy};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use stream::TradeBufferConfig;
+use tokio::sync::watch;
 
 pub struct State {
-    db: Database,
+    db: StorageHandle,
+    bus: EventBus,
+    health: Health,
 }
 
-type SharedState = Arc<Mutex<State>>;
+impl State {
+    pub fn new(db: StorageHandle) -> Self {
+        Self {
+            db,
+            bus: EventBus::default(),
+            health: Health::default(),
+        }
+    }
+}
+
+type SharedState = Arc<State>;
+
+/// Exit code of every failure before the collector is running,
+/// `1` is left to reconciliation reporting discrepancies
+const STARTUP_FAILURE: i32 = 2;
+
+/// Logs why the collector can't start and exits with `STARTUP_FAILURE`
+fn startup_failed(message: impl std::fmt::Display) -> ! {
+    tracing::error!("{}", message);
+    std::process::exit(STARTUP_FAILURE)
+}
+
+async fn open_storage(config: &DatabaseConfig) -> Result<StorageHandle, StorageError> {
+    let config = config.clone();
+
+    StorageHandle::spawn(config.queue_size, move || match config.backend {
+        DatabaseBackend::Sqlite => {
+            Ok(Box::new(SqliteStorage::new(&config.path)?) as Box<dyn Storage>)
+        }
+        #[cfg(feature = "postgres")]
+        DatabaseBackend::Postgres => Ok(Box::new(database::PostgresStorage::new(
+            config.url.as_deref().unwrap_or_default(),
+            config.timescale,
+        )?) as Box<dyn Storage>),
+        #[cfg(not(feature = "postgres"))]
+        DatabaseBackend::Postgres => Err(StorageError::InvalidData(
+            "built without the postgres feature".to_string(),
+        )),
+    })
+    .await
+}
+
+/// Feeds a recorded session into storage and builds klines for every window it covers
+async fn replay(path: &str, config: &Config, state: SharedState, buffer_config: TradeBufferConfig) {
+    let replay = match Replay::open(std::path::Path::new(path)).await {
+        Ok(replay) => replay.speed(config.recording.replay_speed),
+        Err(err) => startup_failed(format_args!("Can't read recording {}: {}", path, err)),
+    };
+    let Some((start, end)) = replay.time_range() else {
+        tracing::warn!("Recording {} is empty", path);
+        return;
+    };
+
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    if let Err(err) = replay.run(state.clone(), buffer_config, shutdown_rx).await {
+        tracing::error!("Replay failed: {}", err);
+    }
+    if let Err(err) = state.db.flush().await {
+        tracing::error!("Failed to flush storage: {}", err);
+    }
+
+    for timeframe in &config.timeframes {
+        match Aggregator::aggregate_windows(&state, timeframe.clone(), start, end).await {
+            Ok(klines) => tracing::info!(
+                "Replay produced {} {} klines",
+                klines.len(),
+                timeframe.as_ref()
+            ),
+            Err(err) => tracing::error!("Failed to aggregate replay: {}", err),
+        }
+    }
+}
+
+/// Logs a report for every configured symbol and timeframe, returns whether all of them matched
+async fn reconcile(
+    config: &Config,
+    state: &SharedState,
+    exchange: &dyn Exchange,
+    from: i64,
+    to: i64,
+) -> bool {
+    let start = DateTime::from_timestamp(from, 0).unwrap_or_default();
+    let end = DateTime::from_timestamp(to, 0).unwrap_or_default();
+    let tolerance = Tolerance {
+        price: config.reconciliation.price_tolerance,
+        volume: config.reconciliation.volume_tolerance,
+    };
+
+    let mut clean = true;
+    for symbol in &config.symbols {
+        for timeframe in &config.timeframes {
+            match reconciliation::reconcile(
+                state, exchange, symbol, timeframe, start, end, tolerance,
+            )
+            .await
+            {
+                Ok(report) if report.is_clean() => tracing::info!("{}", report),
+                Ok(report) => {
+                    clean = false;
+                    tracing::warn!("{}", report);
+                }
+                Err(err) => {
+                    clean = false;
+                    tracing::error!(
+                        "Failed to reconcile {} {}: {}",
+                        symbol,
+                        timeframe.as_ref(),
+                        err
+                    );
+                }
+            }
+        }
+    }
+    clean
+}
 
 #[tokio::main]
 async fn main() {
-    // simple logging
-    let _ = tracing_subscriber::fmt::init();
-    tracing::info!("Running the system");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing_subscriber::fmt::init();
+            startup_failed(err)
+        }
+    };
+    let telemetry = match telemetry::init(&config.logging) {
+        Ok(telemetry) => telemetry,
+        Err(err) => {
+            // the configured subscriber couldn't be installed, fall back to the default one
+            let _ = tracing_subscriber::fmt().try_init();
+            startup_failed(err)
+        }
+    };
+    tracing::info!(symbols = ?config.symbols, "Running the system");
+
+    let db = match open_storage(&config.database).await {
+        Ok(db) => db,
+        Err(err) => startup_failed(format_args!("Can't open storage: {}", err)),
+    };
+    let shared_state: SharedState = Arc::new(State::new(db));
+
+    let buffer_config = TradeBufferConfig {
+        max_trades: config.buffer.max_trades,
+        max_latency: std::time::Duration::from_millis(config.buffer.max_latency_ms),
+    };
+
+    if let Some(path) = &config.recording.replay_from {
+        replay(path, &config, shared_state, buffer_config).await;
+        return;
+    }
+
+    let endpoints = &config.endpoints;
+    let timeout = std::time::Duration::from_millis(endpoints.timeout_ms);
+    // perpetuals are served by the same clients on their own endpoints
+    let (rest_endpoint, ws_endpoint) = match config.exchange {
+        ExchangeKind::PoloniexFutures => (&config.futures.rest, &config.futures.ws),
+        _ => (&endpoints.rest, &endpoints.ws),
+    };
+
+    let mut ws_builder = PoloniexWs::builder()
+        .endpoint(ws_endpoint)
+        .connect_timeout(timeout)
+        .accept_invalid_certs(endpoints.accept_invalid_certs);
+    let mut rest_builder = PoloniexRest::builder()
+        .endpoint(rest_endpoint)
+        .timeout(timeout)
+        .accept_invalid_certs(endpoints.accept_invalid_certs);
+    if let Some(proxy) = &endpoints.proxy {
+        ws_builder = ws_builder.proxy(proxy);
+        rest_builder = rest_builder.proxy(proxy);
+    }
+    if let Some(path) = &config.recording.record_to {
+        ws_builder = ws_builder.record_to(path);
+    }
+
+    // backfill and the futures history use the Poloniex client directly,
+    // everything else goes through `Exchange`
+    let rest = match rest_builder.build() {
+        Ok(rest) => Arc::new(rest),
+        Err(err) => startup_failed(format_args!("Can't build the REST client: {}", err)),
+    };
+    let exchange: Arc<dyn Exchange> = match config.exchange {
+        ExchangeKind::Poloniex | ExchangeKind::PoloniexFutures => {
+            Arc::new(Poloniex::new(rest.clone(), ws_builder).with_buffer_config(buffer_config))
+        }
+        ExchangeKind::Binance => {
+            let mut rest_builder = BinanceRest::builder()
+                .endpoint(&config.binance.rest)
+                .timeout(timeout)
+                .accept_invalid_certs(endpoints.accept_invalid_certs);
+            let mut ws_builder = BinanceWs::builder()
+                .endpoint(&config.binance.ws)
+                .connect_timeout(timeout)
+                .accept_invalid_certs(endpoints.accept_invalid_certs);
+            if let Some(proxy) = &endpoints.proxy {
+                rest_builder = rest_builder.proxy(proxy);
+                ws_builder = ws_builder.proxy(proxy);
+            }
+            let rest = match rest_builder.build() {
+                Ok(rest) => Arc::new(rest),
+                Err(err) => startup_failed(format_args!("Can't build the REST client: {}", err)),
+            };
+            Arc::new(Binance::new(rest, ws_builder).with_buffer_config(buffer_config))
+        }
+    };
+    if let (Some(from), Some(to)) = (config.reconciliation.from, config.reconciliation.to) {
+        let clean = reconcile(&config, &shared_state, exchange.as_ref(), from, to).await;
+        std::process::exit(if clean { 0 } else { 1 });
+    }
+
+    let stream = match exchange.connect().await {
+        Ok(stream) => stream,
+        Err(err) => startup_failed(format_args!(
+            "Can't connect to {}: {}",
+            exchange.name(),
+            err
+        )),
+    };
+    if let Err(err) = stream.subscribe(&config.channels(), &config.symbols).await {
+        tracing::error!(exchange = exchange.name(), error = %err, "Failed to subscribe");
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    // subscribed before the stream runs, so no funding rate is missed
+    let funding_store = (config.exchange == ExchangeKind::PoloniexFutures).then(|| {
+        let rates = shared_state
+            .bus
+            .subscribe("funding", &[Topic::Funding], Delivery::Reliable);
+        tokio::spawn(futures::store_funding_rates(
+            shared_state.clone(),
+            rates,
+            shutdown_rx.clone(),
+        ))
+    });
+    let reader = stream.run(shared_state.clone(), shutdown_rx.clone());
+
+    if config.exchange == ExchangeKind::PoloniexFutures {
+        if config.futures.funding_history_days > 0 {
+            let end = chrono::Utc::now();
+            let start = end - Duration::days(config.futures.funding_history_days);
+            match futures::backfill_funding_rates(&shared_state, &rest, &config.symbols, start, end)
+                .await
+            {
+                Ok(stored) => tracing::info!("Downloaded {} funding rates", stored),
+                Err(err) => tracing::error!("Failed to store funding history: {}", err),
+            }
+        }
+
+        let poller = OpenInterestPoller::new(
+            rest.clone(),
+            config.symbols.clone(),
+            Duration::seconds(config.futures.open_interest_every_secs),
+            shared_state.clone(),
+        );
+        tokio::spawn(async move { poller.run().await });
+    }
 
-    let kline_start_time = 1733011200;
-    let kline_end_time = 1735689599;
+    let aggregator = Aggregator::new(config.timeframes.clone(), shared_state.clone())
+        .with_settle_delay(Duration::milliseconds(
+            config.aggregator.settle_delay_ms as i64,
+        ))
+        .with_symbols(config.symbols.clone())
+        .with_exchange(exchange.clone())
+        .with_max_catch_up(Duration::hours(config.aggregator.max_catch_up_hours));
+    tokio::spawn(async move { aggregator.run().await });
 
-    // The easiest db to setup
-    let state = State {
-        db: Database::new(),
+    let retention_policy = RetentionPolicy {
+        keep_trades_for: Duration::days(config.retention.keep_trades_days),
+        run_every: Duration::seconds(config.retention.run_every_secs),
     };
-    let shared_state: SharedState = Arc::new(Mutex::new(state));
-
-    // DOGE_USDC and BCH_USDC requests result in error. They are not available in Poloniex
-    let symbols: Vec<String> = vec!["BTC_USDT", "TRX_USDT", "ETH_USDT"]
-        .iter()
-        .map(|sym| sym.to_string())
-        .collect();
-
-    let ws = PoloniexWs::new().await.unwrap();
-    ws.subscribe(vec!["trades".to_string()], symbols.clone())
-        .await;
-    ws.read_and_store(shared_state.clone());
-    ws.init_heartbeat();
-
-    for sym in symbols {
-        let payload = PoloniexRequest::Candles {
-            symbol: sym.clone(),
-            interval: PoloniexKLineIntervals::Week1,
-            start_time: kline_start_time,
-            end_time: kline_end_time,
+    let retention = Retention::new(
+        retention_policy,
+        config.timeframes.clone(),
+        shared_state.clone(),
+    );
+    tokio::spawn(async move { retention.run().await });
+
+    if config.gaps.enabled {
+        let gap_policy = GapPolicy {
+            lookback: Duration::hours(config.gaps.lookback_hours),
+            run_every: Duration::seconds(config.gaps.run_every_secs),
+            settle_delay: Duration::milliseconds(config.aggregator.settle_delay_ms as i64),
         };
-        let rest = PoloniexRest::new();
-        let historical_data = rest.request(payload).await.unwrap();
+        let gap_filler = GapFiller::new(
+            gap_policy,
+            config.symbols.clone(),
+            config.timeframes.clone(),
+            shared_state.clone(),
+            exchange.clone(),
+        );
+        tokio::spawn(async move { gap_filler.run().await });
+    }
+
+    // metrics and health stay reachable when the query API is turned off
+    let api = match tokio::net::TcpListener::bind(&config.api.listen).await {
+        Ok(listener) => {
+            let mut router = api::metrics_router().merge(health::router(
+                shared_state.clone(),
+                config.symbols.clone(),
+                Duration::seconds(config.health.stale_after_secs),
+            ));
+            if config.api.enabled {
+                tracing::info!("Serving the query API on {}", config.api.listen);
+                router = router.merge(api::router(shared_state.clone(), config.api.max_limit));
+                if config.api.fanout {
+                    router =
+                        router.merge(fanout::router(shared_state.clone(), shutdown_rx.clone()));
+                }
+            } else {
+                tracing::info!("Serving metrics and health on {}", config.api.listen);
+            }
+            tokio::spawn(api::serve(listener, router, shutdown_rx.clone()))
+        }
+        Err(err) => startup_failed(format_args!(
+            "Can't listen on {}: {}",
+            config.api.listen, err
+        )),
+    };
+
+    let grpc = if config.grpc.enabled {
+        match tokio::net::TcpListener::bind(&config.grpc.listen).await {
+            Ok(listener) => {
+                tracing::info!("Serving gRPC on {}", config.grpc.listen);
+                let service =
+                    grpc::CollectorService::new(shared_state.clone(), config.api.max_limit);
+                Some(tokio::spawn(grpc::serve(
+                    listener,
+                    service,
+                    shutdown_rx.clone(),
+                )))
+            }
+            Err(err) => startup_failed(format_args!(
+                "Can't listen on {}: {}",
+                config.grpc.listen, err
+            )),
+        }
+    } else {
+        None
+    };
 
-        {
-            let locked_state = shared_state.lock().await;
-            locked_state.db.insert_candles(sym, historical_data.data);
+    if config.backfill.enabled && config.exchange == ExchangeKind::Binance {
+        tracing::warn!(
+            exchange = exchange.name(),
+            "Skipping backfill, it only downloads Poloniex candles"
+        );
+    } else if config.backfill.enabled {
+        let backfill = &config.backfill;
+        for sym in &config.symbols {
+            let candles = match rest
+                .candles(
+                    sym,
+                    backfill.interval.clone(),
+                    backfill.start_time,
+                    backfill.end_time,
+                )
+                .await
+            {
+                Ok(candles) => candles,
+                Err(err) => {
+                    tracing::error!("Failed to download KLines for {}: {}", sym, err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = shared_state.db.insert_candles(sym.clone(), candles).await {
+                tracing::error!("Failed to store KLines for {}: {}", sym, err);
+            }
         }
+        tracing::info!("Finished downloading KLines");
     }
-    tracing::info!("Finished downloading KLines");
 
-    // loop to keep up the event loop
-    loop {}
+    // keep up the event loop until interrupted
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to listen for shutdown signal");
+    tracing::info!("Shutting down");
+
+    let _ = shutdown_tx.send(true);
+    if let Err(err) = reader.await {
+        tracing::error!("Trades reader failed: {}", err);
+    }
+    if let Some(funding_store) = funding_store {
+        if let Err(err) = funding_store.await {
+            tracing::error!("Funding rate writer failed: {}", err);
+        }
+    }
+    if let Ok(Err(err)) = api.await {
+        tracing::error!("HTTP API failed: {}", err);
+    }
+    if let Some(grpc) = grpc {
+        if let Ok(Err(err)) = grpc.await {
+            tracing::error!("gRPC service failed: {}", err);
+        }
+    }
+    if let Err(err) = shared_state.db.flush().await {
+        tracing::error!("Failed to flush storage: {}", err);
+    }
+    telemetry.shutdown();
 }
@@ -0,0 +1,187 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, Histogram, IntCounter, IntCounterVec,
+    IntGauge, Registry, TextEncoder,
+};
+
+/// Process wide collector metrics, rendered by `GET /metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Decoded WebSocket messages by channel, or by event for channel-less messages
+    pub ws_messages: IntCounterVec,
+    pub ws_decode_failures: IntCounter,
+    pub ws_connects: IntCounter,
+    pub ws_disconnects: IntCounter,
+    /// Attempts to reopen a lost stream, successful or not
+    pub ws_reconnects: IntCounter,
+    /// Seconds between a heartbeat ping and its pong
+    pub ws_ping_latency: Histogram,
+    /// Trades waiting in the buffer for the next flush
+    pub trades_buffered: IntGauge,
+    pub trades_flushed: IntCounter,
+    pub trades_duplicate: IntCounter,
+    /// Trades deleted by retention after their windows were finalized
+    pub trades_purged: IntCounter,
+    /// Seconds the storage writer spends on one batch of trades
+    pub db_insert_latency: Histogram,
+    pub klines_produced: IntCounterVec,
+    pub rest_requests: IntCounterVec,
+    pub rest_errors: IntCounterVec,
+    pub bus_missed_events: IntCounter,
+    last_ping: Mutex<Option<Instant>>,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("collector".to_string()), None)
+            .expect("valid metrics prefix");
+        let latency_buckets = exponential_buckets(0.001, 2.0, 14).expect("valid buckets");
+
+        let metrics = Self {
+            ws_messages: IntCounterVec::new(
+                opts!("ws_messages_total", "WebSocket messages received"),
+                &["channel"],
+            )
+            .unwrap(),
+            ws_decode_failures: IntCounter::new(
+                "ws_decode_failures_total",
+                "WebSocket messages which couldn't be decoded",
+            )
+            .unwrap(),
+            ws_connects: IntCounter::new("ws_connects_total", "WebSocket connections established")
+                .unwrap(),
+            ws_disconnects: IntCounter::new("ws_disconnects_total", "WebSocket connections lost")
+                .unwrap(),
+            ws_reconnects: IntCounter::new(
+                "ws_reconnects_total",
+                "Attempts to reopen a lost WebSocket connection",
+            )
+            .unwrap(),
+            ws_ping_latency: Histogram::with_opts(histogram_opts!(
+                "ws_ping_latency_seconds",
+                "Time between a heartbeat ping and its pong",
+                latency_buckets.clone()
+            ))
+            .unwrap(),
+            trades_buffered: IntGauge::new("trades_buffered", "Trades waiting to be stored")
+                .unwrap(),
+            trades_flushed: IntCounter::new(
+                "trades_flushed_total",
+                "Trades handed to the storage writer",
+            )
+            .unwrap(),
+            trades_duplicate: IntCounter::new(
+                "trades_duplicate_total",
+                "Received trades which were already stored",
+            )
+            .unwrap(),
+            trades_purged: IntCounter::new(
+                "trades_purged_total",
+                "Stored trades deleted by retention",
+            )
+            .unwrap(),
+            db_insert_latency: Histogram::with_opts(histogram_opts!(
+                "db_insert_latency_seconds",
+                "Time to store one batch of trades",
+                latency_buckets
+            ))
+            .unwrap(),
+            klines_produced: IntCounterVec::new(
+                opts!("klines_produced_total", "Klines built from trades"),
+                &["timeframe"],
+            )
+            .unwrap(),
+            rest_requests: IntCounterVec::new(
+                opts!(
+                    "rest_requests_total",
+                    "Requests sent to the exchange REST API"
+                ),
+                &["request"],
+            )
+            .unwrap(),
+            rest_errors: IntCounterVec::new(
+                opts!("rest_errors_total", "Failed exchange REST requests"),
+                &["request"],
+            )
+            .unwrap(),
+            bus_missed_events: IntCounter::new(
+                "bus_missed_events_total",
+                "Events dropped for lagging event bus subscribers",
+            )
+            .unwrap(),
+            last_ping: Mutex::new(None),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 15] = [
+            Box::new(metrics.ws_messages.clone()),
+            Box::new(metrics.ws_decode_failures.clone()),
+            Box::new(metrics.ws_connects.clone()),
+            Box::new(metrics.ws_disconnects.clone()),
+            Box::new(metrics.ws_reconnects.clone()),
+            Box::new(metrics.ws_ping_latency.clone()),
+            Box::new(metrics.trades_buffered.clone()),
+            Box::new(metrics.trades_flushed.clone()),
+            Box::new(metrics.trades_duplicate.clone()),
+            Box::new(metrics.trades_purged.clone()),
+            Box::new(metrics.db_insert_latency.clone()),
+            Box::new(metrics.klines_produced.clone()),
+            Box::new(metrics.rest_requests.clone()),
+            Box::new(metrics.rest_errors.clone()),
+            Box::new(metrics.bus_missed_events.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    pub fn ping_sent(&self) {
+        *self.last_ping.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Observes the latency of the outstanding ping, if any
+    pub fn pong_received(&self) {
+        if let Some(sent) = self.last_ping.lock().unwrap().take() {
+            self.ws_ping_latency.observe(sent.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", err);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_with_prefix_and_labels() {
+        metrics().ws_messages.with_label_values(&["trades"]).inc();
+        metrics().ping_sent();
+        metrics().pong_received();
+
+        let rendered = metrics().render();
+        assert!(rendered.contains(r#"collector_ws_messages_total{channel="trades"}"#));
+        assert!(rendered.contains("collector_ws_ping_latency_seconds_count"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeMap, fmt};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    common::models::{Kline, KlineSource, TimeFrame},
+    database::StorageError,
+    exchange::{Exchange, ExchangeError},
+    SharedState,
+};
+
+/// Largest accepted relative difference, e.g. `0.001` is 0.1%
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub price: f64,
+    pub volume: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub utc_begin: i64,
+    pub field: &'static str,
+    pub ours: f64,
+    pub exchange: f64,
+}
+
+impl Discrepancy {
+    pub fn relative(&self) -> f64 {
+        relative_difference(self.ours, self.exchange)
+    }
+}
+
+/// Result of comparing aggregated klines of one symbol and timeframe with exchange candles
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconciliationReport {
+    pub symbol: String,
+    pub timeframe: TimeFrame,
+    /// Buckets present on both sides
+    pub compared: usize,
+    /// Buckets with an exchange candle but no aggregated kline
+    pub missing_ours: Vec<i64>,
+    /// Buckets with an aggregated kline but no exchange candle
+    pub missing_exchange: Vec<i64>,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_ours.is_empty()
+            && self.missing_exchange.is_empty()
+            && self.discrepancies.is_empty()
+    }
+}
+
+impl fmt::Display for ReconciliationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} buckets compared, {} discrepancies, {} missing here, {} missing on exchange",
+            self.symbol,
+            self.timeframe.as_ref(),
+            self.compared,
+            self.discrepancies.len(),
+            self.missing_ours.len(),
+            self.missing_exchange.len()
+        )?;
+        for d in &self.discrepancies {
+            let begin = DateTime::from_timestamp(d.utc_begin, 0).unwrap_or_default();
+            write!(
+                f,
+                "\n  {} {}: ours {} exchange {} ({:.4}%)",
+                begin,
+                d.field,
+                d.ours,
+                d.exchange,
+                d.relative() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReconciliationError {
+    Storage(StorageError),
+    Exchange(ExchangeError),
+}
+
+impl fmt::Display for ReconciliationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconciliationError::Storage(err) => write!(f, "storage: {}", err),
+            ReconciliationError::Exchange(err) => write!(f, "exchange: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReconciliationError {}
+
+impl From<StorageError> for ReconciliationError {
+    fn from(err: StorageError) -> Self {
+        ReconciliationError::Storage(err)
+    }
+}
+
+impl From<ExchangeError> for ReconciliationError {
+    fn from(err: ExchangeError) -> Self {
+        ReconciliationError::Exchange(err)
+    }
+}
+
+/// Compares stored aggregated klines with exchange candles starting in `[start_time, end_time)`
+pub async fn reconcile(
+    state: &SharedState,
+    exchange: &dyn Exchange,
+    symbol: &str,
+    timeframe: &TimeFrame,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    tolerance: Tolerance,
+) -> Result<ReconciliationReport, ReconciliationError> {
+    let ours = state
+        .db
+        .retrieve_klines_in_interval(
+            symbol.to_string(),
+            timeframe.clone(),
+            start_time,
+            end_time,
+            None,
+        )
+        .await?;
+    let theirs = exchange
+        .candles(symbol, timeframe, start_time, end_time)
+        .await?;
+
+    Ok(compare(symbol, timeframe, &ours, &theirs, tolerance))
+}
+
+/// Klines tagged as `KlineSource::Exchange` are skipped on our side, they are copies of candles
+pub fn compare(
+    symbol: &str,
+    timeframe: &TimeFrame,
+    ours: &[Kline],
+    exchange: &[Kline],
+    tolerance: Tolerance,
+) -> ReconciliationReport {
+    let mut report = ReconciliationReport {
+        symbol: symbol.to_string(),
+        timeframe: timeframe.clone(),
+        compared: 0,
+        missing_ours: Vec::new(),
+        missing_exchange: Vec::new(),
+        discrepancies: Vec::new(),
+    };
+
+    let mut candles: BTreeMap<i64, &Kline> = exchange
+        .iter()
+        .map(|kline| (kline.utc_begin, kline))
+        .collect();
+
+    for kline in ours {
+        if kline.source != KlineSource::Aggregated {
+            candles.remove(&kline.utc_begin);
+            continue;
+        }
+        let Some(candle) = candles.remove(&kline.utc_begin) else {
+            report.missing_exchange.push(kline.utc_begin);
+            continue;
+        };
+        report.compared += 1;
+
+        let fields = [
+            ("open", kline.open, candle.open, tolerance.price),
+            ("high", kline.high, candle.high, tolerance.price),
+            ("low", kline.low, candle.low, tolerance.price),
+            ("close", kline.close, candle.close, tolerance.price),
+            (
+                "base volume",
+                kline.volume_bs.buy_base + kline.volume_bs.sell_base,
+                candle.volume_bs.buy_base + candle.volume_bs.sell_base,
+                tolerance.volume,
+            ),
+            (
+                "quote volume",
+                kline.volume_bs.buy_quote + kline.volume_bs.sell_quote,
+                candle.volume_bs.buy_quote + candle.volume_bs.sell_quote,
+                tolerance.volume,
+            ),
+        ];
+        for (field, ours, exchange, tolerance) in fields {
+            if relative_difference(ours, exchange) > tolerance {
+                report.discrepancies.push(Discrepancy {
+                    utc_begin: kline.utc_begin,
+                    field,
+                    ours,
+                    exchange,
+                });
+            }
+        }
+    }
+
+    report.missing_ours = candles.into_keys().collect();
+    report
+}
+
+fn relative_difference(a: f64, b: f64) -> f64 {
+    let scale = a.abs().max(b.abs());
+    if scale == 0.0 {
+        0.0
+    } else {
+        (a - b).abs() / scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::kline;
+
+    #[test]
+    fn discrepancies_over_tolerance_are_reported() {
+        let kline = |utc_begin, close, volume: f64, source| {
+            kline("BTC_USDT", TimeFrame::Hour, utc_begin, utc_begin + 3600)
+                .prices(100.0, 110.0, 90.0, close)
+                .volume(volume / 2.0, volume / 2.0, volume * 50.0, volume * 50.0)
+                .source(source)
+                .build()
+        };
+        let ours = [
+            kline(0, 105.0, 10.0, KlineSource::Aggregated),
+            kline(3600, 105.0, 10.0, KlineSource::Aggregated),
+            kline(7200, 105.0, 10.0, KlineSource::Exchange),
+            kline(10800, 105.0, 10.0, KlineSource::Aggregated),
+        ];
+        let exchange = [
+            kline(0, 105.01, 10.05, KlineSource::Exchange),
+            kline(3600, 106.0, 12.0, KlineSource::Exchange),
+            kline(7200, 105.0, 10.0, KlineSource::Exchange),
+            kline(14400, 105.0, 10.0, KlineSource::Exchange),
+        ];
+        let tolerance = Tolerance {
+            price: 0.001,
+            volume: 0.01,
+        };
+
+        let report = compare("BTC_USDT", &TimeFrame::Hour, &ours, &exchange, tolerance);
+
+        assert_eq!(report.compared, 2);
+        assert_eq!(report.missing_exchange, vec![10800]);
+        assert_eq!(report.missing_ours, vec![14400]);
+        let fields: Vec<(i64, &str)> = report
+            .discrepancies
+            .iter()
+            .map(|d| (d.utc_begin, d.field))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (3600, "close"),
+                (3600, "base volume"),
+                (3600, "quote volume")
+            ]
+        );
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::{DateTime, Duration, Utc};
+use tokio::time::sleep;
+
+use crate::{
+    common::models::TimeFrame,
+    database::{Storage, StorageError},
+    metrics::metrics,
+    SharedState,
+};
+
+pub struct RetentionPolicy {
+    /// Raw trades younger than this are always kept
+    pub keep_trades_for: Duration,
+    pub run_every: Duration,
+}
+
+/// Periodically purges raw trades which are no longer needed.
+///
+/// A trade is deleted only when it is older than `keep_trades_for` and its window
+/// has already been finalized into a kline for every configured timeframe,
+/// so the aggregator never loses input it hasn't processed yet.
+pub struct Retention {
+    policy: RetentionPolicy,
+    timeframes: Vec<TimeFrame>,
+    state: SharedState,
+}
+
+impl Retention {
+    pub fn new(policy: RetentionPolicy, timeframes: Vec<TimeFrame>, state: SharedState) -> Self {
+        Self {
+            policy,
+            timeframes,
+            state,
+        }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            sleep(self.policy.run_every.to_std().unwrap_or_default()).await;
+
+            match self.purge(Utc::now()).await {
+                Ok(purged) => tracing::info!(
+                    "Retention purged {} trades, {} in total",
+                    purged,
+                    metrics().trades_purged.get()
+                ),
+                Err(err) => tracing::error!("Retention failed: {}", err),
+            }
+        }
+    }
+
+    pub async fn purge(&self, now: DateTime<Utc>) -> Result<usize, StorageError> {
+        let cutoff = now - self.policy.keep_trades_for;
+        let timeframes = self.timeframes.clone();
+
+        let purged = self
+            .state
+            .db
+            .call(move |storage| {
+                let purged = purge_finalized_trades(storage, &timeframes, &cutoff)?;
+                if purged > 0 {
+                    storage.compact()?;
+                }
+                Ok(purged)
+            })
+            .await?;
+
+        metrics().trades_purged.inc_by(purged as u64);
+        Ok(purged)
+    }
+}
+
+/// Deletes trades before `cutoff` whose windows are covered by the latest kline of every timeframe
+pub fn purge_finalized_trades(
+    storage: &mut dyn Storage,
+    timeframes: &[TimeFrame],
+    cutoff: &DateTime<Utc>,
+) -> Result<usize, StorageError> {
+    let mut purged = 0;
+
+    for symbol in storage.trade_symbols()? {
+        let mut finalized_until = Some(*cutoff);
+
+        for timeframe in timeframes {
+            let kline_end = storage
+                .latest_kline(&symbol, timeframe)?
+                .and_then(|kline| DateTime::from_timestamp(kline.utc_end, 0));
+
+            finalized_until = match (finalized_until, kline_end) {
+                (Some(until), Some(end)) => Some(until.min(end)),
+                _ => None,
+            };
+        }
+
+        if let Some(before) = finalized_until {
+            purged += storage.delete_trades_before(&symbol, &before)?;
+        }
+    }
+
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::MemoryStorage,
+        test_support::{kline, trade},
+    };
+
+    #[test]
+    fn only_trades_finalized_for_every_timeframe_are_purged() {
+        let mut db = MemoryStorage::new();
+        db.insert_recent_trades(&[
+            trade("1", "BTC_USDT", "buy", "1", 0),
+            trade("2", "BTC_USDT", "buy", "1", 1_000_000),
+            trade("3", "BTC_USDT", "buy", "1", 3_000_000),
+            trade("4", "ETH_USDT", "buy", "1", 0),
+        ])
+        .unwrap();
+
+        // BTC is finalized up to 900s for 15m but up to 3600s for 1h, ETH has no hourly kline
+        db.insert_kline(&kline("BTC_USDT", TimeFrame::Minutes15, 0, 900).build())
+            .unwrap();
+        db.insert_kline(&kline("BTC_USDT", TimeFrame::Hour, 0, 3600).build())
+            .unwrap();
+        db.insert_kline(&kline("ETH_USDT", TimeFrame::Minutes15, 0, 900).build())
+            .unwrap();
+
+        let timeframes = [TimeFrame::Minutes15, TimeFrame::Hour];
+        let cutoff = DateTime::from_timestamp(10_000, 0).unwrap();
+        let purged = purge_finalized_trades(&mut db, &timeframes, &cutoff).unwrap();
+
+        assert_eq!(purged, 1);
+
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let remaining = db
+            .retrieve_trades_in_interval(None, &start, &cutoff, None)
+            .unwrap();
+        let ids: Vec<&str> = remaining.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, vec!["4", "2", "3"]);
+    }
+}
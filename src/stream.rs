@@ -0,0 +1,321 @@
+//! Exchange-neutral plumbing of the market data streams: how they connect and reconnect,
+//! report their connection state and hand published trades to storage
+
+use std::{future::Future, io, time::Duration};
+
+use reqwest::Url;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::watch,
+    time::{interval, sleep, timeout, MissedTickBehavior},
+};
+use tokio_tungstenite::{client_async_tls_with_config, Connector};
+use tungstenite::error::Error;
+
+use crate::{
+    bus::{ConnectionState, Delivery, Event, RecvError, Subscription, Topic},
+    common::models::Trade,
+    metrics::metrics,
+    SharedState,
+};
+
+const TRADES_BUFFER_SIZE: usize = 100;
+const TRADES_BUFFER_LATENCY: Duration = Duration::from_secs(1);
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+pub type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// When received trades are handed over to storage
+#[derive(Debug, Clone)]
+pub struct TradeBufferConfig {
+    pub max_trades: usize,
+    pub max_latency: Duration,
+}
+
+impl Default for TradeBufferConfig {
+    fn default() -> Self {
+        Self {
+            max_trades: TRADES_BUFFER_SIZE,
+            max_latency: TRADES_BUFFER_LATENCY,
+        }
+    }
+}
+
+/// How long a stream reader waits before reopening a lost connection.
+/// The wait doubles after every failed attempt, up to `max_backoff`
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: RECONNECT_INITIAL_BACKOFF,
+            max_backoff: RECONNECT_MAX_BACKOFF,
+        }
+    }
+}
+
+/// Endpoint, proxy and TLS settings of a stream connection, shared by the exchange stream
+/// builders so every venue connects the same way
+#[derive(Clone)]
+pub struct WsConnectOptions {
+    endpoint: String,
+    connect_timeout: Option<Duration>,
+    proxy: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    accept_invalid_certs: bool,
+}
+
+impl WsConnectOptions {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            connect_timeout: None,
+            proxy: None,
+            root_certificates: Vec::new(),
+            accept_invalid_certs: false,
+        }
+    }
+
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
+    /// Limits TCP connect, proxy tunnel, TLS and WebSocket handshakes together
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// HTTP proxy URL, the connection is tunneled with `CONNECT`
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Trusts an additional PEM encoded root certificate
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Disables certificate validation. Only meant for local test servers
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Opens a new connection, used for the first one and every reconnect
+    pub async fn open(&self) -> Result<WsStream, Error> {
+        let handshake = self.handshake();
+        match self.connect_timeout {
+            Some(limit) => timeout(limit, handshake)
+                .await
+                .map_err(|_| Error::Io(io::Error::from(io::ErrorKind::TimedOut)))?,
+            None => handshake.await,
+        }
+    }
+
+    async fn handshake(&self) -> Result<WsStream, Error> {
+        let url = Url::parse(&self.endpoint).map_err(|_| invalid_input("invalid endpoint"))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid_input("endpoint without host"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| invalid_input("endpoint without port"))?;
+
+        let tcp = match &self.proxy {
+            Some(proxy) => Self::tunnel(proxy, host, port).await?,
+            None => TcpStream::connect((host, port)).await?,
+        };
+
+        let (stream, _) = client_async_tls_with_config(
+            self.endpoint.as_str(),
+            tcp,
+            None,
+            self.connector().map_err(|err| Error::Tls(err.into()))?,
+        )
+        .await?;
+        Ok(stream)
+    }
+
+    /// `None` keeps the default TLS settings
+    fn connector(&self) -> Result<Option<Connector>, native_tls::Error> {
+        if !self.accept_invalid_certs && self.root_certificates.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.danger_accept_invalid_certs(self.accept_invalid_certs);
+        for pem in &self.root_certificates {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+        }
+
+        Ok(Some(Connector::NativeTls(builder.build()?)))
+    }
+
+    async fn tunnel(proxy: &str, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let proxy = Url::parse(proxy).map_err(|_| invalid_input("invalid proxy"))?;
+        let proxy_host = proxy
+            .host_str()
+            .ok_or_else(|| invalid_input("proxy without host"))?;
+        let proxy_port = proxy.port_or_known_default().unwrap_or(8080);
+
+        let mut tcp = TcpStream::connect((proxy_host, proxy_port)).await?;
+        let connect = format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n",
+            host = host,
+            port = port
+        );
+        tcp.write_all(connect.as_bytes()).await?;
+
+        let mut reader = BufReader::new(&mut tcp);
+        let mut status = String::new();
+        reader.read_line(&mut status).await?;
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(Error::Io(io::Error::other(format!(
+                "proxy refused tunnel: {}",
+                status.trim()
+            ))));
+        }
+        // skip the rest of the proxy response headers
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line == "\r\n" {
+                break;
+            }
+        }
+
+        Ok(tcp)
+    }
+}
+
+fn invalid_input(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg.to_string()))
+}
+
+/// Stores the trades published on the bus while `publishing` runs, batched by `buffer_config`
+pub async fn store_published_trades<F>(
+    state: &SharedState,
+    buffer_config: TradeBufferConfig,
+    publishing: F,
+) where
+    F: Future<Output = ()>,
+{
+    let trades = state
+        .bus
+        .subscribe("storage", &[Topic::Trades], Delivery::Reliable);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let writer = tokio::spawn(store_trades(state.clone(), trades, buffer_config, stop_rx));
+
+    publishing.await;
+
+    let _ = stop_tx.send(true);
+    if let Err(err) = writer.await {
+        tracing::error!("Trade writer failed: {}", err);
+    }
+}
+
+/// Waits out the backoff of `policy` and calls `connect` until it succeeds,
+/// doubling the wait after every failure. Returns `None` once `shutdown` fires
+pub async fn reconnect<T, F, Fut>(
+    policy: &ReconnectPolicy,
+    mut shutdown: watch::Receiver<bool>,
+    mut connect: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut backoff = policy.initial_backoff;
+    loop {
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown.wait_for(|stop| *stop) => return None,
+        }
+
+        metrics().ws_reconnects.inc();
+        match connect().await {
+            Ok(connection) => return Some(connection),
+            Err(err) => {
+                tracing::warn!("Failed to reconnect, retrying in {:?}: {}", backoff, err);
+                backoff = (backoff * 2).min(policy.max_backoff);
+            }
+        }
+    }
+}
+
+/// Buffers trades from the bus until `stop`, then drains what the reader published before it
+async fn store_trades(
+    state: SharedState,
+    mut trades: Subscription,
+    buffer_config: TradeBufferConfig,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut trade_buffer: Vec<Trade> = Vec::new();
+    let mut flush_interval = interval(buffer_config.max_latency);
+    flush_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            event = trades.recv() => match event {
+                Ok(Event::Trade(trade)) => {
+                    if trade_buffer.is_empty() {
+                        flush_interval.reset();
+                    }
+                    trade_buffer.push(trade);
+                    metrics().trades_buffered.set(trade_buffer.len() as i64);
+                    if trade_buffer.len() >= buffer_config.max_trades {
+                        flush_trades(&state, &mut trade_buffer).await;
+                    }
+                }
+                // reliable subscriptions don't lag
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = flush_interval.tick() => {
+                flush_trades(&state, &mut trade_buffer).await;
+            }
+            _ = stop.changed() => break,
+        }
+    }
+
+    while let Some(event) = trades.try_recv() {
+        if let Event::Trade(trade) = event {
+            trade_buffer.push(trade);
+        }
+    }
+    flush_trades(&state, &mut trade_buffer).await;
+}
+
+async fn flush_trades(state: &SharedState, trade_buffer: &mut Vec<Trade>) {
+    if trade_buffer.is_empty() {
+        return;
+    }
+
+    let trades = std::mem::take(trade_buffer);
+    metrics().trades_buffered.set(0);
+    metrics().trades_flushed.inc_by(trades.len() as u64);
+    if let Err(err) = state.db.insert_recent_trades(trades).await {
+        tracing::error!("Failed to queue trades: {}", err);
+    }
+}
+
+/// Reports a state change of an exchange stream to metrics, health and the bus
+pub async fn publish_connection(state: &SharedState, connection: ConnectionState) {
+    match connection {
+        ConnectionState::Connected => metrics().ws_connects.inc(),
+        ConnectionState::Disconnected => metrics().ws_disconnects.inc(),
+    }
+    state
+        .health
+        .set_connected(connection == ConnectionState::Connected);
+    state.bus.publish(Event::Connection(connection)).await;
+}
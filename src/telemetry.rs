@@ -0,0 +1,109 @@
+use std::fmt;
+
+use tracing_subscriber::{
+    filter::ParseError, layer::SubscriberExt, util::SubscriberInitExt, util::TryInitError,
+    EnvFilter,
+};
+
+use crate::config::{LogFormat, LoggingConfig};
+
+#[cfg(feature = "otlp")]
+const SERVICE_NAME: &str = "bitsgap-collector";
+
+#[derive(Debug)]
+pub enum TelemetryError {
+    Filter(ParseError),
+    Init(TryInitError),
+    #[cfg(feature = "otlp")]
+    Exporter(opentelemetry::trace::TraceError),
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelemetryError::Filter(err) => write!(f, "invalid log filter: {}", err),
+            TelemetryError::Init(err) => write!(f, "can't install logger: {}", err),
+            #[cfg(feature = "otlp")]
+            TelemetryError::Exporter(err) => write!(f, "can't create OTLP exporter: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {}
+
+/// Keeps the span exporter alive, `shutdown` sends the spans which are still batched
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {}", err);
+            }
+        }
+    }
+}
+
+/// Installs the global logger, must be called from within the tokio runtime when exporting spans
+pub fn init(config: &LoggingConfig) -> Result<Telemetry, TelemetryError> {
+    let filter = EnvFilter::try_new(&config.level).map_err(TelemetryError::Filter)?;
+    let json = config.format == LogFormat::Json;
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+        }));
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider = match &config.otlp_endpoint {
+            Some(endpoint) => Some(otlp_provider(endpoint)?),
+            None => None,
+        };
+        let layer = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider as _;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+        });
+        registry
+            .with(layer)
+            .try_init()
+            .map_err(TelemetryError::Init)?;
+
+        Ok(Telemetry { provider })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.try_init().map_err(TelemetryError::Init)?;
+        Ok(Telemetry {})
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_provider(
+    endpoint: &str,
+) -> Result<opentelemetry_sdk::trace::TracerProvider, TelemetryError> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(TelemetryError::Exporter)?;
+
+    Ok(opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", SERVICE_NAME),
+        ]))
+        .build())
+}
@@ -0,0 +1,437 @@
+//! Local stand-in for the Poloniex REST and WebSocket APIs, so the pipeline can be tested offline,
+//! and fixtures shared by the test modules
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch},
+};
+use tungstenite::Message;
+
+use crate::{
+    client::{models::RawKLHistory, poloniex::Poloniex, rest::PoloniexRest, ws::PoloniexWs},
+    common::models::{Kline, KlineSource, TimeFrame, Trade, Vbs},
+    database::{MemoryStorage, Storage, StorageHandle},
+    exchange::Exchange,
+    SharedState, State,
+};
+
+/// Collector state backed by `MemoryStorage`
+pub async fn memory_state() -> SharedState {
+    let db = StorageHandle::spawn(
+        16,
+        || Ok(Box::new(MemoryStorage::new()) as Box<dyn Storage>),
+    )
+    .await
+    .unwrap();
+    Arc::new(State::new(db))
+}
+
+/// Trade of quantity 1, so its `amount` equals `price`. `ts` is in milliseconds
+pub fn trade(id: &str, symbol: &str, side: &str, price: &str, ts: u64) -> Trade {
+    Trade {
+        symbol: symbol.to_string(),
+        amount: price.to_string(),
+        taker_side: side.to_string(),
+        quantity: "1".to_string(),
+        create_time: ts,
+        price: price.to_string(),
+        id: id.to_string(),
+        ts,
+    }
+}
+
+/// Kline of `[utc_begin, utc_end)` in seconds, flat at price 1 with one unit bought
+pub fn kline(symbol: &str, timeframe: TimeFrame, utc_begin: i64, utc_end: i64) -> KlineBuilder {
+    KlineBuilder(Kline {
+        pair: symbol.to_string(),
+        timeframe,
+        open: 1.0,
+        high: 1.0,
+        low: 1.0,
+        close: 1.0,
+        utc_begin,
+        utc_end,
+        volume_bs: Vbs {
+            buy_base: 1.0,
+            sell_base: 0.0,
+            buy_quote: 1.0,
+            sell_quote: 0.0,
+        },
+        source: KlineSource::Aggregated,
+    })
+}
+
+pub struct KlineBuilder(Kline);
+
+impl KlineBuilder {
+    pub fn prices(mut self, open: f64, high: f64, low: f64, close: f64) -> Self {
+        self.0.open = open;
+        self.0.high = high;
+        self.0.low = low;
+        self.0.close = close;
+        self
+    }
+
+    pub fn volume(
+        mut self,
+        buy_base: f64,
+        sell_base: f64,
+        buy_quote: f64,
+        sell_quote: f64,
+    ) -> Self {
+        self.0.volume_bs = Vbs {
+            buy_base,
+            sell_base,
+            buy_quote,
+            sell_quote,
+        };
+        self
+    }
+
+    pub fn source(mut self, source: KlineSource) -> Self {
+        self.0.source = source;
+        self
+    }
+
+    pub fn build(self) -> Kline {
+        self.0
+    }
+}
+
+/// What the server sends to every connected WebSocket client
+#[derive(Debug, Clone)]
+enum Push {
+    Text(String),
+    Disconnect,
+}
+
+#[derive(Default)]
+struct Recorded {
+    candles: HashMap<String, RawKLHistory>,
+    requests: Vec<String>,
+    subscriptions: Vec<(Vec<String>, Vec<String>)>,
+}
+
+/// Serves `candles`, a fixed `markets` list and canned futures data over HTTP and a public WebSocket channel on two random local ports.
+///
+/// The WebSocket side confirms subscriptions, answers pings with pongs and pushes
+/// whatever the test hands to `push_trades`. Both servers stop when the mock is dropped
+pub struct MockPoloniex {
+    http_port: u16,
+    ws_port: u16,
+    recorded: Arc<Mutex<Recorded>>,
+    pings: Arc<AtomicUsize>,
+    pushes: broadcast::Sender<Push>,
+    subscribed: watch::Receiver<usize>,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl MockPoloniex {
+    pub async fn start() -> Self {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_port = http.local_addr().unwrap().port();
+        let ws_port = ws.local_addr().unwrap().port();
+
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        let pings = Arc::new(AtomicUsize::new(0));
+        let (pushes, _) = broadcast::channel(64);
+        let (subscribed_tx, subscribed) = watch::channel(0);
+        let subscribed_tx = Arc::new(subscribed_tx);
+
+        let http_task = {
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                while let Ok((tcp, _)) = http.accept().await {
+                    tokio::spawn(serve_http(tcp, recorded.clone()));
+                }
+            })
+        };
+
+        let ws_task = {
+            let recorded = recorded.clone();
+            let pings = pings.clone();
+            let pushes = pushes.clone();
+            tokio::spawn(async move {
+                while let Ok((tcp, _)) = ws.accept().await {
+                    tokio::spawn(serve_ws(
+                        tcp,
+                        recorded.clone(),
+                        pings.clone(),
+                        pushes.subscribe(),
+                        subscribed_tx.clone(),
+                    ));
+                }
+            })
+        };
+
+        Self {
+            http_port,
+            ws_port,
+            recorded,
+            pings,
+            pushes,
+            subscribed,
+            tasks: vec![http_task, ws_task],
+        }
+    }
+
+    /// Base URL for `PoloniexRest::with_endpoint`
+    pub fn rest_endpoint(&self) -> String {
+        format!("http://127.0.0.1:{}/v3/market/", self.http_port)
+    }
+
+    /// URL for `PoloniexWs::connect`
+    pub fn ws_endpoint(&self) -> String {
+        format!("ws://127.0.0.1:{}/ws/public", self.ws_port)
+    }
+
+    /// `Exchange` talking to both mock servers
+    pub fn exchange(&self) -> Arc<dyn Exchange> {
+        Arc::new(Poloniex::new(
+            Arc::new(PoloniexRest::with_endpoint(&self.rest_endpoint())),
+            PoloniexWs::builder().endpoint(&self.ws_endpoint()),
+        ))
+    }
+
+    /// Rows returned by the candles endpoint for `symbol`, regardless of interval and range
+    pub fn set_candles(&self, symbol: &str, rows: RawKLHistory) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.candles.insert(symbol.to_string(), rows);
+    }
+
+    /// Sends a `trades` channel message to every connected client
+    pub fn push_trades(&self, trades: &[Trade]) {
+        let data: Vec<Value> = trades
+            .iter()
+            .map(|trade| {
+                json!({
+                    "symbol": trade.symbol,
+                    "amount": trade.amount,
+                    "takerSide": trade.taker_side,
+                    "quantity": trade.quantity,
+                    "createTime": trade.create_time,
+                    "price": trade.price,
+                    "id": trade.id,
+                    "ts": trade.ts,
+                })
+            })
+            .collect();
+
+        self.push_raw(&json!({ "channel": "trades", "data": data }).to_string());
+    }
+
+    /// Sends `text` as is, e.g. to feed malformed messages
+    pub fn push_raw(&self, text: &str) {
+        let _ = self.pushes.send(Push::Text(text.to_string()));
+    }
+
+    /// Drops every client connection without a close frame
+    pub fn disconnect(&self) {
+        let _ = self.pushes.send(Push::Disconnect);
+    }
+
+    /// Resolves once `count` subscribe messages have been confirmed
+    pub async fn wait_for_subscriptions(&self, count: usize) {
+        let mut subscribed = self.subscribed.clone();
+        subscribed.wait_for(|n| *n >= count).await.unwrap();
+    }
+
+    /// `(channels, symbols)` of every subscribe message received
+    pub fn subscriptions(&self) -> Vec<(Vec<String>, Vec<String>)> {
+        self.recorded.lock().unwrap().subscriptions.clone()
+    }
+
+    /// Path and query of every HTTP request received
+    pub fn requests(&self) -> Vec<String> {
+        self.recorded.lock().unwrap().requests.clone()
+    }
+
+    pub fn pings(&self) -> usize {
+        self.pings.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockPoloniex {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.disconnect();
+    }
+}
+
+async fn serve_http(mut tcp: TcpStream, recorded: Arc<Mutex<Recorded>>) {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        match tcp.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let target = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let url = Url::parse(&format!("http://127.0.0.1{}", target)).unwrap();
+    recorded.lock().unwrap().requests.push(target);
+
+    let (status, body) = if url.path() == "/v3/market/candles" {
+        let symbol = url
+            .query_pairs()
+            .find(|(key, _)| key == "symbol")
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default();
+        let rows = recorded
+            .lock()
+            .unwrap()
+            .candles
+            .get(&symbol)
+            .cloned()
+            .unwrap_or_default();
+
+        (
+            "200 OK",
+            json!({ "code": 200, "msg": "Success", "data": rows }).to_string(),
+        )
+    } else if url.path() == "/v3/market/markets" {
+        let markets = json!([
+            { "symbol": "BTC_USDT", "baseCurrencyName": "BTC", "quoteCurrencyName": "USDT", "state": "NORMAL" },
+            { "symbol": "LUNA_USDT", "baseCurrencyName": "LUNA", "quoteCurrencyName": "USDT", "state": "PAUSE" },
+        ]);
+        ("200 OK", markets.to_string())
+    } else if url.path() == "/v3/market/openInterest" {
+        let symbol = url
+            .query_pairs()
+            .find(|(key, _)| key == "symbol")
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default();
+        let data = json!([{ "s": symbol, "oInterest": "1234.5", "ts": 1704067200000u64 }]);
+
+        (
+            "200 OK",
+            json!({ "code": 200, "msg": "Success", "data": data }).to_string(),
+        )
+    } else if url.path() == "/v3/market/fundingRate/history" {
+        let symbol = url
+            .query_pairs()
+            .find(|(key, _)| key == "symbol")
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default();
+        // newest first, like the exchange
+        let data = json!([
+            { "s": symbol, "fR": "0.00012", "fT": 1704096000000u64 },
+            { "s": symbol, "fR": "-0.00003", "fT": 1704067200000u64 },
+        ]);
+
+        (
+            "200 OK",
+            json!({ "code": 200, "msg": "Success", "data": data }).to_string(),
+        )
+    } else {
+        (
+            "404 Not Found",
+            json!({ "code": 404, "msg": "Not Found" }).to_string(),
+        )
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = tcp.write_all(response.as_bytes()).await;
+}
+
+async fn serve_ws(
+    tcp: TcpStream,
+    recorded: Arc<Mutex<Recorded>>,
+    pings: Arc<AtomicUsize>,
+    mut pushes: broadcast::Receiver<Push>,
+    subscribed: Arc<watch::Sender<usize>>,
+) {
+    let Ok(stream) = tokio_tungstenite::accept_async(tcp).await else {
+        return;
+    };
+    let (mut write, mut read) = stream.split();
+
+    loop {
+        tokio::select! {
+            msg = read.next() => {
+                let Some(Ok(Message::Text(text))) = msg else {
+                    return;
+                };
+                let Ok(request) = serde_json::from_str::<Value>(&text) else {
+                    continue;
+                };
+
+                let replies = match request["event"].as_str() {
+                    Some("ping") => {
+                        pings.fetch_add(1, Ordering::Relaxed);
+                        vec![json!({ "event": "pong" })]
+                    }
+                    Some("subscribe") => {
+                        let strings = |key: &str| -> Vec<String> {
+                            request[key]
+                                .as_array()
+                                .map(|values| {
+                                    values
+                                        .iter()
+                                        .filter_map(|v| v.as_str().map(str::to_string))
+                                        .collect()
+                                })
+                                .unwrap_or_default()
+                        };
+                        let (channels, symbols) = (strings("channel"), strings("symbols"));
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .subscriptions
+                            .push((channels.clone(), symbols.clone()));
+
+                        channels
+                            .iter()
+                            .map(|channel| {
+                                json!({ "event": "subscribe", "channel": channel, "symbols": symbols })
+                            })
+                            .collect()
+                    }
+                    _ => vec![json!({ "event": "error", "message": "Unknown event" })],
+                };
+
+                for reply in replies {
+                    if write.send(Message::Text(reply.to_string().into())).await.is_err() {
+                        return;
+                    }
+                }
+                if request["event"] == "subscribe" {
+                    subscribed.send_modify(|n| *n += 1);
+                }
+            }
+            push = pushes.recv() => {
+                match push {
+                    Ok(Push::Text(text)) => {
+                        if write.send(Message::Text(text.into())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Ok(Push::Disconnect) | Err(_) => return,
+                }
+            }
+        }
+    }
+}